@@ -0,0 +1,39 @@
+//! Frames received by the VSOCK listener, parsed as it does, from
+//! arbitrary input:
+//!
+//!     cargo +nightly fuzz run frame
+
+#![no_main]
+
+use fact::frame::{self, Frame, Header, HEADER_LEN};
+use libfuzzer_sys::fuzz_target;
+
+/// Small, for the fuzzer to run into the limit.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rest = data;
+    while let Some((buf, after)) = rest.split_first_chunk::<HEADER_LEN>() {
+        let Ok(header) = Header::decode(buf) else {
+            return;
+        };
+        let length = header.length as usize;
+        if length > MAX_FRAME_SIZE || length > after.len() {
+            return;
+        }
+        let (payload, after) = after.split_at(length);
+        let frame = Frame::from_parts(header, payload.to_vec());
+        assert_eq!(frame.encode(), rest[..HEADER_LEN + length]);
+
+        if let Ok(data) = frame.data(MAX_FRAME_SIZE) {
+            assert!(data.len() <= MAX_FRAME_SIZE);
+        }
+        if let Ok((min, max)) = frame.hello_versions() {
+            if let Some(version) = frame::negotiate(min, max) {
+                assert!((min..=max).contains(&version));
+            }
+        }
+        frame.error_details();
+        rest = after;
+    }
+});
@@ -0,0 +1,176 @@
+//! Resources package scans are allowed to use, so scanning does not get
+//! in the way of the workloads of small VMs.
+//!
+//! Scans run in a thread of their own, with a lower CPU and I/O priority
+//! inherited by the commands they run. Collectors check the budget as
+//! they go and give up once the scan took too long, grew the memory of
+//! the agent too much or fact is shutting down.
+
+use std::{
+    fs::read_to_string,
+    os::unix::process::CommandExt,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use log::{info, warn};
+
+use crate::config::{IoPriority, ScanConfig};
+
+/// ioprio_set() arguments, from linux/ioprio.h
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+/// Limits of a single scan.
+pub struct ScanBudget {
+    started: Instant,
+    timeout: Option<Duration>,
+    /// Growth of the resident memory of the agent allowed, in bytes
+    memory_limit: Option<u64>,
+    baseline_rss: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScanBudget {
+    pub fn new(cfg: &ScanConfig, cancelled: Arc<AtomicBool>) -> Self {
+        ScanBudget {
+            started: Instant::now(),
+            timeout: (cfg.timeout > 0).then(|| Duration::from_secs(cfg.timeout)),
+            memory_limit: cfg.memory_limit.map(|mib| mib * 1024 * 1024),
+            baseline_rss: rss(),
+            cancelled,
+        }
+    }
+
+    /// Fail once the scan is over budget or cancelled.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            bail!("scan cancelled");
+        }
+        if let Some(timeout) = self.timeout {
+            if self.started.elapsed() > timeout {
+                bail!("scan took longer than {}s", timeout.as_secs());
+            }
+        }
+        if let Some(limit) = self.memory_limit {
+            let grown = rss().saturating_sub(self.baseline_rss);
+            if grown > limit {
+                bail!("scan used more than {} MiB of memory", limit / 1024 / 1024);
+            }
+        }
+        Ok(())
+    }
+
+    /// Limit the address space of a command run by a collector to the
+    /// memory limit of the scan.
+    pub fn limit_command(&self, cmd: &mut Command) {
+        let Some(limit) = self.memory_limit else {
+            return;
+        };
+        let rlimit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        // Safety: setrlimit is async-signal-safe
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_AS, &rlimit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Lower the CPU and I/O priority of the calling thread, and of the
+/// processes it spawns.
+pub fn lower_priority(cfg: &ScanConfig) {
+    let tid = unsafe { libc::gettid() } as libc::id_t;
+    if cfg.nice != 0 && unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, cfg.nice) } != 0 {
+        warn!(
+            "Failed to set the scan nice level: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let ioprio = match cfg.ionice {
+        IoPriority::None => return,
+        IoPriority::BestEffort(level) => {
+            IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | libc::c_int::from(level)
+        }
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    // 0 is the calling thread
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+        warn!(
+            "Failed to set the scan I/O priority: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Resources used by the calling thread and by the child processes of
+/// fact that were waited for, to be compared before and after a scan.
+/// Children are counted for the whole process, so commands other threads
+/// ran during the scan count as part of it.
+pub struct Usage {
+    at: Instant,
+    thread_cpu: Duration,
+    children_cpu: Duration,
+    rss: u64,
+}
+
+impl Usage {
+    pub fn now() -> Self {
+        Usage {
+            at: Instant::now(),
+            thread_cpu: cpu_time(libc::RUSAGE_THREAD),
+            children_cpu: cpu_time(libc::RUSAGE_CHILDREN),
+            rss: rss(),
+        }
+    }
+
+    /// Log what was used since `self`.
+    pub fn log_since(&self) {
+        let now = Usage::now();
+        let mib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+        info!(
+            "Scan took {:.2?}, {:.2?} of CPU ({:.2?} in commands), {:.1} MiB of resident memory ({:+.1} MiB)",
+            now.at - self.at,
+            (now.thread_cpu + now.children_cpu).saturating_sub(self.thread_cpu + self.children_cpu),
+            now.children_cpu.saturating_sub(self.children_cpu),
+            mib(now.rss),
+            mib(now.rss) - mib(self.rss),
+        );
+    }
+}
+
+/// User and system CPU time used, as returned by getrusage().
+fn cpu_time(who: libc::c_int) -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+    let time = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Resident memory of the agent, in bytes.
+fn rss() -> u64 {
+    // size resident shared text lib data dt, in pages
+    let pages: u64 = read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse().ok())
+        .unwrap_or(0);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    pages * page_size.max(0) as u64
+}
@@ -1,22 +1,181 @@
-use std::{fs::read_to_string, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
 
+use anyhow::{anyhow, bail, Context};
+use log::{info, warn};
+use nix::sys::inotify::AddWatchFlags;
+use tokio::time::{timeout, Duration};
 use tonic::transport::{Certificate, Identity};
+use x509_parser::time::ASN1Time;
 
+use crate::{config::FactConfig, metrics, watch::Watcher};
+
+/// Time for a certificate rotation to settle before reloading, writers
+/// usually update the CA, certificate and key one after the other.
+const RELOAD_DELAY: Duration = Duration::from_secs(2);
+
+/// Location of the PEM files used for mTLS.
+#[derive(Debug, Clone)]
+pub struct CertPaths {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertPaths {
+    /// Resolve the certificate paths from the configuration, individual
+    /// paths take precedence over the certificates directory. Returns
+    /// `None` if no certificate was configured at all.
+    pub fn from_config(config: &FactConfig) -> anyhow::Result<Option<Self>> {
+        let grpc = &config.grpc;
+        let dir = config.certs.as_deref();
+        let resolve = |path: &Option<PathBuf>, name: &str| {
+            path.clone().or_else(|| dir.map(|d| d.join(name)))
+        };
+
+        let paths = (
+            resolve(&grpc.tls_ca, "ca.pem"),
+            resolve(&grpc.tls_cert, "cert.pem"),
+            resolve(&grpc.tls_key, "key.pem"),
+        );
+        match paths {
+            (None, None, None) => Ok(None),
+            (Some(ca), Some(cert), Some(key)) => Ok(Some(CertPaths { ca, cert, key })),
+            _ => bail!(
+                "Incomplete TLS configuration: a CA, certificate and key are all required, \
+                use --certs or --tls-ca, --tls-cert and --tls-key"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Certs {
     pub ca: Certificate,
     pub identity: Identity,
+    pub not_after: Option<ASN1Time>,
 }
 
-impl TryFrom<PathBuf> for Certs {
-    type Error = anyhow::Error;
+fn read_pem(path: &Path, what: &str) -> anyhow::Result<String> {
+    read_to_string(path).with_context(|| format!("Failed to read {what} from {}", path.display()))
+}
 
-    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        let ca = read_to_string(path.join("ca.pem"))?;
+impl Certs {
+    pub fn load(paths: &CertPaths) -> anyhow::Result<Self> {
+        let ca = read_pem(&paths.ca, "CA certificate")?;
         let ca = Certificate::from_pem(ca);
-        let cert = read_to_string(path.join("cert.pem"))?;
-        let key = read_to_string(path.join("key.pem"))?;
+        let cert = read_pem(&paths.cert, "client certificate")?;
+        let key = read_pem(&paths.key, "client key")?;
+        let not_after = match not_after(&cert) {
+            Ok(not_after) => Some(not_after),
+            Err(e) => {
+                warn!("Failed to read the expiry of {}: {e}", paths.cert.display());
+                None
+            }
+        };
         let identity = Identity::from_pem(cert, key);
 
-        Ok(Self { ca, identity })
+        Ok(Self {
+            ca,
+            identity,
+            not_after,
+        })
+    }
+
+    fn log_expiry(&self) {
+        match &self.not_after {
+            Some(not_after) => {
+                info!("Client certificate expires on {not_after}");
+                metrics::TLS_CERTIFICATE_EXPIRY.set(not_after.timestamp());
+            }
+            None => metrics::TLS_CERTIFICATE_EXPIRY.set(0),
+        }
+    }
+}
+
+fn not_after(pem: &str) -> anyhow::Result<ASN1Time> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem.as_bytes())
+        .map_err(|e| anyhow!("invalid PEM: {e}"))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow!("invalid certificate: {e}"))?;
+    Ok(cert.validity().not_after)
+}
+
+/// Certificates shared by every connection using them, reloaded when
+/// they are rotated on disk. New connections always use the latest
+/// certificates, established ones are left alone.
+#[derive(Debug, Clone)]
+pub struct CertStore {
+    paths: CertPaths,
+    current: Arc<RwLock<Certs>>,
+}
+
+impl CertStore {
+    /// Load the certificates and start watching them for changes.
+    pub fn new(paths: CertPaths) -> anyhow::Result<Self> {
+        let certs = Certs::load(&paths)?;
+        certs.log_expiry();
+
+        let store = CertStore {
+            paths,
+            current: Arc::new(RwLock::new(certs)),
+        };
+        let watched = store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watched.watch().await {
+                warn!("Certificate rotation disabled: {e:#}");
+            }
+        });
+
+        Ok(store)
+    }
+
+    pub fn current(&self) -> Certs {
+        self.current.read().unwrap().clone()
+    }
+
+    async fn watch(&self) -> anyhow::Result<()> {
+        let watcher = Watcher::new()?;
+
+        // Watch the directories rather than the files, rotations replace
+        // files (or the symlinks pointing to them in Kubernetes secrets)
+        // instead of writing to them.
+        let flags = AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_TO;
+        let dirs: BTreeSet<&Path> = [&self.paths.ca, &self.paths.cert, &self.paths.key]
+            .into_iter()
+            .map(|p| {
+                p.parent()
+                    .filter(|d| !d.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."))
+            })
+            .collect();
+        for dir in dirs {
+            watcher.add(dir, flags)?;
+        }
+
+        loop {
+            watcher.next().await?;
+            while let Ok(events) = timeout(RELOAD_DELAY, watcher.next()).await {
+                events?;
+            }
+
+            match Certs::load(&self.paths) {
+                Ok(certs) => {
+                    info!("Reloaded rotated TLS certificates");
+                    certs.log_expiry();
+                    metrics::TLS_CERTIFICATE_RELOADS_TOTAL.inc();
+                    *self.current.write().unwrap() = certs;
+                }
+                Err(e) => warn!("Failed to reload TLS certificates, keeping the current ones: {e:#}"),
+            }
+        }
     }
 }
@@ -1,48 +1,55 @@
-use std::{fs::read_to_string, path::PathBuf};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+};
 
-use anyhow::bail;
-use fact_api::{file_activity_service_client::FileActivityServiceClient, FileActivity};
+use anyhow::{bail, Context};
+use fact_api::FileActivity;
 use log::warn;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use prost::{bytes::BufMut, Message};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+use tokio_stream::Stream;
 use tonic::{
-    metadata::MetadataValue,
-    service::Interceptor,
-    transport::{Certificate, Channel, ClientTlsConfig, Identity},
+    client::Grpc,
+    codec::{Codec, EncodeBuf, Encoder, ProstCodec},
+    codegen::http::uri::PathAndQuery,
+    service::interceptor::InterceptedService,
+    Status,
 };
 
-use crate::event::Event;
-
-struct Certs {
-    pub ca: Certificate,
-    pub identity: Identity,
-}
+use crate::{
+    event::Event,
+    health,
+    identity::{self, MetadataInterceptor},
+    metrics,
+    transport::Transport,
+};
 
-impl TryFrom<PathBuf> for Certs {
-    type Error = anyhow::Error;
+const COMPONENT: &str = "file-activity";
+const COMMUNICATE: &str = "/sensor.FileActivityService/Communicate";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
-    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        let ca = read_to_string(path.join("ca.pem"))?;
-        let ca = Certificate::from_pem(ca);
-        let cert = read_to_string(path.join("cert.pem"))?;
-        let key = read_to_string(path.join("key.pem"))?;
-        let identity = Identity::from_pem(cert, key);
+/// Stream of events sharing the receiving end of the channel, so
+/// pending events survive reconnections to sensor.
+#[derive(Clone)]
+struct EventStream(Arc<Mutex<mpsc::Receiver<FileActivity>>>);
 
-        Ok(Self { ca, identity })
+impl EventStream {
+    fn is_done(&self) -> bool {
+        let rx = self.0.lock().unwrap();
+        rx.is_closed() && rx.is_empty()
     }
 }
 
-struct UserAgentInterceptor {}
-
-impl Interceptor for UserAgentInterceptor {
-    fn call(
-        &mut self,
-        mut request: tonic::Request<()>,
-    ) -> Result<tonic::Request<()>, tonic::Status> {
-        request
-            .metadata_mut()
-            .insert("user-agent", MetadataValue::from_static("Rox SFA Agent"));
-        Ok(request)
+impl Stream for EventStream {
+    type Item = FileActivity;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.lock().unwrap().poll_recv(cx)
     }
 }
 
@@ -51,37 +58,98 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn start(url: &str, certs: Option<PathBuf>) -> anyhow::Result<Self> {
+    pub fn start(url: &str, transport: Transport) -> anyhow::Result<Self> {
         let (tx, rx) = mpsc::channel(100);
-        let rx = ReceiverStream::new(rx);
+        let events = EventStream(Arc::new(Mutex::new(rx)));
         let url = url.to_owned();
 
-        tokio::spawn(async move {
-            let mut channel = Channel::from_shared(url).unwrap();
-            if let Some(certs) = certs.as_ref() {
-                let certs: Certs = certs.clone().try_into().unwrap();
-                let tls = ClientTlsConfig::new()
-                    .domain_name("sensor.stackrox.svc")
-                    .ca_certificate(certs.ca.clone())
-                    .identity(certs.identity.clone());
-                channel = channel.tls_config(tls).unwrap();
-            }
+        health::spawn(health::SENSOR, async move {
+            loop {
+                // A new connection is made every time, so rotated
+                // certificates are picked up.
+                if let Err(e) = Client::communicate(&transport, &url, events.clone()).await {
+                    warn!("{e:#}");
+                    health::set_not_ready(health::SENSOR, format!("{e:#}"));
+                }
 
-            let channel = channel.connect().await.unwrap();
-            let mut client =
-                FileActivityServiceClient::with_interceptor(channel, UserAgentInterceptor {});
-
-            if let Err(e) = client.communicate(rx).await {
-                warn!("Communication failed: {e}");
+                // Stop once the client is gone and every event was sent
+                if events.is_done() {
+                    return Ok(());
+                }
+                sleep(RECONNECT_DELAY).await;
             }
         });
         Ok(Client { tx })
     }
 
+    async fn communicate(
+        transport: &Transport,
+        url: &str,
+        events: EventStream,
+    ) -> anyhow::Result<()> {
+        let channel = transport.connect(url).await?;
+        let mut client = Grpc::new(InterceptedService::new(
+            channel,
+            MetadataInterceptor::new(COMPONENT),
+        ));
+        health::set_ready(health::SENSOR);
+
+        client.ready().await.context("Sensor is not ready")?;
+        client
+            .client_streaming(
+                tonic::Request::new(events),
+                PathAndQuery::from_static(COMMUNICATE),
+                IdentityCodec::default(),
+            )
+            .await
+            .context("Communication failed")?;
+        Ok(())
+    }
+
     pub async fn send(&mut self, event: Event) -> anyhow::Result<()> {
+        // Events are streamed to sensor as it reads them, only the time
+        // they wait for room in the queue is known here
+        let _timer = metrics::EVENT_QUEUE_DURATION.start_timer();
         if let Err(e) = self.tx.send(event.into()).await {
+            metrics::SENSOR_SEND_FAILURES_TOTAL
+                .with_label_values(&["file_activity"])
+                .inc();
             bail!("Failed to send event: {e}");
         }
         Ok(())
     }
 }
+
+/// Encodes file activities followed by the identity of the node, which
+/// has no field of its own in `FileActivity`.
+#[derive(Default)]
+struct IdentityCodec(ProstCodec<FileActivity, ()>);
+
+impl Codec for IdentityCodec {
+    type Encode = FileActivity;
+    type Decode = ();
+    type Encoder = IdentityEncoder;
+    type Decoder = <ProstCodec<FileActivity, ()> as Codec>::Decoder;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        IdentityEncoder
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        self.0.decoder()
+    }
+}
+
+struct IdentityEncoder;
+
+impl Encoder for IdentityEncoder {
+    type Item = FileActivity;
+    type Error = Status;
+
+    fn encode(&mut self, item: FileActivity, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        item.encode(dst)
+            .map_err(|e| Status::internal(format!("Failed to encode file activity: {e}")))?;
+        dst.put_slice(identity::get().encoded());
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Package collectors, each one reading the database of a package
+//! manager. The collector is picked from the distribution described in
+//! `/etc/os-release`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use fact_api::{
+    scanner::v4::{Distribution, Package, Repository},
+    storage::virtual_machine_scan::Note,
+};
+
+use crate::{budget::ScanBudget, config::FactConfig};
+
+mod apk;
+mod dpkg;
+mod language;
+mod rpm;
+
+pub use apk::Apk;
+pub use dpkg::Dpkg;
+pub use language::Language;
+pub use rpm::Rpm;
+
+/// Scanner V4 requires a CPE on every package, used when nothing more
+/// specific is known about it.
+pub const WILDCARD_CPE: &str = "cpe:2.3:*:*:*:*:*:*:*:*:*:*:*";
+
+/// CPE 2.3 of a package shipped by `vendor`, e.g.
+/// `cpe:2.3:a:redhat:bash:5.1.8:9.el9:*:*:*:*:x86_64:*`.
+pub fn package_cpe(vendor: &str, name: &str, version: &str, update: &str, arch: &str) -> String {
+    let [vendor, name, version, update, arch] = [vendor, name, version, update, arch].map(cpe_value);
+    format!("cpe:2.3:a:{vendor}:{name}:{version}:{update}:*:*:*:*:{arch}:*")
+}
+
+/// Escape a CPE 2.3 attribute, any character other than letters, digits,
+/// `_`, `-` and `.` has to be quoted. Empty values match anything.
+fn cpe_value(value: &str) -> String {
+    if value.is_empty() {
+        return "*".to_string();
+    }
+    value
+        .to_lowercase()
+        .chars()
+        .fold(String::with_capacity(value.len()), |mut escaped, c| {
+            if !c.is_ascii_alphanumeric() && !matches!(c, '_' | '-' | '.') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        })
+}
+
+/// Convert a CPE 2.2 URI (`cpe:/o:redhat:enterprise_linux:9::baseos`)
+/// to a CPE 2.3 formatted string, anything else is returned as is.
+pub fn cpe_from_uri(uri: &str) -> String {
+    let Some(components) = uri.strip_prefix("cpe:/") else {
+        return uri.to_string();
+    };
+    let mut values: Vec<String> = components.split(':').map(cpe_value).collect();
+    if values.len() > 7 {
+        return uri.to_string();
+    }
+    values.resize(11, "*".to_string());
+    format!("cpe:2.3:{}", values.join(":"))
+}
+
+/// The CPE 2.2 URI of a CPE 2.3 formatted string, which is how Red Hat
+/// names repositories.
+pub fn cpe_to_uri(cpe: &str) -> String {
+    let Some(components) = cpe.strip_prefix("cpe:2.3:") else {
+        return cpe.to_string();
+    };
+    let mut values: Vec<String> = components
+        .split(':')
+        .take(7)
+        .map(|value| match value {
+            "*" => String::new(),
+            value => value.replace('\\', ""),
+        })
+        .collect();
+    while values.last().is_some_and(String::is_empty) {
+        values.pop();
+    }
+    format!("cpe:/{}", values.join(":"))
+}
+
+/// Vendor of the distribution packages, from the system CPE when the
+/// distribution has one.
+fn vendor(ids: &[&str], system_cpe: &str) -> String {
+    match system_cpe.split(':').nth(3) {
+        Some(vendor) if !vendor.is_empty() && vendor != "*" => vendor.to_string(),
+        _ => match ids.first() {
+            Some(&"ubuntu") => "canonical".to_string(),
+            Some(&"alpine") => "alpinelinux".to_string(),
+            Some(id) => id.to_string(),
+            None => String::new(),
+        },
+    }
+}
+
+pub trait Collector: Send + Sync {
+    /// Name of the package manager, for logging
+    fn name(&self) -> &'static str;
+
+    /// Collect the installed packages, giving up once the scan is over
+    /// `budget`
+    fn collect(&mut self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>>;
+
+    /// Repositories the collected packages come from
+    fn repositories(&self) -> Vec<Repository> {
+        Vec::new()
+    }
+
+    /// Repositories a collected package comes from, all of them unless
+    /// the collector knows better
+    fn repository_ids(&self, _pkg: &Package) -> Vec<String> {
+        self.repositories().into_iter().map(|r| r.id).collect()
+    }
+
+    /// Files changed by the package manager when packages are installed
+    /// or removed, or directories holding a file per package
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Distribution to report, when the package manager needs more than
+    /// what is detected from os-release
+    fn distribution(&self, detected: &Distribution) -> Distribution {
+        detected.clone()
+    }
+
+    /// What a report is missing when this collector failed, the packages
+    /// of the operating system unless the collector knows better
+    fn failure_note(&self) -> Note {
+        Note::OsUnavailable
+    }
+}
+
+/// Pick the collector for the distribution described by `os_release`,
+/// using `ID` and falling back to `ID_LIKE` for derivatives.
+pub fn select(
+    config: &FactConfig,
+    os_release: &HashMap<String, String>,
+    system_cpe: &str,
+) -> Box<dyn Collector> {
+    let ids: Vec<&str> = ["ID", "ID_LIKE"]
+        .into_iter()
+        .filter_map(|key| os_release.get(key))
+        .flat_map(|ids| ids.split_whitespace())
+        .collect();
+
+    let vendor = vendor(&ids, system_cpe);
+
+    if ids.contains(&"alpine") {
+        Box::new(Apk::new(os_release, vendor))
+    } else if ids.iter().any(|&id| id == "debian" || id == "ubuntu") {
+        Box::new(Dpkg::new(vendor))
+    } else {
+        Box::new(Rpm::new(
+            &config.rpmdb,
+            config.repository_cpe_map.clone(),
+            system_cpe,
+            vendor,
+        ))
+    }
+}
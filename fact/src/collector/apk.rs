@@ -0,0 +1,208 @@
+use std::{collections::HashMap, fs::read_to_string, path::PathBuf};
+
+use anyhow::Context;
+use fact_api::scanner::v4::{Distribution, Package};
+use log::debug;
+
+use super::{package_cpe, Collector};
+use crate::{budget::ScanBudget, host_info};
+
+const INSTALLED: &str = "lib/apk/db/installed";
+
+/// Reads packages from the apk database of Alpine Linux.
+pub struct Apk {
+    distribution: Distribution,
+    vendor: String,
+}
+
+impl Apk {
+    pub fn new(os_release: &HashMap<String, String>, vendor: String) -> Self {
+        Apk {
+            distribution: distribution(os_release),
+            vendor,
+        }
+    }
+}
+
+impl Collector for Apk {
+    fn name(&self) -> &'static str {
+        "apk"
+    }
+
+    fn collect(&mut self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let path = host_info::get_host_mount().join(INSTALLED);
+        let content =
+            read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let pkgs = parse_installed(&content, &self.vendor, budget)?;
+        debug!("{pkgs:?}");
+        Ok(pkgs)
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        vec![host_info::get_host_mount().join(INSTALLED)]
+    }
+
+    fn distribution(&self, detected: &Distribution) -> Distribution {
+        Distribution {
+            arch: detected.arch.clone(),
+            ..self.distribution.clone()
+        }
+    }
+}
+
+/// Parse the installed database, made of one block of `X:value` lines
+/// per package.
+fn parse_installed(
+    content: &str,
+    vendor: &str,
+    budget: &ScanBudget,
+) -> anyhow::Result<Vec<Package>> {
+    let mut pkgs = Vec::new();
+    for block in content.split("\n\n") {
+        budget.check()?;
+        let fields: HashMap<&str, &str> = block
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .collect();
+        pkgs.extend(to_package(&fields, vendor));
+    }
+    Ok(pkgs)
+}
+
+fn to_package(fields: &HashMap<&str, &str>, vendor: &str) -> Option<Package> {
+    let name = *fields.get("P")?;
+    let version = *fields.get("V")?;
+    let arch = fields.get("A").copied().unwrap_or_default();
+
+    // Subpackages (e.g. libcrypto3) are built from an origin package
+    // (openssl), which is what the security database refers to.
+    let origin = fields.get("o").copied().unwrap_or(name);
+    let source = Package {
+        name: origin.to_string(),
+        version: version.to_string(),
+        kind: "source".to_string(),
+        cpe: package_cpe(vendor, origin, version, "", ""),
+        ..Default::default()
+    };
+
+    Some(Package {
+        id: format!("{name}-{version}"),
+        name: name.to_string(),
+        version: version.to_string(),
+        kind: "binary".to_string(),
+        source: Some(Box::new(source)),
+        package_db: INSTALLED.to_string(),
+        arch: arch.to_string(),
+        cpe: package_cpe(vendor, name, version, "", arch),
+        ..Default::default()
+    })
+}
+
+/// Alpine releases are identified by their major and minor versions
+/// (3.19), the security database is published per release as `v3.19`.
+fn distribution(os_release: &HashMap<String, String>) -> Distribution {
+    let version = os_release
+        .get("VERSION_ID")
+        .map(String::as_str)
+        .unwrap_or_default();
+    let release = version.split('.').take(2).collect::<Vec<_>>().join(".");
+    let cpe = if release.is_empty() {
+        String::new()
+    } else {
+        format!("cpe:2.3:o:alpinelinux:alpine_linux:{release}:*:*:*:*:*:*:*")
+    };
+
+    Distribution {
+        id: format!("alpine-{release}"),
+        did: "alpine".to_string(),
+        name: "Alpine Linux".to_string(),
+        version: format!("v{release}"),
+        version_id: release.clone(),
+        cpe,
+        pretty_name: format!("Alpine Linux v{release}"),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::config::{IoPriority, ScanConfig};
+
+    const INSTALLED_DB: &str = "\
+C:Q1abc=
+P:musl
+V:1.2.4-r2
+A:x86_64
+S:383152
+o:musl
+t:1694705611
+F:lib
+R:ld-musl-x86_64.so.1
+
+C:Q1def=
+P:libcrypto3
+V:3.1.4-r5
+A:x86_64
+o:openssl
+m:Natanael Copa <ncopa@alpinelinux.org>
+
+P:no-version
+A:x86_64
+";
+
+    fn budget() -> (ScanBudget, Arc<AtomicBool>) {
+        let cfg = ScanConfig {
+            nice: 0,
+            ionice: IoPriority::None,
+            timeout: 0,
+            memory_limit: None,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        (ScanBudget::new(&cfg, cancelled.clone()), cancelled)
+    }
+
+    #[test]
+    fn installed_db() {
+        let (budget, _) = budget();
+        let pkgs = parse_installed(INSTALLED_DB, "alpine", &budget).unwrap();
+        assert_eq!(pkgs.len(), 2);
+
+        assert_eq!(pkgs[0].id, "musl-1.2.4-r2");
+        assert_eq!(pkgs[0].arch, "x86_64");
+        assert_eq!(pkgs[0].source.as_ref().unwrap().name, "musl");
+
+        // Maintainers have colons of their own
+        assert_eq!(pkgs[1].name, "libcrypto3");
+        let source = pkgs[1].source.as_ref().unwrap();
+        assert_eq!(
+            (source.name.as_str(), source.version.as_str()),
+            ("openssl", "3.1.4-r5")
+        );
+        assert_eq!(source.kind, "source");
+    }
+
+    #[test]
+    fn cancelled() {
+        let (budget, cancelled) = budget();
+        cancelled.store(true, Ordering::Relaxed);
+        assert!(parse_installed(INSTALLED_DB, "alpine", &budget).is_err());
+    }
+
+    #[test]
+    fn alpine_release() {
+        let os_release = HashMap::from([("VERSION_ID".to_string(), "3.19.1".to_string())]);
+        let distribution = distribution(&os_release);
+        assert_eq!(distribution.id, "alpine-3.19");
+        assert_eq!(distribution.version, "v3.19");
+        assert_eq!(
+            distribution.cpe,
+            "cpe:2.3:o:alpinelinux:alpine_linux:3.19:*:*:*:*:*:*:*"
+        );
+    }
+}
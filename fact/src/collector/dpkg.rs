@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use fact_api::scanner::v4::Package;
+use log::{debug, warn};
+
+use super::{package_cpe, Collector};
+use crate::{budget::ScanBudget, host_info};
+
+const STATUS: &str = "var/lib/dpkg/status";
+/// Used by distroless images, one file per package without a `Status`
+const STATUS_DIR: &str = "var/lib/dpkg/status.d";
+
+/// Reads packages straight from the dpkg status database.
+pub struct Dpkg {
+    vendor: String,
+}
+
+impl Dpkg {
+    pub fn new(vendor: String) -> Self {
+        Dpkg { vendor }
+    }
+}
+
+impl Collector for Dpkg {
+    fn name(&self) -> &'static str {
+        "dpkg"
+    }
+
+    fn collect(&mut self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let root = host_info::get_host_mount();
+        let mut pkgs = Vec::new();
+        let mut found = false;
+
+        let status = root.join(STATUS);
+        if status.exists() {
+            found = true;
+            let content = read_to_string(&status)
+                .with_context(|| format!("Failed to read {}", status.display()))?;
+            pkgs.extend(parse_status(&content, STATUS, &self.vendor, budget)?);
+        }
+
+        let status_dir = root.join(STATUS_DIR);
+        if status_dir.is_dir() {
+            found = true;
+            pkgs.extend(read_status_dir(&status_dir, &self.vendor, budget)?);
+        }
+
+        if !found {
+            bail!(
+                "No dpkg database found in {}",
+                root.join("var/lib/dpkg").display()
+            );
+        }
+        debug!("{pkgs:?}");
+        Ok(pkgs)
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        let root = host_info::get_host_mount();
+        vec![root.join(STATUS), root.join(STATUS_DIR)]
+    }
+}
+
+fn read_status_dir(dir: &Path, vendor: &str, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+    let mut pkgs = Vec::new();
+    let entries = read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in entries {
+        budget.check()?;
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        if file_name.ends_with(".md5sums") || !path.is_file() {
+            continue;
+        }
+
+        match read_to_string(&path) {
+            Ok(content) => {
+                let package_db = format!("{STATUS_DIR}/{file_name}");
+                pkgs.extend(parse_status(&content, &package_db, vendor, budget)?);
+            }
+            Err(e) => warn!("Failed to read {}: {e}", path.display()),
+        }
+    }
+    Ok(pkgs)
+}
+
+/// Parse the stanzas of a dpkg status file, skipping packages that are
+/// not fully installed.
+fn parse_status(
+    content: &str,
+    package_db: &str,
+    vendor: &str,
+    budget: &ScanBudget,
+) -> anyhow::Result<Vec<Package>> {
+    let mut pkgs = Vec::new();
+    for stanza in content.split("\n\n") {
+        budget.check()?;
+        pkgs.extend(to_package(&parse_stanza(stanza), package_db, vendor));
+    }
+    Ok(pkgs)
+}
+
+fn parse_stanza(stanza: &str) -> HashMap<&str, &str> {
+    // Continuation lines (starting with a space) only appear in fields
+    // we don't need, like Description and Conffiles.
+    stanza
+        .lines()
+        .filter(|line| !line.starts_with([' ', '\t']))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key, value.trim()))
+        .collect()
+}
+
+fn to_package(fields: &HashMap<&str, &str>, package_db: &str, vendor: &str) -> Option<Package> {
+    let name = *fields.get("Package")?;
+    let version = *fields.get("Version")?;
+    if let Some(status) = fields.get("Status") {
+        if status.split_whitespace().last() != Some("installed") {
+            return None;
+        }
+    }
+    let arch = fields.get("Architecture").copied().unwrap_or_default();
+
+    // "Source: name (version)" when the versions differ, "Source: name"
+    // when they don't and no Source at all when the names match too.
+    let (source_name, source_version) = match fields.get("Source") {
+        Some(source) => match source.split_once(' ') {
+            Some((name, version)) => (name, version.trim_matches(['(', ')', ' '])),
+            None => (*source, version),
+        },
+        None => (name, version),
+    };
+
+    let source = Package {
+        name: source_name.to_string(),
+        version: source_version.to_string(),
+        kind: "source".to_string(),
+        cpe: package_cpe(vendor, source_name, source_version, "", ""),
+        ..Default::default()
+    };
+
+    Some(Package {
+        // Multiarch packages share their name and version
+        id: format!("{name}-{version}.{arch}"),
+        name: name.to_string(),
+        version: version.to_string(),
+        kind: "binary".to_string(),
+        source: Some(Box::new(source)),
+        package_db: package_db.to_string(),
+        arch: arch.to_string(),
+        cpe: package_cpe(vendor, name, version, "", arch),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::config::{IoPriority, ScanConfig};
+
+    const STATUS_FILE: &str = "\
+Package: libssl3
+Status: install ok installed
+Architecture: amd64
+Source: openssl (3.0.11-1~deb12u2)
+Version: 3.0.11-1~deb12u2+b1
+Description: Secure Sockets Layer toolkit - shared libraries
+ This package is part of the OpenSSL project's implementation of the SSL
+ and TLS cryptographic protocols.
+ Version: 0.0.0
+ .
+ Package: not-a-package
+
+Package: base-files
+Status: install ok installed
+Architecture: amd64
+Version: 12.4+deb12u5
+Conffiles:
+ /etc/debian_version 1a2b3c
+ /etc/issue 4d5e6f
+
+Package: removed
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0
+
+Package: tzdata
+Status: install ok installed
+Architecture: all
+Source: tzdata-legacy
+Version: 2024a-0+deb12u1
+";
+
+    #[test]
+    fn continuation_lines() {
+        let fields = parse_stanza(STATUS_FILE.split("\n\n").next().unwrap());
+        assert_eq!(fields["Package"], "libssl3");
+        assert_eq!(fields["Version"], "3.0.11-1~deb12u2+b1");
+        assert_eq!(
+            fields["Description"],
+            "Secure Sockets Layer toolkit - shared libraries"
+        );
+        assert_eq!(fields.len(), 6);
+    }
+
+    fn budget() -> (ScanBudget, Arc<AtomicBool>) {
+        let cfg = ScanConfig {
+            nice: 0,
+            ionice: IoPriority::None,
+            timeout: 0,
+            memory_limit: None,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        (ScanBudget::new(&cfg, cancelled.clone()), cancelled)
+    }
+
+    #[test]
+    fn status_file() {
+        let (budget, _) = budget();
+        let pkgs = parse_status(STATUS_FILE, STATUS, "debian", &budget).unwrap();
+        let ids: Vec<_> = pkgs.iter().map(|pkg| pkg.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "libssl3-3.0.11-1~deb12u2+b1.amd64",
+                "base-files-12.4+deb12u5.amd64",
+                "tzdata-2024a-0+deb12u1.all",
+            ]
+        );
+
+        let source = |pkg: &Package| {
+            let source = pkg.source.as_ref().unwrap();
+            (source.name.clone(), source.version.clone())
+        };
+        assert_eq!(
+            source(&pkgs[0]),
+            ("openssl".to_string(), "3.0.11-1~deb12u2".to_string())
+        );
+        assert_eq!(
+            source(&pkgs[1]),
+            ("base-files".to_string(), "12.4+deb12u5".to_string())
+        );
+        assert_eq!(
+            source(&pkgs[2]),
+            ("tzdata-legacy".to_string(), "2024a-0+deb12u1".to_string())
+        );
+        assert!(pkgs.iter().all(|pkg| pkg.package_db == STATUS));
+    }
+
+    #[test]
+    fn cancelled() {
+        let (budget, cancelled) = budget();
+        cancelled.store(true, Ordering::Relaxed);
+        assert!(parse_status(STATUS_FILE, STATUS, "debian", &budget).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Discovery of the packages installed by language ecosystems (Python,
+//! Node.js, Java and Go) by walking the filesystem of the VM.
+
+use std::{
+    fs::read_dir,
+    ops::ControlFlow,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use fact_api::{
+    scanner::v4::{Package, Repository},
+    storage::virtual_machine_scan::Note,
+};
+use log::{debug, info, warn};
+
+use super::{Collector, WILDCARD_CPE};
+use crate::{budget::ScanBudget, config::LanguageConfig, host_info};
+
+mod go;
+mod java;
+mod npm;
+mod python;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ecosystem {
+    Python,
+    Npm,
+    Maven,
+    Go,
+}
+
+const ECOSYSTEMS: [Ecosystem; 4] = [
+    Ecosystem::Python,
+    Ecosystem::Npm,
+    Ecosystem::Maven,
+    Ecosystem::Go,
+];
+
+impl Ecosystem {
+    /// Prefix of the package database of the packages, as expected by
+    /// Scanner V4
+    fn db(&self) -> &'static str {
+        match self {
+            Ecosystem::Python => "python",
+            Ecosystem::Npm => "nodejs",
+            Ecosystem::Maven => "maven",
+            Ecosystem::Go => "go",
+        }
+    }
+
+    fn repository(&self) -> Repository {
+        let (name, uri) = match self {
+            Ecosystem::Python => ("pypi", "https://pypi.org/simple"),
+            Ecosystem::Npm => ("npm", "https://www.npmjs.com/"),
+            Ecosystem::Maven => ("maven", "https://repo1.maven.apache.org/maven2"),
+            Ecosystem::Go => ("go", "https://pkg.go.dev/"),
+        };
+        Repository {
+            id: name.to_string(),
+            name: name.to_string(),
+            uri: uri.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A package found on disk.
+#[derive(Debug)]
+struct Found {
+    ecosystem: Ecosystem,
+    name: String,
+    version: String,
+    /// Where the package was found: the site-packages directory, the
+    /// package directory, the JAR or the binary
+    location: PathBuf,
+}
+
+/// Searches directories for language packages, within a time budget.
+pub struct Language {
+    roots: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+    budget: Duration,
+}
+
+impl Language {
+    pub fn new(config: &LanguageConfig) -> Self {
+        let host = |path: &PathBuf| {
+            host_info::get_host_mount().join(path.strip_prefix("/").unwrap_or(path))
+        };
+        Language {
+            roots: config.roots.iter().map(host).collect(),
+            exclude: config.exclude.iter().map(host).collect(),
+            budget: Duration::from_secs(config.scan_timeout),
+        }
+    }
+
+    /// Search `root`, until `deadline` or failing once the scan is over
+    /// `budget`.
+    fn walk(
+        &self,
+        root: &Path,
+        deadline: Instant,
+        budget: &ScanBudget,
+        found: &mut Vec<Found>,
+    ) -> anyhow::Result<ControlFlow<()>> {
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    debug!("Failed to list {}: {e}", dir.display());
+                    continue;
+                }
+            };
+
+            for entry in entries.flatten() {
+                if Instant::now() > deadline {
+                    return Ok(ControlFlow::Break(()));
+                }
+                budget.check()?;
+
+                let path = entry.path();
+                if self.exclude.contains(&path) {
+                    continue;
+                }
+                // Symbolic links are not followed, so nothing is reported twice
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+
+                let res = if file_type.is_dir() {
+                    if name.ends_with(".dist-info") || name.ends_with(".egg-info") {
+                        python::read(&path).map(|pkg| found.extend(pkg))
+                    } else {
+                        dirs.push(path);
+                        continue;
+                    }
+                } else if !file_type.is_file() {
+                    continue;
+                } else if name.ends_with(".egg-info") {
+                    python::read(&path).map(|pkg| found.extend(pkg))
+                } else if name == "package.json" {
+                    npm::read(&path).map(|pkg| found.extend(pkg))
+                } else if name.ends_with(".jar") || name.ends_with(".war") || name.ends_with(".ear") {
+                    java::read(&path).map(|pkgs| found.extend(pkgs))
+                } else if is_executable(&entry) {
+                    go::read(&path).map(|pkgs| found.extend(pkgs))
+                } else {
+                    continue;
+                };
+
+                if let Err(e) = res {
+                    debug!("Failed to read packages from {}: {e:#}", path.display());
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+fn is_executable(entry: &std::fs::DirEntry) -> bool {
+    entry
+        .metadata()
+        .is_ok_and(|m| m.len() > 0 && m.permissions().mode() & 0o111 != 0)
+}
+
+impl Collector for Language {
+    fn name(&self) -> &'static str {
+        "language"
+    }
+
+    fn collect(&mut self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let start = Instant::now();
+        let deadline = start + self.budget;
+        let mut found = Vec::new();
+        for root in self.roots.iter().filter(|root| !self.exclude.contains(root)) {
+            if self.walk(root, deadline, budget, &mut found)?.is_break() {
+                warn!(
+                    "Language package search took more than {}s, reporting the {} packages found so far",
+                    self.budget.as_secs(),
+                    found.len()
+                );
+                break;
+            }
+        }
+        info!("Found {} language packages in {:?}", found.len(), start.elapsed());
+
+        let root = host_info::get_host_mount();
+        let pkgs = found
+            .into_iter()
+            .map(|found| {
+                let location = found.location.strip_prefix(root).unwrap_or(&found.location);
+                let package_db = format!("{}:{}", found.ecosystem.db(), location.display());
+                Package {
+                    id: format!("{}-{}@{package_db}", found.name, found.version),
+                    name: found.name,
+                    version: found.version,
+                    kind: "binary".to_string(),
+                    package_db,
+                    cpe: WILDCARD_CPE.to_string(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Ok(pkgs)
+    }
+
+    fn repositories(&self) -> Vec<Repository> {
+        ECOSYSTEMS.iter().map(Ecosystem::repository).collect()
+    }
+
+    fn repository_ids(&self, pkg: &Package) -> Vec<String> {
+        let db = pkg.package_db.split_once(':').map(|(db, _)| db);
+        ECOSYSTEMS
+            .iter()
+            .filter(|e| Some(e.db()) == db)
+            .map(|e| e.repository().id)
+            .collect()
+    }
+
+    fn failure_note(&self) -> Note {
+        Note::LanguageCvesUnavailable
+    }
+}
@@ -0,0 +1,328 @@
+//! Build information embedded by the Go toolchain in the binaries it
+//! produces, the equivalent of `go version -m`.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+
+use super::{Ecosystem, Found};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const BUILDINFO_SECTION: &[u8] = b".go.buildinfo";
+const BUILDINFO_MAGIC: &[u8] = b"\xff Go buildinf:";
+/// Set when the version and module information are stored inline,
+/// which is the case since Go 1.18
+const FLAG_INLINE: u8 = 0x2;
+
+const MAX_SECTIONS: usize = 1024;
+const MAX_SECTION_SIZE: u64 = 1024 * 1024;
+
+/// Read the modules a Go binary was built from, along with the standard
+/// library of the toolchain used to build it. Anything that is not a Go
+/// ELF binary yields nothing.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Found>> {
+    let mut file = File::open(path)?;
+    let Some(buildinfo) = buildinfo_section(&mut file)? else {
+        return Ok(Vec::new());
+    };
+    ensure!(
+        buildinfo.starts_with(BUILDINFO_MAGIC),
+        "invalid build information"
+    );
+    let flags = *buildinfo.get(15).context("truncated build information")?;
+    ensure!(
+        flags & FLAG_INLINE != 0,
+        "binaries built before Go 1.18 are not supported"
+    );
+
+    let mut data = buildinfo.get(32..).context("truncated build information")?;
+    let go_version = read_string(&mut data)
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .context("invalid Go version")?;
+    let modinfo = read_string(&mut data)
+        .and_then(modinfo)
+        .context("invalid module information")?;
+
+    let found = |name: &str, version: &str| Found {
+        ecosystem: Ecosystem::Go,
+        name: name.to_string(),
+        version: version.to_string(),
+        location: path.to_path_buf(),
+    };
+
+    // Toolchain experiments are appended, as in `go1.23.1 X:nocoverageredesign`
+    let go_version = go_version.split_whitespace().next().unwrap_or_default();
+    let mut modules = vec![found("stdlib", go_version.trim_start_matches("go"))];
+    // Whether the last module line was kept, so a replacement following
+    // a skipped `(devel)` module does not overwrite the one before it
+    let mut kept = false;
+    for line in modinfo.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["mod" | "dep", path, version, ..] => {
+                kept = *version != "(devel)";
+                if kept {
+                    modules.push(found(path, version));
+                }
+            }
+            // Replacement of the previous module, local directories have
+            // no version and are dropped like `(devel)` modules
+            ["=>", path, version, ..] if kept => {
+                modules.pop();
+                kept = !version.is_empty() && *version != "(devel)";
+                if kept {
+                    modules.push(found(path, version));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(modules)
+}
+
+/// The module information is wrapped in 16 bytes sentinels, which are
+/// not valid UTF-8.
+fn modinfo(data: &[u8]) -> Option<&str> {
+    let data = if data.len() >= 33 {
+        &data[16..data.len() - 16]
+    } else {
+        data
+    };
+    std::str::from_utf8(data).ok()
+}
+
+fn read_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 28 {
+            return None;
+        }
+    }
+    let string = data.get(..len)?;
+    *data = &data[len..];
+    Some(string)
+}
+
+struct Elf {
+    is_64: bool,
+    big_endian: bool,
+}
+
+impl Elf {
+    fn uint(&self, buf: &[u8], offset: usize, size: usize) -> u64 {
+        let bytes = &buf[offset..offset + size];
+        let fold = |value: u64, byte: &u8| (value << 8) | *byte as u64;
+        if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    file.take(len).read_to_end(&mut buf)?;
+    ensure!(buf.len() as u64 == len, "truncated file");
+    Ok(buf)
+}
+
+/// Find the `.go.buildinfo` section, reading no more of the binary than
+/// its headers and that section.
+fn buildinfo_section(file: &mut File) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut ident = [0; 64];
+    if file.read_exact(&mut ident).is_err() || !ident.starts_with(ELF_MAGIC) {
+        return Ok(None);
+    }
+    let elf = Elf {
+        is_64: ident[4] == 2,
+        big_endian: ident[5] == 2,
+    };
+
+    let (shoff, shentsize, shnum, shstrndx) = if elf.is_64 {
+        (
+            elf.uint(&ident, 0x28, 8),
+            elf.uint(&ident, 0x3a, 2),
+            elf.uint(&ident, 0x3c, 2),
+            elf.uint(&ident, 0x3e, 2),
+        )
+    } else {
+        (
+            elf.uint(&ident, 0x20, 4),
+            elf.uint(&ident, 0x2e, 2),
+            elf.uint(&ident, 0x30, 2),
+            elf.uint(&ident, 0x32, 2),
+        )
+    };
+    let min_entsize = if elf.is_64 { 64 } else { 40 };
+    if shnum == 0 || shnum as usize > MAX_SECTIONS || shentsize < min_entsize || shstrndx >= shnum {
+        return Ok(None);
+    }
+
+    let headers = read_at(file, shoff, shnum * shentsize)?;
+    // Offset and size of a section
+    let section = |i: u64| {
+        let header = &headers[(i * shentsize) as usize..];
+        let name = elf.uint(header, 0, 4);
+        if elf.is_64 {
+            (name, elf.uint(header, 24, 8), elf.uint(header, 32, 8))
+        } else {
+            (name, elf.uint(header, 16, 4), elf.uint(header, 20, 4))
+        }
+    };
+
+    let (_, strtab_offset, strtab_size) = section(shstrndx);
+    let strtab = read_at(file, strtab_offset, strtab_size.min(MAX_SECTION_SIZE))?;
+    for i in 0..shnum {
+        let (name, offset, size) = section(i);
+        let name = strtab.get(name as usize..).unwrap_or_default();
+        if name.starts_with(BUILDINFO_SECTION) && name.get(BUILDINFO_SECTION.len()) == Some(&0) {
+            return read_at(file, offset, size.min(MAX_SECTION_SIZE)).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    fn varint(mut n: usize, buf: &mut Vec<u8>) {
+        while n >= 0x80 {
+            buf.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+
+    fn buildinfo(go_version: &str, modinfo: &str) -> Vec<u8> {
+        let mut buf = BUILDINFO_MAGIC.to_vec();
+        buf.extend([8, FLAG_INLINE]);
+        buf.resize(32, 0);
+        varint(go_version.len(), &mut buf);
+        buf.extend(go_version.as_bytes());
+        let modinfo = [&[0xffu8; 16][..], modinfo.as_bytes(), &[0xff; 16]].concat();
+        varint(modinfo.len(), &mut buf);
+        buf.extend(modinfo);
+        buf
+    }
+
+    /// A 64-bit little-endian ELF file with only a section name table
+    /// and a `.go.buildinfo` section.
+    fn elf(buildinfo: &[u8]) -> Vec<u8> {
+        let strtab = b"\0.shstrtab\0.go.buildinfo\0";
+        let strtab_offset = 64u64;
+        let buildinfo_offset = strtab_offset + strtab.len() as u64;
+        let shoff = buildinfo_offset + buildinfo.len() as u64;
+
+        let mut buf = vec![0; 64];
+        buf[..4].copy_from_slice(ELF_MAGIC);
+        buf[4] = 2;
+        buf[5] = 1;
+        buf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+        buf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        buf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+        buf[0x3e..0x40].copy_from_slice(&1u16.to_le_bytes());
+        buf.extend(strtab);
+        buf.extend(buildinfo);
+
+        let sections = [
+            (0u32, 0u64, 0u64),
+            (1, strtab_offset, strtab.len() as u64),
+            (11, buildinfo_offset, buildinfo.len() as u64),
+        ];
+        for (name, offset, size) in sections {
+            let mut header = vec![0; 64];
+            header[..4].copy_from_slice(&name.to_le_bytes());
+            header[24..32].copy_from_slice(&offset.to_le_bytes());
+            header[32..40].copy_from_slice(&size.to_le_bytes());
+            buf.extend(header);
+        }
+        buf
+    }
+
+    fn read_binary(name: &str, content: &[u8]) -> anyhow::Result<Vec<Found>> {
+        let dir = std::env::temp_dir().join(format!("fact-go-{}-{name}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        write(&path, content).unwrap();
+        let found = read(&path);
+        remove_dir_all(&dir).unwrap();
+        found
+    }
+
+    fn modules(found: &[Found]) -> Vec<(&str, &str)> {
+        found
+            .iter()
+            .map(|found| (found.name.as_str(), found.version.as_str()))
+            .collect()
+    }
+
+    const MODINFO: &str = "\
+path\texample.com/cmd/app
+mod\texample.com/cmd/app\t(devel)\t
+dep\tgithub.com/pkg/errors\tv0.9.1\th1:abc=
+dep\tgolang.org/x/net\tv0.17.0\th1:def=
+=>\tgithub.com/fork/net\tv0.17.1\th1:ghi=
+dep\texample.com/internal\t(devel)\t
+=>\texample.com/internal-fork\tv1.0.0\th1:jkl=
+dep\tgolang.org/x/sys\tv0.15.0\th1:mno=
+=>\t../sys\t\t
+build\t-compiler=gc
+";
+
+    #[test]
+    fn modinfo_replacements() {
+        let found = read_binary(
+            "app",
+            &elf(&buildinfo("go1.22.1 X:nocoverageredesign", MODINFO)),
+        )
+        .unwrap();
+        assert_eq!(
+            modules(&found),
+            [
+                ("stdlib", "1.22.1"),
+                ("github.com/pkg/errors", "v0.9.1"),
+                ("github.com/fork/net", "v0.17.1"),
+            ]
+        );
+        assert!(found.iter().all(|found| found.ecosystem == Ecosystem::Go));
+    }
+
+    #[test]
+    fn not_go() {
+        assert!(read_binary("script", b"#!/bin/sh\n").unwrap().is_empty());
+        // An ELF file without build information
+        let mut elf = elf(b"");
+        elf[0x3c..0x3e].copy_from_slice(&2u16.to_le_bytes());
+        assert!(read_binary("elf", &elf).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_buildinfo() {
+        let mut old = buildinfo("go1.17", MODINFO);
+        old[15] = 0;
+        assert!(read_binary("old", &elf(&old)).is_err());
+
+        let mut truncated = buildinfo("go1.22.1", MODINFO);
+        truncated.truncate(40);
+        assert!(read_binary("truncated", &elf(&truncated)).is_err());
+
+        assert!(read_binary("magic", &elf(b"not a buildinfo section")).is_err());
+    }
+}
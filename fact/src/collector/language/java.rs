@@ -0,0 +1,210 @@
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+
+use anyhow::Context;
+use zip::ZipArchive;
+
+use super::{Ecosystem, Found};
+
+/// Metadata files are tiny, don't let a bogus archive make us read more.
+const MAX_ENTRY_SIZE: u64 = 1024 * 1024;
+
+/// Read the Maven coordinates of a JAR (or WAR, EAR) from the
+/// `pom.properties` it embeds, one per artifact for shaded JARs, falling
+/// back to its manifest.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Found>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = ZipArchive::new(file)
+        .with_context(|| format!("Failed to read archive {}", path.display()))?;
+
+    let poms: Vec<String> = archive
+        .file_names()
+        .filter(|n| n.starts_with("META-INF/maven/") && n.ends_with("/pom.properties"))
+        .map(str::to_owned)
+        .collect();
+
+    let mut found = Vec::new();
+    for pom in poms {
+        let content = read_entry(&mut archive, &pom)?;
+        let props = properties(&content);
+        if let (Some(group), Some(artifact), Some(version)) = (
+            props.get("groupId"),
+            props.get("artifactId"),
+            props.get("version"),
+        ) {
+            found.push(Found {
+                ecosystem: Ecosystem::Maven,
+                name: format!("{group}:{artifact}"),
+                version: version.to_string(),
+                location: path.to_path_buf(),
+            });
+        }
+    }
+
+    if found.is_empty() && archive.index_for_name("META-INF/MANIFEST.MF").is_some() {
+        let content = read_entry(&mut archive, "META-INF/MANIFEST.MF")?;
+        let manifest = manifest(&content);
+        let name = manifest
+            .get("Bundle-SymbolicName")
+            .or_else(|| manifest.get("Implementation-Title"));
+        let version = manifest
+            .get("Bundle-Version")
+            .or_else(|| manifest.get("Implementation-Version"));
+        if let (Some(name), Some(version)) = (name, version) {
+            // OSGi bundle names may carry directives, e.g. "name;singleton:=true"
+            let name = name.split(';').next().unwrap_or_default();
+            found.push(Found {
+                ecosystem: Ecosystem::Maven,
+                name: name.to_string(),
+                version: version.to_string(),
+                location: path.to_path_buf(),
+            });
+        }
+    }
+    Ok(found)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> anyhow::Result<String> {
+    let entry = archive.by_name(name)?;
+    let mut content = String::new();
+    entry
+        .take(MAX_ENTRY_SIZE)
+        .read_to_string(&mut content)
+        .with_context(|| format!("Failed to read {name}"))?;
+    Ok(content)
+}
+
+fn properties(content: &str) -> HashMap<&str, &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#') && !l.starts_with('!'))
+        .filter_map(|l| l.split_once(['=', ':']))
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .collect()
+}
+
+/// Parse the main section of a manifest, joining continuation lines.
+fn manifest(content: &str) -> HashMap<String, String> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in content.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(continuation) = line.strip_prefix(' ') {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push_str(continuation);
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            fields.push((key.to_string(), value.trim_start().to_string()));
+        }
+    }
+    fields.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{create_dir_all, remove_dir_all},
+        io::Write,
+    };
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn read_archive(name: &str, entries: &[(&str, &str)]) -> Vec<Found> {
+        let dir = std::env::temp_dir().join(format!("fact-java-{}-{name}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        for (name, content) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let found = read(&path).unwrap();
+        remove_dir_all(&dir).unwrap();
+        found
+    }
+
+    fn coordinates(found: &[Found]) -> Vec<(&str, &str)> {
+        found
+            .iter()
+            .map(|found| (found.name.as_str(), found.version.as_str()))
+            .collect()
+    }
+
+    const MANIFEST: &str = "Manifest-Version: 1.0\r\n\
+Bundle-SymbolicName: org.eclipse.jetty.util;singleton:=tr\r\n ue\r\n\
+Bundle-Version: 9.4.53.v20231009\r\n\
+Implementation-Title: Jetty Utilities\r\n\
+Implementation-Version: 9.4.53\r\n\
+\r\n\
+Name: org/eclipse/jetty/util/\r\n\
+Bundle-Version: 0.0.0\r\n";
+
+    #[test]
+    fn pom_properties() {
+        let found = read_archive(
+            "shaded.jar",
+            &[
+                ("META-INF/MANIFEST.MF", MANIFEST),
+                (
+                    "META-INF/maven/org.apache.logging.log4j/log4j-core/pom.properties",
+                    "#Created by Apache Maven\ngroupId=org.apache.logging.log4j\nartifactId=log4j-core\nversion=2.17.1\n",
+                ),
+                (
+                    "META-INF/maven/com.google.guava/guava/pom.properties",
+                    "groupId: com.google.guava\nartifactId: guava\nversion: 32.1.3-jre\n",
+                ),
+                // Incomplete
+                (
+                    "META-INF/maven/com.example/example/pom.properties",
+                    "groupId=com.example\n",
+                ),
+            ],
+        );
+        let mut coordinates = coordinates(&found);
+        coordinates.sort();
+        assert_eq!(
+            coordinates,
+            [
+                ("com.google.guava:guava", "32.1.3-jre"),
+                ("org.apache.logging.log4j:log4j-core", "2.17.1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_fallback() {
+        let found = read_archive("jetty-util.jar", &[("META-INF/MANIFEST.MF", MANIFEST)]);
+        assert_eq!(
+            coordinates(&found),
+            [("org.eclipse.jetty.util", "9.4.53.v20231009")]
+        );
+
+        let found = read_archive(
+            "app.war",
+            &[(
+                "META-INF/MANIFEST.MF",
+                "Implementation-Title: app\nImplementation-Version: 1.0\n",
+            )],
+        );
+        assert_eq!(coordinates(&found), [("app", "1.0")]);
+
+        assert!(read_archive("empty.jar", &[("README", "")]).is_empty());
+    }
+
+    #[test]
+    fn manifest_sections() {
+        let manifest = manifest(MANIFEST);
+        assert_eq!(
+            manifest["Bundle-SymbolicName"],
+            "org.eclipse.jetty.util;singleton:=true"
+        );
+        // Per-entry sections are ignored
+        assert_eq!(manifest["Bundle-Version"], "9.4.53.v20231009");
+        assert!(!manifest.contains_key("Name"));
+    }
+}
@@ -0,0 +1,95 @@
+use std::{ffi::OsStr, fs::read_to_string, path::Path};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{Ecosystem, Found};
+
+/// Read the `package.json` of a package installed in a `node_modules`
+/// directory, either `node_modules/<name>` or `node_modules/@scope/<name>`.
+pub fn read(path: &Path) -> anyhow::Result<Option<Found>> {
+    let Some(dir) = path.parent() else {
+        return Ok(None);
+    };
+    let mut parent = dir.parent();
+    if parent
+        .and_then(Path::file_name)
+        .is_some_and(|n| n.to_string_lossy().starts_with('@'))
+    {
+        parent = parent.and_then(Path::parent);
+    }
+    if parent.and_then(Path::file_name) != Some(OsStr::new("node_modules")) {
+        return Ok(None);
+    }
+
+    let content =
+        read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let manifest: Value = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let (Some(name), Some(version)) = (manifest["name"].as_str(), manifest["version"].as_str())
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(Found {
+        ecosystem: Ecosystem::Npm,
+        name: name.to_string(),
+        version: version.to_string(),
+        location: dir.to_path_buf(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    #[test]
+    fn package_names() {
+        let root = std::env::temp_dir().join(format!("fact-npm-{}", std::process::id()));
+        let manifest = |dir: &str, content: &str| {
+            let dir = root.join(dir);
+            create_dir_all(&dir).unwrap();
+            write(dir.join("package.json"), content).unwrap();
+            dir.join("package.json")
+        };
+
+        let scoped = manifest(
+            "node_modules/@angular/core",
+            r#"{"name": "@angular/core", "version": "17.0.0"}"#,
+        );
+        let found = read(&scoped).unwrap().unwrap();
+        assert_eq!(
+            (found.name.as_str(), found.version.as_str()),
+            ("@angular/core", "17.0.0")
+        );
+        assert_eq!(found.location, root.join("node_modules/@angular/core"));
+
+        let unscoped = manifest(
+            "node_modules/lodash",
+            r#"{"name": "lodash", "version": "4.17.21"}"#,
+        );
+        let found = read(&unscoped).unwrap().unwrap();
+        assert_eq!(
+            (found.name.as_str(), found.version.as_str()),
+            ("lodash", "4.17.21")
+        );
+
+        // Only packages installed in node_modules
+        let app = manifest("srv/app", r#"{"name": "app", "version": "1.0.0"}"#);
+        assert!(read(&app).unwrap().is_none());
+        let nested = manifest(
+            "node_modules/lodash/fp",
+            r#"{"name": "fp", "version": "1.0.0"}"#,
+        );
+        assert!(read(&nested).unwrap().is_none());
+
+        let unversioned = manifest("node_modules/private", r#"{"name": "private"}"#);
+        assert!(read(&unversioned).unwrap().is_none());
+        let invalid = manifest("node_modules/invalid", "{");
+        assert!(read(&invalid).is_err());
+
+        remove_dir_all(&root).unwrap();
+    }
+}
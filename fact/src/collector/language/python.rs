@@ -0,0 +1,122 @@
+use std::{fs::read_to_string, path::Path};
+
+use anyhow::Context;
+
+use super::{Ecosystem, Found};
+
+/// Read a `*.dist-info` or `*.egg-info` directory, or an `*.egg-info`
+/// file, whose metadata are in the email header format.
+pub fn read(path: &Path) -> anyhow::Result<Option<Found>> {
+    let metadata = if path.is_dir() {
+        let file = if path.extension().is_some_and(|e| e == "dist-info") {
+            "METADATA"
+        } else {
+            "PKG-INFO"
+        };
+        path.join(file)
+    } else {
+        path.to_path_buf()
+    };
+    let content = read_to_string(&metadata)
+        .with_context(|| format!("Failed to read {}", metadata.display()))?;
+
+    let mut name = None;
+    let mut version = None;
+    for line in content.lines().take_while(|l| !l.is_empty()) {
+        match line.split_once(':') {
+            Some(("Name", value)) => name = Some(value.trim()),
+            Some(("Version", value)) => version = Some(value.trim()),
+            _ => {}
+        }
+    }
+    let (Some(name), Some(version)) = (name, version) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Found {
+        ecosystem: Ecosystem::Python,
+        name: normalize(name),
+        version: version.to_string(),
+        location: path.parent().unwrap_or(path).to_path_buf(),
+    }))
+}
+
+/// Normalize a project name as described in PEP 503.
+fn normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    const METADATA: &str = "\
+Metadata-Version: 2.1
+Name: Flask_SQLAlchemy
+Version: 3.1.1
+Summary: Add SQLAlchemy support to your Flask application.
+
+Name: not-the-name
+Version: 0.0.0
+";
+
+    #[test]
+    fn metadata() {
+        let root = std::env::temp_dir().join(format!("fact-python-{}", std::process::id()));
+        let site_packages = root.join("site-packages");
+
+        let dist_info = site_packages.join("Flask_SQLAlchemy-3.1.1.dist-info");
+        create_dir_all(&dist_info).unwrap();
+        write(dist_info.join("METADATA"), METADATA).unwrap();
+        let found = read(&dist_info).unwrap().unwrap();
+        assert_eq!(
+            (found.name.as_str(), found.version.as_str()),
+            ("flask-sqlalchemy", "3.1.1")
+        );
+        assert_eq!(found.location, site_packages);
+
+        let egg_info = site_packages.join("six-1.16.0.egg-info");
+        create_dir_all(&egg_info).unwrap();
+        write(egg_info.join("PKG-INFO"), "Name: six\nVersion: 1.16.0\n").unwrap();
+        let found = read(&egg_info).unwrap().unwrap();
+        assert_eq!(
+            (found.name.as_str(), found.version.as_str()),
+            ("six", "1.16.0")
+        );
+
+        let egg_file = site_packages.join("PyYAML-6.0.1.egg-info");
+        write(&egg_file, "Name: PyYAML\nVersion: 6.0.1\n").unwrap();
+        let found = read(&egg_file).unwrap().unwrap();
+        assert_eq!(
+            (found.name.as_str(), found.version.as_str()),
+            ("pyyaml", "6.0.1")
+        );
+
+        let unversioned = site_packages.join("broken.egg-info");
+        write(&unversioned, "Name: broken\n").unwrap();
+        assert!(read(&unversioned).unwrap().is_none());
+        assert!(read(&site_packages.join("missing.dist-info")).is_err());
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!(normalize("Django"), "django");
+        assert_eq!(normalize("zope.interface"), "zope-interface");
+        assert_eq!(normalize("Foo__Bar-.baz"), "foo-bar-baz");
+        assert_eq!(normalize("ruamel.yaml.clib"), "ruamel-yaml-clib");
+    }
+}
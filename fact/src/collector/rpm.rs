@@ -0,0 +1,329 @@
+use std::{
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use fact_api::scanner::v4::{Package, Repository};
+use log::{debug, warn};
+
+use super::{package_cpe, Collector};
+use crate::{budget::ScanBudget, host_info};
+
+mod db;
+mod header;
+mod repos;
+
+use db::Database;
+use header::{Header, RpmPackage};
+use repos::Repositories;
+
+/// Default location of the database since RHEL 9 and Fedora 36,
+/// `/var/lib/rpm` usually being a symlink to it.
+const SYSIMAGE_RPMDB: &str = "usr/lib/sysimage/rpm";
+
+/// Fields of [`RpmPackage`] queried from the rpm command, unset tags
+/// are printed as `(none)`.
+const QUERY_FORMAT: &str = "%{NAME}|%{EPOCH}|%{VERSION}|%{RELEASE}|%{ARCH}|%{SOURCERPM}|\
+    %{VENDOR}|%{INSTALLTIME}|%{MODULARITYLABEL}|%{RSAHEADER:pgpsig}\\n";
+
+/// Lists packages by reading the rpm database directly, falling back to
+/// running `rpm -qa` for databases it cannot read.
+pub struct Rpm {
+    dirs: Vec<PathBuf>,
+    /// Database the command reads, as passed to `--dbpath`
+    rpmdb: String,
+    /// Reported as the database of the packages listed by the command
+    cli_package_db: String,
+    /// Red Hat mapping of repositories to CPEs, extending the bundled one
+    cpe_map: Option<PathBuf>,
+    system_cpe: String,
+    vendor: String,
+    /// Repositories of the packages found by the last collection
+    repositories: Repositories,
+}
+
+impl Rpm {
+    pub fn new(rpmdb: &str, cpe_map: Option<PathBuf>, system_cpe: &str, vendor: String) -> Self {
+        let root = host_info::get_host_mount();
+        let mut dirs = vec![root.join(rpmdb.trim_start_matches('/'))];
+        if !dirs.contains(&root.join(SYSIMAGE_RPMDB)) {
+            dirs.push(root.join(SYSIMAGE_RPMDB));
+        }
+
+        Rpm {
+            dirs,
+            rpmdb: rpmdb.to_owned(),
+            cli_package_db: format!("rpm:{}", rpmdb.trim_start_matches('/')),
+            cpe_map,
+            system_cpe: system_cpe.to_owned(),
+            vendor,
+            repositories: Repositories::default(),
+        }
+    }
+
+    fn read_db(&self, db: &Database, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let dir = db
+            .dir
+            .strip_prefix(host_info::get_host_mount())
+            .unwrap_or(&db.dir);
+        let package_db = format!("{}:{}", db.kind, dir.display());
+
+        let mut pkgs = Vec::new();
+        for blob in db.read_blobs()? {
+            budget.check()?;
+            let pkg = match Header::parse(&blob).and_then(|h| RpmPackage::try_from(&h)) {
+                Ok(pkg) => pkg,
+                Err(e) => {
+                    warn!(
+                        "Skipping invalid package header in {}: {e:#}",
+                        db.path().display()
+                    );
+                    continue;
+                }
+            };
+            // Public keys imported in the database are not packages
+            if pkg.name == "gpg-pubkey" {
+                continue;
+            }
+            debug!(
+                "{}-{}.{} from {}, installed at {:?}, signed with {:?}",
+                pkg.name,
+                pkg.evr(),
+                pkg.arch,
+                pkg.vendor,
+                pkg.install_time,
+                pkg.key_id
+            );
+            pkgs.push(to_package(pkg, &package_db, &self.vendor));
+        }
+        Ok(pkgs)
+    }
+
+    /// List the packages with the rpm command, which has to be installed.
+    /// The command is killed once the scan is over `budget`.
+    fn run_cli(&self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let mut cmd = Command::new("rpm");
+        cmd.args(["--dbpath", &self.rpmdb, "-qa", "--qf", QUERY_FORMAT]);
+        budget.limit_command(&mut cmd);
+        // In a group of its own, so whatever it runs is killed with it
+        let child = cmd
+            .process_group(0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run rpm command")?;
+        let pid = child.id() as libc::pid_t;
+        let done = AtomicBool::new(false);
+        let output = thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    if budget.check().is_err() {
+                        // The child is not reaped until wait_with_output
+                        // returns, so its PID is not reused
+                        unsafe { libc::kill(-pid, libc::SIGKILL) };
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            });
+            let output = child.wait_with_output();
+            done.store(true, Ordering::Relaxed);
+            output
+        })
+        .context("Failed to run rpm command")?;
+        budget.check()?;
+        if !output.status.success() {
+            bail!(
+                "rpm command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let stdout = std::str::from_utf8(&output.stdout).context("Failed to parse rpm output")?;
+
+        let pkgs = stdout
+            .lines()
+            .filter_map(parse_query_line)
+            .filter(|pkg| pkg.name != "gpg-pubkey")
+            .map(|pkg| to_package(pkg, &self.cli_package_db, &self.vendor))
+            .collect();
+        Ok(pkgs)
+    }
+}
+
+impl Collector for Rpm {
+    fn name(&self) -> &'static str {
+        "rpm"
+    }
+
+    fn collect(&mut self, budget: &ScanBudget) -> anyhow::Result<Vec<Package>> {
+        let pkgs = match Database::find(&self.dirs) {
+            Some(db) => match self.read_db(&db, budget) {
+                Ok(pkgs) => pkgs,
+                Err(e) => {
+                    budget.check()?;
+                    warn!("{e:#}, falling back to the rpm command");
+                    self.run_cli(budget)?
+                }
+            },
+            None => {
+                debug!(
+                    "No rpm database found in {:?}, falling back to the rpm command",
+                    self.dirs
+                );
+                self.run_cli(budget)?
+            }
+        };
+        debug!("{pkgs:?}");
+
+        self.repositories = Repositories::detect(
+            host_info::get_host_mount(),
+            &pkgs,
+            self.cpe_map.as_deref(),
+            &self.system_cpe,
+            &self.vendor,
+        );
+        Ok(pkgs)
+    }
+
+    fn repositories(&self) -> Vec<Repository> {
+        self.repositories.repositories()
+    }
+
+    fn repository_ids(&self, pkg: &Package) -> Vec<String> {
+        self.repositories.ids(pkg)
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        Database::find(&self.dirs)
+            .map(|db| vec![db.path()])
+            .unwrap_or_default()
+    }
+}
+
+/// Parse a line printed with [`QUERY_FORMAT`].
+fn parse_query_line(line: &str) -> Option<RpmPackage> {
+    let fields: Vec<&str> = line.split('|').collect();
+    let [name, epoch, version, release, arch, source_rpm, vendor, install_time, label, signature] =
+        fields.as_slice()
+    else {
+        warn!("Skipping unexpected rpm output: {line}");
+        return None;
+    };
+    let set = |value: &str| Some(value.to_owned()).filter(|v| v != "(none)");
+
+    Some(RpmPackage {
+        name: name.to_string(),
+        epoch: epoch.parse().ok(),
+        version: version.to_string(),
+        release: release.to_string(),
+        arch: set(arch).unwrap_or_default(),
+        source_rpm: set(source_rpm).unwrap_or_default(),
+        vendor: set(vendor).unwrap_or_default(),
+        install_time: install_time.parse().ok(),
+        modularity_label: set(label),
+        // "RSA/SHA256, Tue 15 Nov 2022 10:04:12 AM UTC, Key ID 199e2f91fd431d51"
+        key_id: signature
+            .rsplit_once("Key ID ")
+            .map(|(_, key_id)| key_id.trim().to_owned()),
+    })
+}
+
+fn to_package(pkg: RpmPackage, package_db: &str, vendor: &str) -> Package {
+    let version = pkg.evr();
+    let module = pkg.module().unwrap_or_default();
+    let (source_name, source_version) = match pkg.source() {
+        Some((name, version)) => (name.to_owned(), version),
+        None => (pkg.name.clone(), version.clone()),
+    };
+    let source = Package {
+        cpe: package_cpe(vendor, &source_name, &pkg.version, &pkg.release, ""),
+        name: source_name,
+        version: source_version,
+        kind: "source".to_string(),
+        module: module.clone(),
+        ..Default::default()
+    };
+
+    Package {
+        id: format!("{}-{version}.{}", pkg.name, pkg.arch),
+        cpe: package_cpe(vendor, &pkg.name, &pkg.version, &pkg.release, &pkg.arch),
+        name: pkg.name,
+        version,
+        kind: "binary".to_string(),
+        source: Some(Box::new(source)),
+        package_db: package_db.to_owned(),
+        repository_hint: pkg
+            .key_id
+            .map(|key| format!("key:{key}"))
+            .unwrap_or_default(),
+        module,
+        arch: pkg.arch,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_line() {
+        let line = "openssl-libs|1|3.0.7|27.el9|x86_64|openssl-3.0.7-27.el9.src.rpm|\
+            Red Hat, Inc.|1700000000|(none)|\
+            RSA/SHA256, Tue 15 Nov 2022 10:04:12 AM UTC, Key ID 199e2f91fd431d51";
+        let pkg = parse_query_line(line).unwrap();
+        assert_eq!(pkg.epoch, Some(1));
+        assert_eq!(pkg.install_time, Some(1700000000));
+        assert_eq!(pkg.modularity_label, None);
+        assert_eq!(pkg.key_id.as_deref(), Some("199e2f91fd431d51"));
+
+        let pkg = to_package(pkg, "rpm:var/lib/rpm", "redhat");
+        assert_eq!(pkg.id, "openssl-libs-1:3.0.7-27.el9.x86_64");
+        assert_eq!(pkg.version, "1:3.0.7-27.el9");
+        assert_eq!(pkg.arch, "x86_64");
+        assert_eq!(pkg.package_db, "rpm:var/lib/rpm");
+        assert_eq!(pkg.repository_hint, "key:199e2f91fd431d51");
+        assert_eq!(
+            pkg.cpe,
+            "cpe:2.3:a:redhat:openssl-libs:3.0.7:27.el9:*:*:*:*:x86_64:*"
+        );
+        let source = pkg.source.unwrap();
+        assert_eq!(
+            (source.name.as_str(), source.version.as_str()),
+            ("openssl", "1:3.0.7-27.el9")
+        );
+    }
+
+    #[test]
+    fn query_line_without_optional_fields() {
+        let line = "gpg-pubkey|(none)|fd431d51|4ae0493b|(none)|(none)|(none)|1700000000|\
+            nodejs:18:9020020230920:rhel9|(none)";
+        let pkg = parse_query_line(line).unwrap();
+        assert_eq!(pkg.epoch, None);
+        assert_eq!(pkg.arch, "");
+        assert_eq!(pkg.key_id, None);
+        assert_eq!(pkg.module().as_deref(), Some("nodejs:18"));
+
+        let pkg = to_package(pkg, "rpm:var/lib/rpm", "redhat");
+        assert_eq!(pkg.id, "gpg-pubkey-fd431d51-4ae0493b.");
+        assert_eq!(pkg.module, "nodejs:18");
+        let source = pkg.source.unwrap();
+        assert_eq!(
+            (source.name.as_str(), source.version.as_str()),
+            ("gpg-pubkey", "fd431d51-4ae0493b")
+        );
+    }
+
+    #[test]
+    fn unexpected_query_line() {
+        assert!(parse_query_line("").is_none());
+        assert!(parse_query_line("bash|0|5.1.8").is_none());
+        assert!(parse_query_line("a|b|c|d|e|f|g|h|i|j|k").is_none());
+    }
+}
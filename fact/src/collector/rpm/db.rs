@@ -0,0 +1,397 @@
+//! Readers for the formats of the rpm database, returning the raw
+//! header blobs of the installed packages.
+
+use std::{
+    fmt,
+    fs::read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use rusqlite::{Connection, OpenFlags};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// `rpmdb.sqlite`, the default since RHEL 9 and Fedora 33
+    Sqlite,
+    /// `Packages.db`, used by SUSE
+    Ndb,
+    /// `Packages`, the BerkeleyDB hash database of older releases
+    Bdb,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Kind::Sqlite => "sqlite",
+            Kind::Ndb => "ndb",
+            Kind::Bdb => "bdb",
+        };
+        f.write_str(kind)
+    }
+}
+
+impl Kind {
+    fn file_name(&self) -> &'static str {
+        match self {
+            Kind::Sqlite => "rpmdb.sqlite",
+            Kind::Ndb => "Packages.db",
+            Kind::Bdb => "Packages",
+        }
+    }
+}
+
+/// A database found on disk.
+#[derive(Debug, Clone)]
+pub struct Database {
+    pub kind: Kind,
+    /// Directory holding the database
+    pub dir: PathBuf,
+}
+
+impl Database {
+    /// Look for a database in the given directories, in order.
+    pub fn find(dirs: &[PathBuf]) -> Option<Self> {
+        dirs.iter().find_map(|dir| {
+            [Kind::Sqlite, Kind::Ndb, Kind::Bdb]
+                .into_iter()
+                .find(|kind| dir.join(kind.file_name()).is_file())
+                .map(|kind| Database {
+                    kind,
+                    dir: dir.clone(),
+                })
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(self.kind.file_name())
+    }
+
+    pub fn read_blobs(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let path = self.path();
+        let blobs = match self.kind {
+            Kind::Sqlite => read_sqlite(&path),
+            Kind::Ndb => read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|db| read_ndb(&db)),
+            Kind::Bdb => read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|db| read_bdb(&db)),
+        };
+        blobs.with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+fn read_sqlite(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    // Opening as immutable never touches the database, not even to
+    // recover the write-ahead log, which would need write access.
+    let uri = format!("file:{}?immutable=1", path.display());
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = Connection::open_with_flags(uri, flags)?;
+    let mut stmt = conn.prepare("SELECT blob FROM Packages")?;
+    let blobs = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(blobs)
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = buf
+        .get(offset..offset + 4)
+        .context("unexpected end of database")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> anyhow::Result<u16> {
+    let bytes = buf
+        .get(offset..offset + 2)
+        .context("unexpected end of database")?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+const NDB_HEADER_MAGIC: u32 = u32::from_le_bytes(*b"RpmP");
+const NDB_SLOT_MAGIC: u32 = u32::from_le_bytes(*b"Slot");
+const NDB_BLOB_MAGIC: u32 = u32::from_le_bytes(*b"BlbS");
+const NDB_PAGE_SIZE: usize = 4096;
+const NDB_BLOCK_SIZE: usize = 16;
+const NDB_HEADER_SIZE: usize = 16;
+const NDB_SLOT_SIZE: usize = 16;
+
+/// Read an NDB database: a header, pages of slots pointing to blocks,
+/// and the blocks holding the blobs.
+fn read_ndb(db: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    ensure!(le_u32(db, 0)? == NDB_HEADER_MAGIC, "not an NDB database");
+    let version = le_u32(db, 4)?;
+    ensure!(version == 0, "unsupported NDB version {version}");
+    let slot_pages = le_u32(db, 12)? as usize;
+
+    let slots_end = (slot_pages * NDB_PAGE_SIZE).min(db.len());
+    let mut blobs = Vec::new();
+    for slot in (NDB_HEADER_SIZE..slots_end).step_by(NDB_SLOT_SIZE) {
+        ensure!(
+            le_u32(db, slot)? == NDB_SLOT_MAGIC,
+            "corrupted slot at {slot}"
+        );
+        let index = le_u32(db, slot + 4)?;
+        if index == 0 {
+            continue;
+        }
+        let offset = le_u32(db, slot + 8)? as usize * NDB_BLOCK_SIZE;
+
+        ensure!(
+            le_u32(db, offset)? == NDB_BLOB_MAGIC,
+            "corrupted blob at {offset}"
+        );
+        ensure!(
+            le_u32(db, offset + 4)? == index,
+            "blob index mismatch at {offset}"
+        );
+        let len = le_u32(db, offset + 12)? as usize;
+        let start = offset + NDB_BLOCK_SIZE;
+        let blob = db.get(start..start + len).context("truncated blob")?;
+        blobs.push(blob.to_vec());
+    }
+    Ok(blobs)
+}
+
+const BDB_HASH_MAGIC: u32 = 0x061561;
+const BDB_HASH_META_PAGE: u8 = 8;
+const BDB_HASH_UNSORTED_PAGE: u8 = 2;
+const BDB_HASH_PAGE: u8 = 13;
+const BDB_OFF_PAGE_ENTRY: u8 = 3;
+const BDB_PAGE_HEADER_SIZE: usize = 26;
+
+/// Read a BerkeleyDB hash database. Header blobs never fit in a hash
+/// page, so they are all stored in chains of overflow pages referenced
+/// from the hash pages.
+fn read_bdb(db: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let magic = le_u32(db, 12)?;
+    if magic == BDB_HASH_MAGIC.swap_bytes() {
+        bail!("big endian BerkeleyDB databases are not supported");
+    }
+    ensure!(magic == BDB_HASH_MAGIC, "not a BerkeleyDB hash database");
+    let page_size = le_u32(db, 20)? as usize;
+    ensure!(
+        page_size >= 512 && page_size.is_power_of_two(),
+        "invalid page size {page_size}"
+    );
+    ensure!(
+        db.get(24) == Some(&0),
+        "encrypted databases are not supported"
+    );
+    ensure!(
+        db.get(25) == Some(&BDB_HASH_META_PAGE),
+        "invalid metadata page"
+    );
+    let last_page = le_u32(db, 32)? as usize;
+
+    let get_page = |n: usize| db.get(n * page_size..(n + 1) * page_size);
+
+    let mut blobs = Vec::new();
+    for n in 1..=last_page {
+        let Some(page) = get_page(n) else {
+            break;
+        };
+        if page[25] != BDB_HASH_UNSORTED_PAGE && page[25] != BDB_HASH_PAGE {
+            continue;
+        }
+
+        // Entries are key/value pairs, the values come second
+        let entries = le_u16(page, 20)? as usize;
+        for i in (1..entries).step_by(2) {
+            let offset = le_u16(page, BDB_PAGE_HEADER_SIZE + i * 2)? as usize;
+            if page.get(offset) != Some(&BDB_OFF_PAGE_ENTRY) {
+                continue;
+            }
+            let mut next = le_u32(page, offset + 4)? as usize;
+            let len = le_u32(page, offset + 8)? as usize;
+
+            let mut blob = Vec::with_capacity(len.min(db.len()));
+            let mut visited = 0;
+            while next != 0 {
+                ensure!(visited <= last_page, "overflow page loop");
+                visited += 1;
+
+                let overflow = get_page(next).with_context(|| format!("missing page {next}"))?;
+                next = le_u32(overflow, 16)? as usize;
+                let data = &overflow[BDB_PAGE_HEADER_SIZE..];
+                if next == 0 {
+                    // The free area offset holds the length on the last page
+                    let end = le_u16(overflow, 22)? as usize;
+                    blob.extend_from_slice(data.get(..end).context("invalid overflow page")?);
+                } else {
+                    blob.extend_from_slice(data);
+                }
+            }
+            ensure!(blob.len() == len, "truncated blob");
+            blobs.push(blob);
+        }
+    }
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// An NDB database with a page of slots, the blobs being stored in
+    /// the following pages.
+    fn ndb(blobs: &[&[u8]]) -> Vec<u8> {
+        let mut db = vec![0; NDB_PAGE_SIZE];
+        put_u32(&mut db, 0, NDB_HEADER_MAGIC);
+        put_u32(&mut db, 12, 1);
+        for slot in (NDB_HEADER_SIZE..NDB_PAGE_SIZE).step_by(NDB_SLOT_SIZE) {
+            put_u32(&mut db, slot, NDB_SLOT_MAGIC);
+        }
+        for (i, blob) in blobs.iter().enumerate() {
+            let offset = db.len();
+            let slot = NDB_HEADER_SIZE + i * NDB_SLOT_SIZE;
+            put_u32(&mut db, slot + 4, i as u32 + 1);
+            put_u32(&mut db, slot + 8, (offset / NDB_BLOCK_SIZE) as u32);
+
+            let blocks = (NDB_BLOCK_SIZE + blob.len()).div_ceil(NDB_BLOCK_SIZE);
+            db.resize(offset + blocks * NDB_BLOCK_SIZE, 0);
+            put_u32(&mut db, offset, NDB_BLOB_MAGIC);
+            put_u32(&mut db, offset + 4, i as u32 + 1);
+            put_u32(&mut db, offset + 12, blob.len() as u32);
+            db[offset + NDB_BLOCK_SIZE..][..blob.len()].copy_from_slice(blob);
+        }
+        db
+    }
+
+    #[test]
+    fn ndb_blobs() {
+        let db = ndb(&[b"first blob", &[0xab; 100]]);
+        assert_eq!(
+            read_ndb(&db).unwrap(),
+            [b"first blob".to_vec(), vec![0xab; 100]]
+        );
+        assert!(read_ndb(&ndb(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ndb_corrupted() {
+        let db = ndb(&[b"first blob"]);
+        assert!(read_ndb(&db[..NDB_PAGE_SIZE + NDB_BLOCK_SIZE + 5]).is_err());
+        assert!(read_ndb(&db[..2]).is_err());
+        // Slots announced past the end of the file are not read
+        assert!(read_ndb(&ndb(&[])[..NDB_PAGE_SIZE / 2]).unwrap().is_empty());
+
+        let mut bad = db.clone();
+        bad[0] = b'X';
+        assert!(read_ndb(&bad).is_err());
+
+        let mut bad = db.clone();
+        put_u32(&mut bad, NDB_HEADER_SIZE + NDB_SLOT_SIZE, 0);
+        assert!(read_ndb(&bad).is_err());
+
+        let mut bad = db.clone();
+        put_u32(&mut bad, NDB_PAGE_SIZE + 4, 2);
+        assert!(read_ndb(&bad).is_err());
+
+        // Slot pointing past the end of the file
+        let mut bad = db.clone();
+        put_u32(&mut bad, NDB_HEADER_SIZE + 8, u32::MAX);
+        assert!(read_ndb(&bad).is_err());
+
+        let mut bad = db;
+        put_u32(&mut bad, NDB_PAGE_SIZE + 12, u32::MAX);
+        assert!(read_ndb(&bad).is_err());
+    }
+
+    const BDB_PAGE_SIZE: usize = 512;
+    const BDB_PAGE_DATA: usize = BDB_PAGE_SIZE - BDB_PAGE_HEADER_SIZE;
+
+    /// A BerkeleyDB hash database with a metadata page, a hash page and
+    /// the overflow pages holding `blob`.
+    fn bdb(blob: &[u8]) -> Vec<u8> {
+        let chunks: Vec<&[u8]> = blob.chunks(BDB_PAGE_DATA).collect();
+        let last_page = 1 + chunks.len();
+        let mut db = vec![0; (last_page + 1) * BDB_PAGE_SIZE];
+
+        put_u32(&mut db, 12, BDB_HASH_MAGIC);
+        put_u32(&mut db, 20, BDB_PAGE_SIZE as u32);
+        db[25] = BDB_HASH_META_PAGE;
+        put_u32(&mut db, 32, last_page as u32);
+
+        let page = &mut db[BDB_PAGE_SIZE..2 * BDB_PAGE_SIZE];
+        page[25] = BDB_HASH_PAGE;
+        put_u16(page, 20, 2);
+        // The key is inline, the value is an off-page entry
+        let (key, value) = (BDB_PAGE_SIZE - 8, BDB_PAGE_SIZE - 32);
+        put_u16(page, BDB_PAGE_HEADER_SIZE, key as u16);
+        put_u16(page, BDB_PAGE_HEADER_SIZE + 2, value as u16);
+        page[key] = 1;
+        page[value] = BDB_OFF_PAGE_ENTRY;
+        put_u32(page, value + 4, 2);
+        put_u32(page, value + 8, blob.len() as u32);
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let n = 2 + i;
+            let page = &mut db[n * BDB_PAGE_SIZE..(n + 1) * BDB_PAGE_SIZE];
+            let next = if n == last_page { 0 } else { n as u32 + 1 };
+            put_u32(page, 16, next);
+            put_u16(page, 22, chunk.len() as u16);
+            page[BDB_PAGE_HEADER_SIZE..][..chunk.len()].copy_from_slice(chunk);
+        }
+        db
+    }
+
+    #[test]
+    fn bdb_blobs() {
+        let blob: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        assert_eq!(read_bdb(&bdb(&blob)).unwrap(), [blob]);
+        assert_eq!(read_bdb(&bdb(b"short")).unwrap(), [b"short".to_vec()]);
+    }
+
+    #[test]
+    fn bdb_corrupted() {
+        let blob = vec![0xcd; 1000];
+        let db = bdb(&blob);
+        // Missing overflow page
+        assert!(read_bdb(&db[..db.len() - BDB_PAGE_SIZE]).is_err());
+        assert!(read_bdb(&db[..30]).is_err());
+
+        let mut bad = db.clone();
+        put_u32(&mut bad, 12, BDB_HASH_MAGIC.swap_bytes());
+        assert!(read_bdb(&bad).is_err());
+
+        let mut bad = db.clone();
+        put_u32(&mut bad, 20, 1000);
+        assert!(read_bdb(&bad).is_err());
+
+        let mut bad = db.clone();
+        bad[24] = 1;
+        assert!(read_bdb(&bad).is_err());
+
+        // Overflow page pointing to itself
+        let mut bad = db.clone();
+        put_u32(&mut bad, 3 * BDB_PAGE_SIZE + 16, 3);
+        assert!(read_bdb(&bad).is_err());
+
+        // Length on the last page past its end
+        let mut bad = db.clone();
+        put_u16(&mut bad, 4 * BDB_PAGE_SIZE + 22, u16::MAX);
+        assert!(read_bdb(&bad).is_err());
+
+        // Shorter than announced
+        let mut bad = db.clone();
+        put_u32(&mut bad, BDB_PAGE_SIZE * 2 - 32 + 8, 2000);
+        assert!(read_bdb(&bad).is_err());
+
+        // Entry table past the end of the page
+        let mut bad = db;
+        put_u16(&mut bad, BDB_PAGE_SIZE + 20, u16::MAX);
+        assert!(read_bdb(&bad).is_err());
+    }
+}
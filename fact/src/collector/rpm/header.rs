@@ -0,0 +1,397 @@
+//! Decoding of the RPM header blobs stored in the rpm databases.
+//!
+//! A blob is made of an index of tag entries followed by the data they
+//! point into, every integer being big endian:
+//!
+//! ```text
+//! il: u32, dl: u32, il * (tag: i32, type: u32, offset: i32, count: u32), data: [u8; dl]
+//! ```
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context};
+
+const TAG_NAME: i32 = 1000;
+const TAG_VERSION: i32 = 1001;
+const TAG_RELEASE: i32 = 1002;
+const TAG_EPOCH: i32 = 1003;
+const TAG_INSTALLTIME: i32 = 1008;
+const TAG_VENDOR: i32 = 1011;
+const TAG_ARCH: i32 = 1022;
+const TAG_SOURCERPM: i32 = 1044;
+const TAG_MODULARITYLABEL: i32 = 5096;
+const TAG_DSAHEADER: i32 = 267;
+const TAG_RSAHEADER: i32 = 268;
+const TAG_SIGPGP: i32 = 259;
+const TAG_SIGGPG: i32 = 262;
+
+const TYPE_INT32: u32 = 4;
+const TYPE_STRING: u32 = 6;
+const TYPE_BIN: u32 = 7;
+const TYPE_STRING_ARRAY: u32 = 8;
+const TYPE_I18NSTRING: u32 = 9;
+
+/// Same limits as rpm itself, anything bigger is corrupted.
+const MAX_TAGS: usize = 0xffff;
+const MAX_DATA: usize = 256 * 1024 * 1024;
+
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tag: i32,
+    kind: u32,
+    offset: usize,
+    count: usize,
+}
+
+pub struct Header<'a> {
+    entries: Vec<Entry>,
+    data: &'a [u8],
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Header<'a> {
+    pub fn parse(blob: &'a [u8]) -> anyhow::Result<Self> {
+        if blob.len() < 8 {
+            bail!("header blob too short ({} bytes)", blob.len());
+        }
+        let il = be_u32(blob, 0) as usize;
+        let dl = be_u32(blob, 4) as usize;
+        if il == 0 || il > MAX_TAGS || dl > MAX_DATA {
+            bail!("invalid header size (il={il}, dl={dl})");
+        }
+
+        let data_start = 8 + il * ENTRY_SIZE;
+        let data = blob
+            .get(data_start..data_start + dl)
+            .context("header blob truncated")?;
+
+        let entries = blob[8..data_start]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|e| Entry {
+                tag: be_u32(e, 0) as i32,
+                kind: be_u32(e, 4),
+                offset: be_u32(e, 8) as usize,
+                count: be_u32(e, 12) as usize,
+            })
+            .collect();
+
+        Ok(Header { entries, data })
+    }
+
+    fn entry(&self, tag: i32) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+
+    /// First string of a string entry.
+    pub fn string(&self, tag: i32) -> Option<&'a str> {
+        let entry = self.entry(tag)?;
+        match entry.kind {
+            TYPE_STRING | TYPE_STRING_ARRAY | TYPE_I18NSTRING => {}
+            _ => return None,
+        }
+        let data = self.data.get(entry.offset..)?;
+        let end = data.iter().position(|&b| b == 0)?;
+        std::str::from_utf8(&data[..end]).ok()
+    }
+
+    pub fn int32(&self, tag: i32) -> Option<u32> {
+        let entry = self.entry(tag)?;
+        if entry.kind != TYPE_INT32 || entry.count == 0 {
+            return None;
+        }
+        let bytes = self.data.get(entry.offset..entry.offset + 4)?;
+        Some(be_u32(bytes, 0))
+    }
+
+    pub fn binary(&self, tag: i32) -> Option<&'a [u8]> {
+        let entry = self.entry(tag)?;
+        if entry.kind != TYPE_BIN {
+            return None;
+        }
+        self.data.get(entry.offset..entry.offset + entry.count)
+    }
+}
+
+/// The fields of an installed package fact cares about.
+#[derive(Debug, Clone)]
+pub struct RpmPackage {
+    pub name: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+    /// Name of the source RPM, e.g. `bash-5.1.8-9.el9.src.rpm`
+    pub source_rpm: String,
+    pub vendor: String,
+    pub install_time: Option<u32>,
+    /// `name:stream:version:context` of the module the package was
+    /// built for, if any
+    pub modularity_label: Option<String>,
+    /// ID of the key the package was signed with, in hex
+    pub key_id: Option<String>,
+}
+
+impl TryFrom<&Header<'_>> for RpmPackage {
+    type Error = anyhow::Error;
+
+    fn try_from(header: &Header<'_>) -> Result<Self, Self::Error> {
+        let string = |tag| header.string(tag).unwrap_or_default().to_owned();
+        let name = header.string(TAG_NAME).context("package without a name")?;
+        let key_id = [TAG_RSAHEADER, TAG_DSAHEADER, TAG_SIGPGP, TAG_SIGGPG]
+            .into_iter()
+            .filter_map(|tag| header.binary(tag))
+            .find_map(signature_key_id);
+
+        Ok(RpmPackage {
+            name: name.to_owned(),
+            epoch: header.int32(TAG_EPOCH),
+            version: string(TAG_VERSION),
+            release: string(TAG_RELEASE),
+            arch: string(TAG_ARCH),
+            source_rpm: string(TAG_SOURCERPM),
+            vendor: string(TAG_VENDOR),
+            install_time: header.int32(TAG_INSTALLTIME),
+            modularity_label: header
+                .string(TAG_MODULARITYLABEL)
+                .filter(|label| !label.is_empty())
+                .map(str::to_owned),
+            key_id,
+        })
+    }
+}
+
+impl RpmPackage {
+    /// `[epoch:]version-release`, the way rpm compares versions.
+    pub fn evr(&self) -> String {
+        match self.epoch {
+            Some(epoch) if epoch != 0 => format!("{epoch}:{}-{}", self.version, self.release),
+            _ => format!("{}-{}", self.version, self.release),
+        }
+    }
+
+    /// Name and `[epoch:]version-release` of the source package, from
+    /// the source RPM file name. The file name has no epoch, it is the
+    /// same as the one of the binary packages built from it.
+    pub fn source(&self) -> Option<(&str, String)> {
+        let nvr = self
+            .source_rpm
+            .strip_suffix(".src.rpm")
+            .or_else(|| self.source_rpm.strip_suffix(".nosrc.rpm"))?;
+        let (nv, release) = nvr.rsplit_once('-')?;
+        let (name, version) = nv.rsplit_once('-')?;
+        let evr = match self.epoch {
+            Some(epoch) if epoch != 0 => format!("{epoch}:{version}-{release}"),
+            _ => format!("{version}-{release}"),
+        };
+        Some((name, evr))
+    }
+
+    /// `name:stream` of the module the package belongs to, the way
+    /// advisories refer to it.
+    pub fn module(&self) -> Option<String> {
+        let label = self.modularity_label.as_deref()?;
+        let mut parts = label.split(':');
+        let (name, stream) = (parts.next()?, parts.next()?);
+        Some(format!("{name}:{stream}"))
+    }
+}
+
+/// Key ID of the issuer of an OpenPGP signature packet.
+fn signature_key_id(packet: &[u8]) -> Option<String> {
+    let body = packet_body(packet)?;
+    let key_id = match *body.first()? {
+        // version, hashed length (5), type, creation time, key ID
+        3 => body.get(7..15)?,
+        // version, type, public key algorithm, hash algorithm, then the
+        // hashed and unhashed subpackets
+        4 => {
+            let hashed_len = u16::from_be_bytes(body.get(4..6)?.try_into().ok()?) as usize;
+            let hashed = body.get(6..6 + hashed_len)?;
+            let rest = body.get(6 + hashed_len..)?;
+            let unhashed_len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+            let unhashed = rest.get(2..2 + unhashed_len)?;
+            issuer(hashed).or_else(|| issuer(unhashed))?
+        }
+        _ => return None,
+    };
+    let key_id = key_id.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    Some(key_id)
+}
+
+fn packet_body(packet: &[u8]) -> Option<&[u8]> {
+    let tag = *packet.first()?;
+    if tag & 0x80 == 0 {
+        return None;
+    }
+
+    let (tag, len, start) = if tag & 0x40 == 0 {
+        // Old format, the length size is in the tag
+        let len = match tag & 0x03 {
+            0 => *packet.get(1)? as usize,
+            1 => u16::from_be_bytes(packet.get(1..3)?.try_into().ok()?) as usize,
+            2 => be_u32(packet.get(1..5)?, 0) as usize,
+            _ => packet.len() - 1,
+        };
+        let start = [2, 3, 5, 1][(tag & 0x03) as usize];
+        ((tag >> 2) & 0x0f, len, start)
+    } else {
+        let (len, start) = match *packet.get(1)? {
+            len @ 0..192 => (len as usize, 2),
+            first @ 192..224 => {
+                let second = *packet.get(2)? as usize;
+                (((first as usize - 192) << 8) + second + 192, 3)
+            }
+            255 => (be_u32(packet.get(2..6)?, 0) as usize, 6),
+            _ => return None,
+        };
+        (tag & 0x3f, len, start)
+    };
+
+    // Signature packet
+    if tag != 2 {
+        return None;
+    }
+    packet.get(start..start + len)
+}
+
+/// Find the issuer key ID in a list of signature subpackets.
+fn issuer(mut subpackets: &[u8]) -> Option<&[u8]> {
+    while !subpackets.is_empty() {
+        let (len, start) = match subpackets[0] {
+            len @ 0..192 => (len as usize, 1),
+            first @ 192..255 => {
+                let second = *subpackets.get(1)? as usize;
+                (((first as usize - 192) << 8) + second + 192, 2)
+            }
+            255 => (be_u32(subpackets.get(1..5)?, 0) as usize, 5),
+        };
+        let subpacket = subpackets.get(start..start + len)?;
+        let (&kind, data) = subpacket.split_first()?;
+        match kind & 0x7f {
+            // Issuer
+            16 => return data.get(..8),
+            // Issuer fingerprint, the key ID is its last 8 bytes
+            33 if data.len() >= 9 => return Some(&data[data.len() - 8..]),
+            _ => {}
+        }
+        subpackets = &subpackets[start + len..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a header blob out of `(tag, kind, offset, count)` entries
+    /// and their data.
+    fn blob(entries: &[(i32, u32, u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        blob.extend((entries.len() as u32).to_be_bytes());
+        blob.extend((data.len() as u32).to_be_bytes());
+        for &(tag, kind, offset, count) in entries {
+            for value in [tag as u32, kind, offset, count] {
+                blob.extend(value.to_be_bytes());
+            }
+        }
+        blob.extend(data);
+        blob
+    }
+
+    #[test]
+    fn read_entries() {
+        let data = b"bash\0\0\0\0\0\0\0\x01\xca\xfe";
+        let blob = blob(
+            &[
+                (TAG_NAME, TYPE_STRING, 0, 1),
+                (TAG_EPOCH, TYPE_INT32, 8, 1),
+                (TAG_RSAHEADER, TYPE_BIN, 12, 2),
+            ],
+            data,
+        );
+        let header = Header::parse(&blob).unwrap();
+        assert_eq!(header.string(TAG_NAME), Some("bash"));
+        assert_eq!(header.int32(TAG_EPOCH), Some(1));
+        assert_eq!(header.binary(TAG_RSAHEADER), Some(&b"\xca\xfe"[..]));
+        assert_eq!(header.string(TAG_VERSION), None);
+    }
+
+    #[test]
+    fn wrong_kind() {
+        let blob = blob(
+            &[(TAG_NAME, TYPE_INT32, 0, 1), (TAG_EPOCH, TYPE_STRING, 0, 1)],
+            b"\0\0\0\x01",
+        );
+        let header = Header::parse(&blob).unwrap();
+        assert_eq!(header.string(TAG_NAME), None);
+        assert_eq!(header.int32(TAG_EPOCH), None);
+        assert_eq!(header.binary(TAG_NAME), None);
+    }
+
+    #[test]
+    fn offsets_out_of_bounds() {
+        let blob = blob(
+            &[
+                (TAG_NAME, TYPE_STRING, 8, 1),
+                // Not terminated
+                (TAG_VERSION, TYPE_STRING, 4, 1),
+                (TAG_EPOCH, TYPE_INT32, 6, 1),
+                (TAG_INSTALLTIME, TYPE_INT32, u32::MAX, 1),
+                (TAG_RSAHEADER, TYPE_BIN, 4, 5),
+                (TAG_DSAHEADER, TYPE_BIN, u32::MAX, u32::MAX),
+            ],
+            b"bash5.18",
+        );
+        let header = Header::parse(&blob).unwrap();
+        assert_eq!(header.string(TAG_NAME), None);
+        assert_eq!(header.string(TAG_VERSION), None);
+        assert_eq!(header.int32(TAG_EPOCH), None);
+        assert_eq!(header.int32(TAG_INSTALLTIME), None);
+        assert_eq!(header.binary(TAG_RSAHEADER), None);
+        assert_eq!(header.binary(TAG_DSAHEADER), None);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(Header::parse(b"\0\0\0\x01").is_err());
+        // No entries
+        assert!(Header::parse(&blob(&[], b"")).is_err());
+        // Data shorter than announced
+        let mut truncated = blob(&[(TAG_NAME, TYPE_STRING, 0, 1)], b"bash\0");
+        truncated.pop();
+        assert!(Header::parse(&truncated).is_err());
+        // Too many entries
+        let mut blob = blob(&[(TAG_NAME, TYPE_STRING, 0, 1)], b"bash\0");
+        blob[..4].copy_from_slice(&(MAX_TAGS as u32 + 1).to_be_bytes());
+        assert!(Header::parse(&blob).is_err());
+        blob[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Header::parse(&blob).is_err());
+    }
+
+    #[test]
+    fn package_source() {
+        let data = b"bash\x005.1.8\x009.el9\x00bash-5.1.8-9.el9.src.rpm\0\0\0\0\0\0\x02";
+        let blob = blob(
+            &[
+                (TAG_NAME, TYPE_STRING, 0, 1),
+                (TAG_VERSION, TYPE_STRING, 5, 1),
+                (TAG_RELEASE, TYPE_STRING, 11, 1),
+                (TAG_SOURCERPM, TYPE_STRING, 17, 1),
+                (TAG_EPOCH, TYPE_INT32, 44, 1),
+            ],
+            data,
+        );
+        let pkg = RpmPackage::try_from(&Header::parse(&blob).unwrap()).unwrap();
+        assert_eq!(pkg.evr(), "2:5.1.8-9.el9");
+        assert_eq!(pkg.source(), Some(("bash", "2:5.1.8-9.el9".to_string())));
+        assert_eq!(pkg.module(), None);
+    }
+}
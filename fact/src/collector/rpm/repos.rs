@@ -0,0 +1,508 @@
+//! Repositories the installed packages come from.
+//!
+//! The repository of a package is found in the history of dnf (or yum
+//! on RHEL 7). Packages of unknown origin belong to the content sets
+//! listed in the content manifests, or the repositories enabled in
+//! `/etc/yum.repos.d`.
+//!
+//! On RHEL, repositories are identified by the CPEs Red Hat publishes
+//! security data for, mapped with the `repository-to-cpe.json` bundled
+//! for the main RHEL and UBI repositories, extended with
+//! `--repository-cpe-map`, and ultimately the CPE of the operating
+//! system. Other distributions do not have such security data, their
+//! repositories are identified by their ID.
+
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
+
+use anyhow::Context;
+use fact_api::scanner::v4::{Package, Repository};
+use log::{debug, info, warn};
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+
+use crate::collector::{cpe_from_uri, cpe_to_uri};
+
+const DNF_HISTORY: &str = "var/lib/dnf/history.sqlite";
+const DNF5_PACKAGES: &str = "usr/lib/sysimage/libdnf5/packages.toml";
+const YUMDB: &str = "var/lib/yum/yumdb";
+const YUM_REPOS: &str = "etc/yum.repos.d";
+const CONTENT_MANIFESTS: &str = "root/buildinfo/content_manifests";
+
+/// Key under which Scanner V4 looks for the CPE of RHEL repositories
+const RHEL_REPOSITORY_KEY: &str = "rhel-cpe-repository";
+/// Vendor of the CPEs of RHEL and UBI
+const RED_HAT: &str = "redhat";
+/// CPEs of the main RHEL and UBI repositories
+const BUNDLED_CPE_MAP: &str = include_str!("repository-to-cpe.json");
+
+#[derive(Deserialize)]
+struct CpeMap {
+    data: HashMap<String, CpeMapEntry>,
+}
+
+#[derive(Deserialize)]
+struct CpeMapEntry {
+    cpes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ContentManifest {
+    #[serde(default)]
+    content_sets: Vec<String>,
+}
+
+/// Repositories of a set of packages.
+#[derive(Debug, Default)]
+pub struct Repositories {
+    /// Repositories found, their index being their ID
+    repos: Vec<Repository>,
+    /// Repository IDs of each package, by package ID
+    packages: HashMap<String, Vec<String>>,
+}
+
+impl Repositories {
+    /// Repositories of `pkgs`, by CPE on RHEL, whose `vendor` is Red Hat,
+    /// by repository ID on other distributions.
+    pub fn detect(
+        root: &Path,
+        pkgs: &[Package],
+        cpe_map: Option<&Path>,
+        system_cpe: &str,
+        vendor: &str,
+    ) -> Self {
+        let mut default_repos = content_sets(root);
+        if default_repos.is_empty() {
+            default_repos = enabled_repos(root);
+        }
+        let naming = if vendor == RED_HAT {
+            Naming::cpes(cpe_map, &default_repos, system_cpe)
+        } else {
+            debug!("Default repositories {default_repos:?}");
+            Naming::Ids { default_repos }
+        };
+
+        let origins = origins(root);
+        let mut repositories = Repositories::default();
+        for pkg in pkgs {
+            let origin = origins.get(&origin_key(pkg)).map(String::as_str);
+            let ids = naming
+                .repositories(origin)
+                .into_iter()
+                .map(|repo| repositories.id(repo))
+                .collect();
+            repositories.packages.insert(pkg.id.clone(), ids);
+        }
+        info!(
+            "Found {} repositories for {} packages, {} of known origin",
+            repositories.repos.len(),
+            pkgs.len(),
+            pkgs.iter()
+                .filter(|pkg| origins.contains_key(&origin_key(pkg)))
+                .count()
+        );
+        repositories
+    }
+
+    fn id(&mut self, repo: Repository) -> String {
+        let index = match self
+            .repos
+            .iter()
+            .position(|r| r.name == repo.name && r.cpe == repo.cpe)
+        {
+            Some(index) => index,
+            None => {
+                self.repos.push(Repository {
+                    id: self.repos.len().to_string(),
+                    ..repo
+                });
+                self.repos.len() - 1
+            }
+        };
+        index.to_string()
+    }
+
+    pub fn repositories(&self) -> Vec<Repository> {
+        self.repos.clone()
+    }
+
+    pub fn ids(&self, pkg: &Package) -> Vec<String> {
+        self.packages.get(&pkg.id).cloned().unwrap_or_default()
+    }
+}
+
+/// How the repositories of a distribution are identified.
+enum Naming {
+    /// By the CPEs of their security data, as Scanner V4 matches RHEL
+    Cpes {
+        cpe_map: HashMap<String, Vec<String>>,
+        default_cpes: Vec<String>,
+    },
+    /// By their ID
+    Ids { default_repos: Vec<String> },
+}
+
+impl Naming {
+    fn cpes(cpe_map: Option<&Path>, default_repos: &[String], system_cpe: &str) -> Self {
+        let cpe_map = cpe_map_with(cpe_map);
+        let mut default_cpes = cpes_of(&cpe_map, default_repos.iter().map(String::as_str));
+        if default_cpes.is_empty() && !system_cpe.is_empty() {
+            default_cpes.push(system_cpe.to_string());
+        }
+        debug!("Default repositories {default_repos:?} with CPEs {default_cpes:?}");
+        Naming::Cpes {
+            cpe_map,
+            default_cpes,
+        }
+    }
+
+    /// Repositories of a package installed from `origin`, the default ones
+    /// when it is unknown or has no CPE.
+    fn repositories(&self, origin: Option<&str>) -> Vec<Repository> {
+        match self {
+            Naming::Cpes {
+                cpe_map,
+                default_cpes,
+            } => {
+                let mut cpes = cpes_of(cpe_map, origin.into_iter());
+                if cpes.is_empty() {
+                    cpes = default_cpes.clone();
+                }
+                cpes.into_iter()
+                    .map(|cpe| Repository {
+                        name: cpe_to_uri(&cpe),
+                        key: RHEL_REPOSITORY_KEY.to_string(),
+                        cpe,
+                        ..Default::default()
+                    })
+                    .collect()
+            }
+            Naming::Ids { default_repos } => match origin {
+                Some(repo) => vec![repo],
+                None => default_repos.iter().map(String::as_str).collect(),
+            }
+            .into_iter()
+            .map(|repo| Repository {
+                name: repo.to_string(),
+                ..Default::default()
+            })
+            .collect(),
+        }
+    }
+}
+
+fn cpes_of<'a>(
+    cpe_map: &HashMap<String, Vec<String>>,
+    repos: impl Iterator<Item = &'a str>,
+) -> Vec<String> {
+    let mut cpes: Vec<String> = repos
+        .filter_map(|repo| cpe_map.get(repo))
+        .flatten()
+        .map(|cpe| cpe_from_uri(cpe))
+        .collect();
+    cpes.sort();
+    cpes.dedup();
+    cpes
+}
+
+/// The bundled CPE map, extended with the one at `path`.
+fn cpe_map_with(path: Option<&Path>) -> HashMap<String, Vec<String>> {
+    let mut map =
+        parse_cpe_map(BUNDLED_CPE_MAP).expect("the bundled repository-to-cpe.json is valid");
+    if let Some(path) = path {
+        let loaded = read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .and_then(|content| {
+                parse_cpe_map(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))
+            });
+        match loaded {
+            Ok(loaded) => map.extend(loaded),
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+    map
+}
+
+fn parse_cpe_map(content: &str) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let map: CpeMap = serde_json::from_str(content)?;
+    Ok(map
+        .data
+        .into_iter()
+        .map(|(repo, entry)| (repo, entry.cpes))
+        .collect())
+}
+
+/// `name-version-release.arch`, without the epoch that yum does not
+/// record.
+fn origin_key(pkg: &Package) -> String {
+    let version = match pkg.version.split_once(':') {
+        Some((_, version)) => version,
+        None => &pkg.version,
+    };
+    format!("{}-{version}.{}", pkg.name, pkg.arch)
+}
+
+type ReadOrigins = fn(&Path) -> anyhow::Result<Vec<(String, String)>>;
+
+/// Repository each package was installed from, by [`origin_key`].
+fn origins(root: &Path) -> HashMap<String, String> {
+    let mut origins = HashMap::new();
+    let sources: [(&str, ReadOrigins); 3] = [
+        (YUMDB, read_yumdb),
+        (DNF_HISTORY, read_dnf_history),
+        (DNF5_PACKAGES, read_dnf5_packages),
+    ];
+    for (path, read) in sources {
+        let path = root.join(path);
+        if !path.exists() {
+            continue;
+        }
+        match read(&path) {
+            // Not repositories, but what dnf reports for packages installed
+            // from a file or already present
+            Ok(found) => origins.extend(
+                found
+                    .into_iter()
+                    .filter(|(_, repo)| !repo.starts_with('@') && repo != "anaconda"),
+            ),
+            Err(e) => warn!("Failed to read {}: {e:#}", path.display()),
+        }
+    }
+    origins
+}
+
+/// The yum database has a directory per package, named
+/// `<first letter>/<checksum>-<name>-<version>-<release>-<arch>`.
+fn read_yumdb(dir: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let mut origins = Vec::new();
+    for letter in read_dir(dir)?.flatten() {
+        for pkg in read_dir(letter.path())?.flatten() {
+            let Ok(repo) = read_to_string(pkg.path().join("from_repo")) else {
+                continue;
+            };
+            let name = pkg.file_name();
+            let name = name.to_string_lossy();
+            let Some((_, nvra)) = name.split_once('-') else {
+                continue;
+            };
+            // The architecture is the last dash separated field
+            let Some((nvr, arch)) = nvra.rsplit_once('-') else {
+                continue;
+            };
+            origins.push((format!("{nvr}.{arch}"), repo.trim().to_string()));
+        }
+    }
+    Ok(origins)
+}
+
+fn read_dnf_history(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let uri = format!("file:{}?immutable=1", path.display());
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = Connection::open_with_flags(uri, flags)?;
+    // Later transactions come last, overriding the earlier ones
+    let mut stmt = conn.prepare(
+        "SELECT rpm.name, rpm.version, rpm.release, rpm.arch, repo.repoid
+         FROM trans_item
+         JOIN rpm ON rpm.item_id = trans_item.item_id
+         JOIN repo ON repo.id = trans_item.repo_id
+         ORDER BY trans_item.id",
+    )?;
+    let origins = stmt
+        .query_map([], |row| {
+            let (name, version, release, arch, repo): (String, String, String, String, String) = (
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            );
+            Ok((format!("{name}-{version}-{release}.{arch}"), repo))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(origins)
+}
+
+/// dnf5 keeps one table per installed package:
+///
+/// ```toml
+/// [packages."bash-5.2.26-3.fc40.x86_64"]
+/// reason = "User"
+/// from_repo_id = "fedora"
+/// ```
+fn read_dnf5_packages(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let content = read_to_string(path)?;
+    let mut origins = Vec::new();
+    let mut current = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(table) = line.strip_prefix("[packages.") {
+            current = table.strip_suffix(']').map(|nevra| nevra.trim_matches('"'));
+        } else if line.starts_with('[') {
+            current = None;
+        } else if let (Some(nevra), Some((key, value))) = (current, line.split_once('=')) {
+            if key.trim() == "from_repo_id" {
+                // dnf5 keeps the epoch only when it is set
+                let nevra = match nevra.split_once(':') {
+                    Some((name_epoch, vra)) => {
+                        let name = name_epoch
+                            .rsplit_once('-')
+                            .map_or(name_epoch, |(name, _)| name);
+                        format!("{name}-{vra}")
+                    }
+                    None => nevra.to_string(),
+                };
+                origins.push((nevra, value.trim().trim_matches('"').to_string()));
+            }
+        }
+    }
+    Ok(origins)
+}
+
+/// IDs of the repositories enabled in the yum configuration.
+fn enabled_repos(root: &Path) -> Vec<String> {
+    let Ok(entries) = read_dir(root.join(YUM_REPOS)) else {
+        return Vec::new();
+    };
+    let mut repos = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|ext| ext != "repo") {
+            continue;
+        }
+        let Ok(content) = read_to_string(&path) else {
+            warn!("Failed to read {}", path.display());
+            continue;
+        };
+        repos.extend(parse_repo_file(&content));
+    }
+    repos
+}
+
+/// Parse a `.repo` file, an INI file with a section per repository,
+/// which are enabled unless `enabled=0`.
+fn parse_repo_file(content: &str) -> Vec<String> {
+    let mut repos: Vec<(String, bool)> = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            repos.push((section.trim().to_string(), true));
+        } else if let (Some((_, enabled)), Some((key, value))) =
+            (repos.last_mut(), line.split_once('='))
+        {
+            if key.trim() == "enabled" {
+                *enabled = !matches!(value.trim(), "0" | "false" | "no" | "off");
+            }
+        }
+    }
+    repos
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(repo, _)| repo)
+        .collect()
+}
+
+/// Content sets of the content manifests written by Red Hat builds.
+fn content_sets(root: &Path) -> Vec<String> {
+    let Ok(entries) = read_dir(root.join(CONTENT_MANIFESTS)) else {
+        return Vec::new();
+    };
+    let mut sets = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let manifest = read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str::<ContentManifest>(&content)?));
+        match manifest {
+            Ok(manifest) => sets.extend(manifest.content_sets),
+            Err(e) => warn!("Failed to read {}: {e:#}", path.display()),
+        }
+    }
+    sets.sort();
+    sets.dedup();
+    sets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPE_MAP: &str = r#"{
+        "data": {
+            "rhel-9-for-x86_64-baseos-rpms": {
+                "cpes": ["cpe:/o:redhat:enterprise_linux:9::baseos"]
+            },
+            "internal-rpms": {
+                "cpes": [
+                    "cpe:/a:example:internal:1",
+                    "cpe:/o:redhat:enterprise_linux:9::baseos"
+                ]
+            },
+            "empty-rpms": { "cpes": [] }
+        }
+    }"#;
+
+    #[test]
+    fn cpe_map() {
+        let map = parse_cpe_map(CPE_MAP).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["internal-rpms"].len(), 2);
+        assert!(map["empty-rpms"].is_empty());
+
+        assert!(parse_cpe_map("{}").is_err());
+        assert!(parse_cpe_map(r#"{"data": {"repo": {}}}"#).is_err());
+        assert!(parse_cpe_map("not json").is_err());
+    }
+
+    #[test]
+    fn bundled_cpe_map() {
+        let map = parse_cpe_map(BUNDLED_CPE_MAP).unwrap();
+        assert_eq!(
+            map["rhel-9-for-x86_64-baseos-rpms"],
+            ["cpe:/o:redhat:enterprise_linux:9::baseos"]
+        );
+        assert!(map.values().all(|cpes| !cpes.is_empty()));
+    }
+
+    #[test]
+    fn cpes_of_repos() {
+        let map = parse_cpe_map(CPE_MAP).unwrap();
+        let cpes = cpes_of(
+            &map,
+            [
+                "rhel-9-for-x86_64-baseos-rpms",
+                "internal-rpms",
+                "unknown-rpms",
+            ]
+            .into_iter(),
+        );
+        assert_eq!(
+            cpes,
+            [
+                "cpe:2.3:a:example:internal:1:*:*:*:*:*:*:*",
+                "cpe:2.3:o:redhat:enterprise_linux:9:*:baseos:*:*:*:*:*",
+            ]
+        );
+        assert!(cpes_of(&map, ["empty-rpms"].into_iter()).is_empty());
+    }
+
+    #[test]
+    fn repo_file() {
+        let content = "\
+[baseos]
+name = BaseOS
+enabled = 1
+
+[debug]
+enabled=0
+
+[ appstream ]
+baseurl=https://example.com/appstream
+";
+        assert_eq!(parse_repo_file(content), ["baseos", "appstream"]);
+    }
+}
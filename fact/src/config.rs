@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum AgentMode {
@@ -14,18 +14,108 @@ pub enum AgentMode {
     Hybrid,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable output (Rust debug formatting)
+    #[default]
+    Text,
+    /// One JSON record per line, following the schemas in `fact/schema`
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SbomFormat {
+    /// SPDX 2.3 JSON
+    Spdx,
+    /// CycloneDX 1.5 JSON
+    Cyclonedx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SignaturePolicy {
+    /// Relay every report, noting on the VM when its signature is not
+    /// verified
+    Annotate,
+    /// Only relay reports with a valid signature
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VmKeyPolicy {
+    /// Register the key of a VM without a known key with its first signed
+    /// report
+    TrustOnFirstUse,
+    /// Only accept the keys listed in --vm-keys
+    KnownOnly,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Collect the packages of this machine once and save the index
+    /// report to a file
+    Scan(ScanArgs),
+    /// Send an index report saved by `scan` to sensor, using --url or
+    /// --use-vsock
+    Replay(ReplayArgs),
+    /// Collect the packages of this machine once and write their SBOM
+    Sbom(SbomArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ScanArgs {
+    /// File to write the report to, as a JSON record if its name ends
+    /// with .json and as protobuf otherwise
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ReplayArgs {
+    /// Report to send, as written by `scan`
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SbomArgs {
+    /// Format of the SBOM
+    #[arg(short, long, value_enum, default_value = "spdx")]
+    pub format: SbomFormat,
+
+    /// File to write the SBOM to, stdout by default
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Build the SBOM from an index report saved by `scan` instead of
+    /// collecting the packages
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, Parser)]
 #[clap(version, about)]
 pub struct FactConfig {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Agent mode
     #[arg(long, env = "FACT_MODE", default_value = "file-monitor")]
     pub mode: AgentMode,
 
+    /// Format of the records printed to stdout
+    #[arg(long, env = "FACT_OUTPUT", value_enum, default_value_t)]
+    pub output: OutputFormat,
+
+    /// Address to serve metrics (/metrics) and health checks (/healthz,
+    /// /readyz) on, e.g. 0.0.0.0:9000 (all modes)
+    #[arg(long, env = "FACT_HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
     /// List of paths to be monitored (file-monitor mode only)
     #[clap(short, long, num_args = 0..16, value_delimiter = ':')]
     pub paths: Vec<PathBuf>,
 
-    /// URL to forward the packages to
+    /// URL to forward the packages to, either http(s)://host:port or
+    /// unix:///path/to/socket
     #[arg(long, env = "FACT_URL")]
     pub url: Option<String>,
 
@@ -33,6 +123,21 @@ pub struct FactConfig {
     #[arg(short, long, env = "FACT_CERTS")]
     pub certs: Option<PathBuf>,
 
+    #[command(flatten)]
+    pub grpc: GrpcConfig,
+
+    /// Node name reported to sensor, defaults to the hostname
+    #[arg(long, env = "FACT_NODE_NAME")]
+    pub node_name: Option<String>,
+
+    /// Cluster ID reported to sensor
+    #[arg(long, env = "FACT_CLUSTER_ID")]
+    pub cluster_id: Option<String>,
+
+    /// Deployment labels reported to sensor, as key=value pairs
+    #[arg(long = "label", env = "FACT_LABELS", value_delimiter = ',', value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
+
     /// Skip sending packages over HTTP (vm-agent mode)
     #[arg(long, env = "FACT_SKIP_HTTP")]
     pub skip_http: bool,
@@ -45,15 +150,52 @@ pub struct FactConfig {
     #[arg(long, env = "FACT_RPMDB", default_value = "/var/lib/rpm")]
     pub rpmdb: String,
 
-    /// Interval between package scanning in seconds (vm-agent mode)
+    /// Red Hat repository-to-cpe.json, mapping repositories to the CPEs
+    /// of their security data, extending the mapping of the main RHEL and
+    /// UBI repositories bundled with fact (vm-agent mode)
+    #[arg(long, env = "FACT_REPOSITORY_CPE_MAP")]
+    pub repository_cpe_map: Option<PathBuf>,
+
+    /// Interval between package scans in seconds, with up to 10% of
+    /// random jitter (vm-agent mode)
     #[arg(long, env = "FACT_INTERVAL", default_value_t = 3600)]
     pub interval: u64,
 
+    /// Seconds without changes to the package database to wait for
+    /// before rescanning, 0 disables watching it (vm-agent mode)
+    #[arg(long, env = "FACT_RESCAN_DELAY", default_value_t = 10)]
+    pub rescan_delay: u64,
+
+    /// Send the index report after this many seconds even when the
+    /// packages did not change (vm-agent mode)
+    #[arg(long, env = "FACT_RESEND_INTERVAL", default_value_t = 86400)]
+    pub resend_interval: u64,
+
+    /// Directory where the state kept across restarts is stored
+    #[arg(long, env = "FACT_STATE_DIR", default_value = "/var/lib/fact")]
+    pub state_dir: PathBuf,
+
+    #[command(flatten)]
+    pub scan: ScanConfig,
+
+    #[command(flatten)]
+    pub language: LanguageConfig,
+
+    #[command(flatten)]
+    pub sbom: SbomConfig,
+
+    #[command(flatten)]
+    pub signing: SigningConfig,
+
     /// VSOCK port to listen on (vsock-listener/hybrid mode)
     #[arg(long, env = "FACT_VSOCK_PORT", default_value_t = 818)]
     pub vsock_port: u32,
 
-    /// Sensor endpoint for relaying VM data (vsock-listener/hybrid mode)
+    #[command(flatten)]
+    pub vsock: VsockConfig,
+
+    /// Sensor endpoint for relaying VM data, either http(s)://host:port or
+    /// unix:///path/to/socket (vsock-listener/hybrid mode)
     #[arg(long, env = "FACT_SENSOR_ENDPOINT", default_value = "sensor:443")]
     pub sensor_endpoint: String,
 
@@ -65,3 +207,208 @@ pub struct FactConfig {
     #[arg(long, env = "FACT_ENABLE_VM_AGENT")]
     pub enable_vm_agent: bool,
 }
+
+// Settings shared by every gRPC client talking to sensor. This and the
+// comments of the other flattened structs below are not doc comments on
+// purpose, clap would use them as the program description.
+#[derive(Debug, Clone, Args)]
+pub struct GrpcConfig {
+    /// Server name to verify the sensor certificate against
+    #[arg(long, env = "FACT_TLS_SERVER_NAME", default_value = "sensor.stackrox.svc")]
+    pub tls_server_name: String,
+
+    /// CA certificate, defaults to ca.pem in the certificates directory
+    #[arg(long, env = "FACT_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Client certificate, defaults to cert.pem in the certificates directory
+    #[arg(long, env = "FACT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Client private key, defaults to key.pem in the certificates directory
+    #[arg(long, env = "FACT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Connect without TLS, even if certificates are configured (development only)
+    #[arg(long, env = "FACT_INSECURE_PLAINTEXT")]
+    pub insecure_plaintext: bool,
+
+    /// Timeout for establishing a connection, in seconds
+    #[arg(long, env = "FACT_CONNECT_TIMEOUT", default_value_t = 10)]
+    pub connect_timeout: u64,
+
+    /// Timeout for unary requests, in seconds (0 disables it)
+    #[arg(long, env = "FACT_REQUEST_TIMEOUT", default_value_t = 30)]
+    pub request_timeout: u64,
+
+    /// Interval between HTTP/2 keepalive pings, in seconds (0 disables them)
+    #[arg(long, env = "FACT_KEEPALIVE_INTERVAL", default_value_t = 30)]
+    pub keepalive_interval: u64,
+
+    /// Time to wait for a keepalive ping to be acknowledged, in seconds
+    #[arg(long, env = "FACT_KEEPALIVE_TIMEOUT", default_value_t = 10)]
+    pub keepalive_timeout: u64,
+}
+
+// Resources package scans are allowed to use.
+#[derive(Debug, Clone, Args)]
+pub struct ScanConfig {
+    /// Nice level package scans run at, e.g. 10, 0 keeps the one of
+    /// fact (vm-agent mode)
+    #[arg(
+        long = "scan-nice",
+        env = "FACT_SCAN_NICE",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(i32).range(-20..=19)
+    )]
+    pub nice: i32,
+
+    /// I/O scheduling class of package scans: idle, best-effort:LEVEL
+    /// (0 to 7) or none to keep the one of fact
+    #[arg(
+        long = "scan-ionice",
+        env = "FACT_SCAN_IONICE",
+        default_value = "none",
+        value_parser = parse_io_priority
+    )]
+    pub ionice: IoPriority,
+
+    /// Time after which a package scan is abandoned, in seconds (0
+    /// disables it)
+    #[arg(long = "scan-timeout", env = "FACT_SCAN_TIMEOUT", default_value_t = 600)]
+    pub timeout: u64,
+
+    /// Memory a package scan may use, in MiB. Scans using more are
+    /// abandoned, and commands run by them are limited to it
+    #[arg(long = "scan-memory-limit", env = "FACT_SCAN_MEMORY_LIMIT")]
+    pub memory_limit: Option<u64>,
+}
+
+/// I/O scheduling class, as set by ionice(1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    None,
+    BestEffort(u8),
+    Idle,
+}
+
+// Discovery of language packages by the VM agent.
+#[derive(Debug, Clone, Args)]
+pub struct LanguageConfig {
+    /// Also collect Python, Node.js, Java and Go packages (vm-agent mode)
+    #[arg(long = "language-packages", env = "FACT_LANGUAGE_PACKAGES")]
+    pub enabled: bool,
+
+    /// Directories searched for language packages
+    #[arg(
+        long = "language-roots",
+        env = "FACT_LANGUAGE_ROOTS",
+        value_delimiter = ':',
+        default_value = "/usr:/opt:/srv:/home:/root:/var/lib"
+    )]
+    pub roots: Vec<PathBuf>,
+
+    /// Directories skipped when searching for language packages
+    #[arg(
+        long = "language-exclude",
+        env = "FACT_LANGUAGE_EXCLUDE",
+        value_delimiter = ':',
+        default_value = "/proc:/sys:/dev:/run:/tmp:/var/lib/containers"
+    )]
+    pub exclude: Vec<PathBuf>,
+
+    /// Time budget for searching language packages, in seconds
+    #[arg(long = "language-scan-timeout", env = "FACT_LANGUAGE_SCAN_TIMEOUT", default_value_t = 60)]
+    pub scan_timeout: u64,
+}
+
+// SBOMs written by the VM agent after every scan.
+#[derive(Debug, Clone, Args)]
+pub struct SbomConfig {
+    /// Directory to write the SBOM of the VM to whenever its packages
+    /// change (vm-agent mode)
+    #[arg(long = "sbom-dir", env = "FACT_SBOM_DIR")]
+    pub dir: Option<PathBuf>,
+
+    /// Formats of the SBOMs written to --sbom-dir
+    #[arg(
+        long = "sbom-formats",
+        env = "FACT_SBOM_FORMATS",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "spdx,cyclonedx"
+    )]
+    pub formats: Vec<SbomFormat>,
+}
+
+// Signatures of the index reports.
+#[derive(Debug, Clone, Args)]
+pub struct SigningConfig {
+    /// Ed25519 key the index reports are signed with, as PKCS#8 DER.
+    /// Generated in --state-dir on first run by default (vm-agent mode)
+    #[arg(long, env = "FACT_SIGNING_KEY")]
+    pub signing_key: Option<PathBuf>,
+
+    /// JSON file mapping the product UUID of VMs, or their VSOCK CID, to
+    /// their hex encoded public key, vm-keys.json in --state-dir by default (vsock-listener mode)
+    #[arg(long, env = "FACT_VM_KEYS")]
+    pub vm_keys: Option<PathBuf>,
+
+    /// What to do with reports without a valid signature
+    #[arg(long, env = "FACT_SIGNATURE_POLICY", value_enum, default_value = "annotate")]
+    pub signature_policy: SignaturePolicy,
+
+    /// Which keys VMs may sign their reports with
+    #[arg(long, env = "FACT_VM_KEY_POLICY", value_enum, default_value = "trust-on-first-use")]
+    pub vm_key_policy: VmKeyPolicy,
+}
+
+// Limits of the VSOCK listener, protecting it from misbehaving VMs.
+#[derive(Debug, Clone, Args)]
+pub struct VsockConfig {
+    /// Largest message accepted from a VM, in MiB, before and after
+    /// decompression (vsock-listener/hybrid mode)
+    #[arg(
+        long = "vsock-max-frame-size",
+        env = "FACT_VSOCK_MAX_FRAME_SIZE",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..=4095)
+    )]
+    pub max_frame_size: u64,
+
+    /// Time a VM has to send each message once its connection is open,
+    /// in seconds (0 disables it)
+    #[arg(long = "vsock-read-timeout", env = "FACT_VSOCK_READ_TIMEOUT", default_value_t = 30)]
+    pub read_timeout: u64,
+
+    /// Connections a single VM may have open at the same time
+    #[arg(
+        long = "vsock-max-connections-per-cid",
+        env = "FACT_VSOCK_MAX_CONNECTIONS_PER_CID",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connections_per_cid: u64,
+}
+
+fn parse_io_priority(s: &str) -> Result<IoPriority, String> {
+    match s {
+        "none" => return Ok(IoPriority::None),
+        "idle" => return Ok(IoPriority::Idle),
+        _ => {}
+    }
+    let Some(level) = s.strip_prefix("best-effort:") else {
+        return Err(format!("expected idle, best-effort:LEVEL or none, got '{s}'"));
+    };
+    match level.parse() {
+        Ok(level) if level <= 7 => Ok(IoPriority::BestEffort(level)),
+        _ => Err(format!("expected a level from 0 to 7, got '{level}'")),
+    }
+}
+
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected key=value, got '{s}'")),
+    }
+}
@@ -0,0 +1,90 @@
+//! Last index report delivered to sensor, kept in the state directory so
+//! that an unchanged report is not sent again after a restart.
+
+use std::{
+    fs::{create_dir_all, read_to_string, rename, write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+const STATE_FILE: &str = "vm-index-report.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Delivered {
+    digest: String,
+    /// Seconds since the Unix epoch
+    sent_at: u64,
+}
+
+pub struct Delivery {
+    path: PathBuf,
+    last: Option<Delivered>,
+    resend_after: Duration,
+}
+
+impl Delivery {
+    pub fn load(state_dir: &Path, resend_after: Duration) -> Self {
+        let path = state_dir.join(STATE_FILE);
+        let last = match read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .inspect_err(|e| warn!("Ignoring invalid {}: {e}", path.display()))
+                .ok(),
+            Err(e) => {
+                debug!("No previous delivery in {}: {e}", path.display());
+                None
+            }
+        };
+        Delivery {
+            path,
+            last,
+            resend_after,
+        }
+    }
+
+    /// Whether a report with the given digest needs to be sent, because it
+    /// changed or was last sent too long ago.
+    pub fn is_due(&self, digest: &str) -> bool {
+        let Some(last) = &self.last else {
+            return true;
+        };
+        let sent_at = UNIX_EPOCH + Duration::from_secs(last.sent_at);
+        let stale = SystemTime::now()
+            .duration_since(sent_at)
+            .map_or(true, |age| age >= self.resend_after);
+        last.digest != digest || stale
+    }
+
+    /// Record a successful delivery. Failing to persist it only means the
+    /// report will be sent again after a restart.
+    pub fn record(&mut self, digest: &str) {
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let delivered = Delivered {
+            digest: digest.to_string(),
+            sent_at,
+        };
+        if let Err(e) = self.persist(&delivered) {
+            warn!("Failed to save the delivered report digest: {e:#}");
+        }
+        self.last = Some(delivered);
+    }
+
+    fn persist(&self, delivered: &Delivered) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Written aside and renamed, so a crash never leaves a truncated file
+        let tmp = self.path.with_extension("json.tmp");
+        write(&tmp, serde_json::to_vec(delivered)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to rename {}", tmp.display()))?;
+        Ok(())
+    }
+}
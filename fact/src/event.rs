@@ -1,5 +1,6 @@
 use std::ffi::CStr;
 
+use serde::Serialize;
 use uuid::Uuid;
 
 use crate::{
@@ -14,7 +15,7 @@ fn slice_to_string(s: &[i8]) -> anyhow::Result<String> {
     }.to_str()?.to_owned())
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize)]
 pub struct Lineage {
     uid: u32,
     exe_path: String,
@@ -53,7 +54,7 @@ impl From<Lineage> for fact_api::process_signal::LineageInfo {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize)]
 pub struct Process {
     comm: String,
     args: Vec<String>,
@@ -186,10 +187,9 @@ impl From<Process> for fact_api::ProcessSignal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Event {
     timestamp: u64,
-    #[allow(dead_code)]
     hostname: &'static str,
     process: Process,
     is_external_mount: bool,
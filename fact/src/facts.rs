@@ -0,0 +1,219 @@
+//! Facts about the VM the agent runs in, reported to sensor as the
+//! `facts` of its `storage.VirtualMachine`.
+//!
+//! Facts are read from the files under the host mount or queried from
+//! the kernel, a fact that cannot be read is left out rather than
+//! failing the report.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read_dir, read_to_string},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::debug;
+use nix::ifaddrs::getifaddrs;
+
+use crate::{host_info, output::rfc3339, vsock};
+
+pub type Facts = BTreeMap<String, String>;
+
+/// Gather the facts of this VM. `package_dbs` are the package databases
+/// in use, the last time one of them changed being the last time the VM
+/// was patched.
+pub fn gather(package_dbs: &[PathBuf]) -> Facts {
+    let root = host_info::get_host_mount();
+    let facts = [
+        ("vsock_cid", vsock::local_cid().ok().map(|cid| cid.to_string())),
+        ("machine_id", machine_id(root)),
+        ("product_uuid", product_uuid(root)),
+        ("kernel_version", kernel_version(root)),
+        ("boot_time", boot_time(root)),
+        ("cpu_count", cpu_count(root)),
+        ("memory_bytes", memory_bytes(root)),
+        ("ip_addresses", ip_addresses()),
+        ("selinux_mode", Some(selinux_mode(root))),
+        ("fips_mode", fips_mode(root)),
+        ("systemd_enabled_units", enabled_units(root)),
+        ("listening_ports", listening_ports(root)),
+        ("last_patch_time", last_patch_time(package_dbs)),
+    ];
+    facts
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Some(value) => Some((name.to_string(), value)),
+            None => {
+                debug!("Unable to gather the {name} fact");
+                None
+            }
+        })
+        .collect()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// systemd's machine ID, unless the image was not booted yet.
+fn machine_id(root: &Path) -> Option<String> {
+    read_trimmed(&root.join("etc/machine-id"))
+        .filter(|id| !id.is_empty() && id != "uninitialized")
+}
+
+/// UUID of the VM in its SMBIOS tables, set by KubeVirt from
+/// `spec.template.spec.domain.firmware.uuid`. Only readable by root.
+pub fn product_uuid(root: &Path) -> Option<String> {
+    read_trimmed(&root.join("sys/class/dmi/id/product_uuid")).map(|uuid| uuid.to_lowercase())
+}
+
+fn kernel_version(root: &Path) -> Option<String> {
+    read_trimmed(&root.join("proc/sys/kernel/osrelease"))
+}
+
+fn boot_time(root: &Path) -> Option<String> {
+    let stat = read_to_string(root.join("proc/stat")).ok()?;
+    let btime = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(rfc3339(UNIX_EPOCH + Duration::from_secs(btime)))
+}
+
+fn cpu_count(root: &Path) -> Option<String> {
+    let cpuinfo = read_to_string(root.join("proc/cpuinfo")).ok()?;
+    let count = cpuinfo
+        .lines()
+        .filter(|line| line.split(':').next().is_some_and(|key| key.trim() == "processor"))
+        .count();
+    (count > 0).then(|| count.to_string())
+}
+
+fn memory_bytes(root: &Path) -> Option<String> {
+    let meminfo = read_to_string(root.join("proc/meminfo")).ok()?;
+    // MemTotal:        16318948 kB
+    let kb: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some((kb * 1024).to_string())
+}
+
+/// Addresses of the network interfaces, but loopback and link-local
+/// ones. fact shares the network namespace of the VM.
+fn ip_addresses() -> Option<String> {
+    let mut addresses = BTreeSet::new();
+    for ifaddr in getifaddrs().ok()? {
+        let Some(address) = ifaddr.address else {
+            continue;
+        };
+        let ip = if let Some(v4) = address.as_sockaddr_in() {
+            IpAddr::V4(v4.ip())
+        } else if let Some(v6) = address.as_sockaddr_in6() {
+            IpAddr::V6(v6.ip())
+        } else {
+            continue;
+        };
+        let link_local = match ip {
+            IpAddr::V4(v4) => v4.is_link_local(),
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+        };
+        if !ip.is_loopback() && !link_local {
+            addresses.insert(ip);
+        }
+    }
+    Some(join(addresses))
+}
+
+fn selinux_mode(root: &Path) -> String {
+    match read_trimmed(&root.join("sys/fs/selinux/enforce")).as_deref() {
+        Some("1") => "enforcing",
+        Some("0") => "permissive",
+        _ => "disabled",
+    }
+    .to_string()
+}
+
+fn fips_mode(root: &Path) -> Option<String> {
+    let enabled = read_trimmed(&root.join("proc/sys/crypto/fips_enabled"))?;
+    Some(if enabled == "1" { "enabled" } else { "disabled" }.to_string())
+}
+
+/// Units enabled with `systemctl enable`, which links them in the
+/// `.wants` and `.requires` directories of their targets.
+fn enabled_units(root: &Path) -> Option<String> {
+    let mut units = BTreeSet::new();
+    for target in read_dir(root.join("etc/systemd/system")).ok()?.flatten() {
+        let name = target.file_name();
+        let name = name.to_string_lossy();
+        if !name.ends_with(".wants") && !name.ends_with(".requires") {
+            continue;
+        }
+        let Ok(entries) = read_dir(target.path()) else {
+            continue;
+        };
+        for unit in entries.flatten() {
+            units.insert(unit.file_name().to_string_lossy().into_owned());
+        }
+    }
+    Some(join(units))
+}
+
+/// TCP ports in the LISTEN state and bound UDP ports, as `tcp/22`.
+fn listening_ports(root: &Path) -> Option<String> {
+    // Socket states in /proc/net, from include/net/tcp_states.h
+    const TCP_LISTEN: &str = "0A";
+    const UDP_UNCONNECTED: &str = "07";
+
+    let tables = [
+        ("tcp", "proc/net/tcp", TCP_LISTEN),
+        ("tcp", "proc/net/tcp6", TCP_LISTEN),
+        ("udp", "proc/net/udp", UDP_UNCONNECTED),
+        ("udp", "proc/net/udp6", UDP_UNCONNECTED),
+    ];
+    let mut found = false;
+    let mut ports = BTreeSet::new();
+    for (protocol, table, listening) in tables {
+        let Ok(content) = read_to_string(root.join(table)) else {
+            continue;
+        };
+        found = true;
+        // sl local_address rem_address st ..., the header comes first
+        for line in content.lines().skip(1) {
+            let mut fields = line.split_whitespace().skip(1);
+            let (Some(local), Some(_), Some(state)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let port = local.rsplit_once(':').and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if let (Some(port), true) = (port, state == listening) {
+                ports.insert((protocol, port));
+            }
+        }
+    }
+    found.then(|| join(ports.iter().map(|(protocol, port)| format!("{protocol}/{port}"))))
+}
+
+/// When the package databases last changed.
+fn last_patch_time(package_dbs: &[PathBuf]) -> Option<String> {
+    package_dbs
+        .iter()
+        .filter_map(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .max()
+        .map(rfc3339)
+}
+
+fn join<T: ToString>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
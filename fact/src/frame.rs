@@ -0,0 +1,361 @@
+//! Framing of the messages exchanged over VSOCK between the VM agent and
+//! the VSOCK listener.
+//!
+//! Every message starts with a 16 bytes header, little endian:
+//!
+//! | Offset | Size | Field |
+//! |--------|------|-------|
+//! | 0 | 4 | magic, `FACT` |
+//! | 4 | 1 | protocol version |
+//! | 5 | 1 | message type |
+//! | 6 | 2 | flags |
+//! | 8 | 4 | request ID |
+//! | 12 | 4 | payload length |
+//!
+//! A connection starts with the agent sending a `Hello` with the range of
+//! versions it speaks, the listener answering with a `HelloAck` carrying
+//! the version picked for the connection. Every other request is answered
+//! with an `Ack` or an `Error` with the same request ID, listeners
+//! answering requests of types they do not know with
+//! [`ERROR_UNSUPPORTED_TYPE`] so new types can be added.
+//!
+//! Listeners refuse frames longer than their limit, compressed or not,
+//! with [`ERROR_TOO_LARGE`] instead of reading them, and reports they
+//! cannot relay right away with [`ERROR_UNAVAILABLE`], to be sent again
+//! later.
+//!
+//! Agents predating this protocol send the length of their report, then
+//! the report. Listeners tell them apart by the magic, which no report
+//! sent as a legacy length starts with. Listeners predating this protocol
+//! read the magic as a length of about 1.4 GB, so agents only send them a
+//! hello once.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use anyhow::{bail, ensure, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+pub const MAGIC: [u8; 4] = *b"FACT";
+pub const HEADER_LEN: usize = 16;
+
+/// Versions of the protocol this build speaks.
+pub const MIN_VERSION: u8 = 1;
+pub const MAX_VERSION: u8 = 1;
+
+/// The payload is gzip compressed.
+pub const FLAG_GZIP: u16 = 1 << 0;
+
+/// Codes of `Error` messages.
+pub const ERROR_UNSUPPORTED_VERSION: u32 = 1;
+pub const ERROR_UNSUPPORTED_TYPE: u32 = 2;
+pub const ERROR_INVALID: u32 = 3;
+pub const ERROR_TOO_LARGE: u32 = 4;
+pub const ERROR_UNAVAILABLE: u32 = 5;
+
+/// A payload is longer than the peer accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "payload larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Agent to listener, with the lowest and highest versions the agent
+    /// speaks as payload
+    Hello,
+    /// Listener to agent, with the version picked in the header
+    HelloAck,
+    /// The request was received
+    Ack,
+    /// The request was not accepted, with an error code and a message
+    Error,
+    /// Agent to listener, a serialized `IndexReport`, possibly signed
+    IndexReport,
+    /// Agent to listener, a serialized `storage.VirtualMachine` with the
+    /// facts of the VM, possibly signed
+    Facts,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => MessageType::Hello,
+            2 => MessageType::HelloAck,
+            3 => MessageType::Ack,
+            4 => MessageType::Error,
+            5 => MessageType::IndexReport,
+            6 => MessageType::Facts,
+            other => MessageType::Unknown(other),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(kind: MessageType) -> Self {
+        match kind {
+            MessageType::Hello => 1,
+            MessageType::HelloAck => 2,
+            MessageType::Ack => 3,
+            MessageType::Error => 4,
+            MessageType::IndexReport => 5,
+            MessageType::Facts => 6,
+            MessageType::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub kind: MessageType,
+    pub flags: u16,
+    pub request_id: u32,
+    pub length: u32,
+}
+
+impl Header {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4] = self.version;
+        buf[5] = self.kind.into();
+        buf[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.request_id.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.length.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; HEADER_LEN]) -> anyhow::Result<Self> {
+        ensure!(buf[..4] == MAGIC, "invalid magic {:02x?}", &buf[..4]);
+        let le_u32 = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        Ok(Header {
+            version: buf[4],
+            kind: buf[5].into(),
+            flags: u16::from_le_bytes([buf[6], buf[7]]),
+            request_id: le_u32(8),
+            length: le_u32(12),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub version: u8,
+    pub kind: MessageType,
+    pub flags: u16,
+    pub request_id: u32,
+    /// As sent, compressed when flagged so
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(version: u8, kind: MessageType, request_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            version,
+            kind,
+            flags: 0,
+            request_id,
+            payload,
+        }
+    }
+
+    /// A frame with its payload gzip compressed.
+    pub fn compressed(
+        version: u8,
+        kind: MessageType,
+        request_id: u32,
+        payload: &[u8],
+    ) -> anyhow::Result<Self> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload)?;
+        Ok(Frame {
+            flags: FLAG_GZIP,
+            ..Frame::new(version, kind, request_id, encoder.finish()?)
+        })
+    }
+
+    pub fn hello() -> Self {
+        Frame::new(MAX_VERSION, MessageType::Hello, 0, vec![MIN_VERSION, MAX_VERSION])
+    }
+
+    pub fn ack(version: u8, request_id: u32) -> Self {
+        Frame::new(version, MessageType::Ack, request_id, Vec::new())
+    }
+
+    pub fn error(version: u8, request_id: u32, code: u32, message: &str) -> Self {
+        let mut payload = code.to_le_bytes().to_vec();
+        payload.extend_from_slice(message.as_bytes());
+        Frame::new(version, MessageType::Error, request_id, payload)
+    }
+
+    pub fn from_parts(header: Header, payload: Vec<u8>) -> Self {
+        Frame {
+            version: header.version,
+            kind: header.kind,
+            flags: header.flags,
+            request_id: header.request_id,
+            payload,
+        }
+    }
+
+    pub fn header(&self) -> Header {
+        Header {
+            version: self.version,
+            kind: self.kind,
+            flags: self.flags,
+            request_id: self.request_id,
+            length: self.payload.len() as u32,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.header().encode());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// The payload, decompressed, failing with [`TooLarge`] when it is
+    /// longer than `limit` bytes.
+    pub fn data(&self, limit: usize) -> anyhow::Result<Vec<u8>> {
+        if self.flags & FLAG_GZIP == 0 {
+            if self.payload.len() > limit {
+                return Err(TooLarge { limit }.into());
+            }
+            return Ok(self.payload.clone());
+        }
+        // Read one byte past the limit to tell a payload of exactly
+        // `limit` bytes from a longer one, without inflating the rest
+        let mut data = Vec::new();
+        GzDecoder::new(self.payload.as_slice())
+            .take(limit as u64 + 1)
+            .read_to_end(&mut data)
+            .context("invalid gzip payload")?;
+        if data.len() > limit {
+            return Err(TooLarge { limit }.into());
+        }
+        Ok(data)
+    }
+
+    /// Versions offered by a `Hello`, lowest and highest.
+    pub fn hello_versions(&self) -> anyhow::Result<(u8, u8)> {
+        match self.payload[..] {
+            [min, max, ..] if min <= max => Ok((min, max)),
+            _ => bail!("invalid hello payload {:02x?}", self.payload),
+        }
+    }
+
+    /// Code and message of an `Error`.
+    pub fn error_details(&self) -> (u32, String) {
+        match self.payload.split_first_chunk::<4>() {
+            Some((code, message)) => {
+                (u32::from_le_bytes(*code), String::from_utf8_lossy(message).into_owned())
+            }
+            None => (0, String::new()),
+        }
+    }
+}
+
+/// Version to speak with an agent offering `min..=max`, the highest both
+/// sides know.
+pub fn negotiate(min: u8, max: u8) -> Option<u8> {
+    let version = max.min(MAX_VERSION);
+    (version >= min.max(MIN_VERSION)).then_some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            version: 1,
+            kind: MessageType::Unknown(42),
+            flags: FLAG_GZIP,
+            request_id: 0xdead_beef,
+            length: 1234,
+        };
+        let buf = header.encode();
+        assert_eq!(&buf[..4], b"FACT");
+        assert_eq!(Header::decode(&buf).unwrap(), header);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = Frame::error(1, 7, ERROR_TOO_LARGE, "too large");
+        let buf = frame.encode();
+        let header = Header::decode(buf[..HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(header.length as usize, buf.len() - HEADER_LEN);
+        let decoded = Frame::from_parts(header, buf[HEADER_LEN..].to_vec());
+        assert_eq!(decoded, frame);
+        assert_eq!(decoded.error_details(), (ERROR_TOO_LARGE, "too large".to_string()));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = Frame::hello().header().encode();
+        buf[..4].copy_from_slice(b"FACS");
+        assert!(Header::decode(&buf).is_err());
+        // The length of a legacy report
+        let mut buf = [0; HEADER_LEN];
+        buf[..4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(Header::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn hello_versions() {
+        assert_eq!(Frame::hello().hello_versions().unwrap(), (MIN_VERSION, MAX_VERSION));
+        let hello = |payload: Vec<u8>| Frame::new(MAX_VERSION, MessageType::Hello, 0, payload);
+        assert_eq!(hello(vec![1, 3, 0]).hello_versions().unwrap(), (1, 3));
+        assert!(hello(vec![3, 1]).hello_versions().is_err());
+        assert!(hello(vec![1]).hello_versions().is_err());
+        assert!(hello(vec![]).hello_versions().is_err());
+    }
+
+    #[test]
+    fn negotiate_versions() {
+        assert_eq!(negotiate(MIN_VERSION, MAX_VERSION), Some(MAX_VERSION));
+        assert_eq!(negotiate(0, u8::MAX), Some(MAX_VERSION));
+        assert_eq!(negotiate(MAX_VERSION + 1, u8::MAX), None);
+        assert_eq!(negotiate(0, MIN_VERSION - 1), None);
+    }
+
+    #[test]
+    fn data_limit() {
+        let payload = vec![b'x'; 1000];
+        let plain = Frame::new(MAX_VERSION, MessageType::IndexReport, 1, payload.clone());
+        assert_eq!(plain.data(1000).unwrap(), payload);
+        assert!(plain.data(999).unwrap_err().is::<TooLarge>());
+
+        let compressed = Frame::compressed(MAX_VERSION, MessageType::IndexReport, 1, &payload).unwrap();
+        assert!(compressed.payload.len() < 100);
+        assert_eq!(compressed.data(1000).unwrap(), payload);
+        assert_eq!(
+            compressed.data(999).unwrap_err().downcast::<TooLarge>().unwrap(),
+            TooLarge { limit: 999 }
+        );
+    }
+
+    #[test]
+    fn data_invalid_gzip() {
+        let frame = Frame {
+            flags: FLAG_GZIP,
+            ..Frame::new(MAX_VERSION, MessageType::IndexReport, 1, b"not gzip".to_vec())
+        };
+        let err = frame.data(1000).unwrap_err();
+        assert!(!err.is::<TooLarge>());
+    }
+}
@@ -0,0 +1,167 @@
+//! Health of the components making up a running fact instance.
+//!
+//! Components report their own state, the `/healthz` (liveness) and
+//! `/readyz` (readiness) endpoints aggregate them. A component that
+//! failed for good makes the process unhealthy so it gets restarted,
+//! one that is merely not ready (e.g. sensor is unreachable) only
+//! affects readiness.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+pub const BPF: &str = "bpf";
+pub const RINGBUF_CONSUMER: &str = "ringbuf_consumer";
+pub const SENSOR: &str = "sensor";
+pub const SENSOR_RELAY: &str = "sensor_relay";
+pub const VSOCK_SERVER: &str = "vsock_server";
+pub const VM_AGENT: &str = "vm_agent";
+pub const VM_SCAN: &str = "vm_scan";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Registered, but not ready yet
+    Starting,
+    Ready,
+    /// Running, but currently unable to do its job
+    NotReady,
+    /// Stopped for good, restarting the process is the only remedy
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Component {
+    state: State,
+    message: String,
+    since: Instant,
+    /// A ready component goes stale if not refreshed before this
+    expires: Option<Instant>,
+}
+
+impl Component {
+    fn state(&self) -> State {
+        match self.expires {
+            Some(expires) if self.state == State::Ready && expires < Instant::now() => {
+                State::NotReady
+            }
+            _ => self.state,
+        }
+    }
+}
+
+static COMPONENTS: LazyLock<Mutex<BTreeMap<&'static str, Component>>> =
+    LazyLock::new(Default::default);
+
+fn set(name: &'static str, state: State, message: String, ttl: Option<Duration>) {
+    let now = Instant::now();
+    let component = Component {
+        state,
+        message,
+        since: now,
+        expires: ttl.map(|ttl| now + ttl),
+    };
+    COMPONENTS.lock().unwrap().insert(name, component);
+}
+
+/// Start tracking a component, it is considered not ready until it
+/// reports otherwise.
+pub fn register(name: &'static str) {
+    set(name, State::Starting, String::new(), None);
+}
+
+pub fn set_ready(name: &'static str) {
+    set(name, State::Ready, String::new(), None);
+}
+
+/// Mark a component as ready, going stale if not refreshed within `ttl`.
+pub fn set_ready_for(name: &'static str, ttl: Duration) {
+    set(name, State::Ready, String::new(), Some(ttl));
+}
+
+pub fn set_not_ready(name: &'static str, message: impl ToString) {
+    set(name, State::NotReady, message.to_string(), None);
+}
+
+pub fn set_failed(name: &'static str, message: impl ToString) {
+    set(name, State::Failed, message.to_string(), None);
+}
+
+/// Spawn a long running task for a component, marking it as failed if
+/// the task returns an error or panics.
+pub fn spawn<F>(name: &'static str, task: F) -> JoinHandle<()>
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    register(name);
+    let handle = tokio::spawn(task);
+    tokio::spawn(async move {
+        match handle.await {
+            Ok(Ok(())) => set_not_ready(name, "stopped"),
+            Ok(Err(e)) => {
+                warn!("{name} failed: {e:#}");
+                set_failed(name, format!("{e:#}"));
+            }
+            Err(e) => {
+                warn!("{name} task died: {e}");
+                set_failed(name, e);
+            }
+        }
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentReport {
+    state: State,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+    seconds_in_state: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    ok: bool,
+    components: BTreeMap<&'static str, ComponentReport>,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+}
+
+fn report(ok: impl Fn(State) -> bool) -> Report {
+    let components = COMPONENTS.lock().unwrap();
+    let components: BTreeMap<_, _> = components
+        .iter()
+        .map(|(name, c)| {
+            let report = ComponentReport {
+                state: c.state(),
+                message: c.message.clone(),
+                seconds_in_state: c.since.elapsed().as_secs(),
+            };
+            (*name, report)
+        })
+        .collect();
+    Report {
+        ok: components.values().all(|c| ok(c.state)),
+        components,
+    }
+}
+
+/// Liveness: nothing has failed beyond recovery.
+pub fn liveness() -> Report {
+    report(|state| state != State::Failed)
+}
+
+/// Readiness: every component is up and doing its job.
+pub fn readiness() -> Report {
+    report(|state| state == State::Ready)
+}
@@ -0,0 +1,108 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::Context;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, ALLOW, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use log::{debug, info, warn};
+use tokio::{net::TcpListener, time::Duration};
+
+use crate::{health, metrics};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the operational endpoints (`/metrics`, `/healthz` and
+/// `/readyz`) on the given address.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    metrics::init();
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind HTTP endpoint on {addr}"))?;
+    info!("Serving metrics and health checks on http://{addr}");
+
+    let mut builder = http1::Builder::new();
+    builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(REQUEST_TIMEOUT)
+        .keep_alive(false);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Failed to accept HTTP connection: {e}");
+                continue;
+            }
+        };
+
+        let conn = builder.serve_connection(TokioIo::new(stream), service_fn(handle));
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("Failed to handle HTTP request from {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.method() != Method::GET {
+        let mut response = error(StatusCode::METHOD_NOT_ALLOWED);
+        response
+            .headers_mut()
+            .insert(ALLOW, HeaderValue::from_static("GET"));
+        return Ok(response);
+    }
+
+    let response = match request.uri().path() {
+        "/metrics" => match metrics::gather() {
+            Ok(body) => response(StatusCode::OK, metrics::CONTENT_TYPE, body),
+            Err(e) => {
+                warn!("Failed to gather metrics: {e}");
+                error(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+        "/healthz" => health_response(health::liveness()),
+        "/readyz" => health_response(health::readiness()),
+        _ => error(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+fn response(
+    status: StatusCode,
+    content_type: &'static str,
+    body: Vec<u8>,
+) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+fn error(status: StatusCode) -> Response<Full<Bytes>> {
+    response(status, "text/plain", format!("{status}\n").into_bytes())
+}
+
+fn health_response(report: health::Report) -> Response<Full<Bytes>> {
+    let status = if report.ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    match serde_json::to_vec(&report) {
+        Ok(body) => response(status, "application/json", body),
+        Err(e) => {
+            warn!("Failed to serialize health report: {e}");
+            error(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Identity of the node fact runs on, attached as gRPC metadata to every
+//! request made to sensor, to every file activity and to every JSON
+//! record, so activity can be attributed even when connections go
+//! through a proxy.
+
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use anyhow::Context;
+use prost::Message;
+use serde::Serialize;
+use tonic::{
+    metadata::{AsciiMetadataKey, AsciiMetadataValue},
+    service::Interceptor,
+};
+
+use crate::{config::FactConfig, host_info};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Serialize)]
+pub struct Identity {
+    node_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster_id: Option<String>,
+    version: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    #[serde(skip)]
+    metadata: Vec<(AsciiMetadataKey, AsciiMetadataValue)>,
+    #[serde(skip)]
+    encoded: Vec<u8>,
+}
+
+/// Identity appended to every serialized file activity, its tags are
+/// out of the range used by `FileActivity` so sensors that predate it
+/// keep it as unknown fields.
+#[derive(Clone, PartialEq, Message)]
+struct NodeIdentity {
+    #[prost(string, tag = "1001")]
+    hostname: String,
+    #[prost(string, tag = "1002")]
+    node_name: String,
+    #[prost(string, tag = "1003")]
+    cluster_id: String,
+    #[prost(string, tag = "1004")]
+    version: String,
+    #[prost(btree_map = "string, string", tag = "1005")]
+    labels: BTreeMap<String, String>,
+}
+
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+impl Identity {
+    fn from_config(config: &FactConfig) -> anyhow::Result<Self> {
+        let node_name = config
+            .node_name
+            .clone()
+            .unwrap_or_else(|| host_info::get_hostname().to_owned());
+        let labels: BTreeMap<_, _> = config.labels.iter().cloned().collect();
+
+        let mut metadata = vec![
+            ("x-fact-node-name", node_name.clone()),
+            ("x-fact-version", VERSION.to_owned()),
+        ];
+        if let Some(cluster_id) = &config.cluster_id {
+            metadata.push(("x-fact-cluster-id", cluster_id.clone()));
+        }
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            metadata.push(("x-fact-labels", labels));
+        }
+        let metadata = metadata
+            .into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .parse()
+                    .with_context(|| format!("Invalid value for {key} metadata: '{value}'"))?;
+                Ok((AsciiMetadataKey::from_static(key), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let encoded = NodeIdentity {
+            hostname: host_info::get_hostname().to_owned(),
+            node_name: node_name.clone(),
+            cluster_id: config.cluster_id.clone().unwrap_or_default(),
+            version: VERSION.to_owned(),
+            labels: labels.clone(),
+        }
+        .encode_to_vec();
+
+        Ok(Identity {
+            node_name,
+            cluster_id: config.cluster_id.clone(),
+            version: VERSION,
+            labels,
+            metadata,
+            encoded,
+        })
+    }
+
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    pub fn cluster_id(&self) -> Option<&str> {
+        self.cluster_id.as_deref()
+    }
+
+    /// The identity serialized as protobuf fields, to be appended to a
+    /// serialized message.
+    pub fn encoded(&self) -> &[u8] {
+        &self.encoded
+    }
+}
+
+/// Resolve the identity of this node, must be called before any
+/// connection to sensor is made.
+pub fn init(config: &FactConfig) -> anyhow::Result<()> {
+    let identity = Identity::from_config(config)?;
+    let _ = IDENTITY.set(identity);
+    Ok(())
+}
+
+pub fn get() -> &'static Identity {
+    IDENTITY.get().expect("node identity is not initialized")
+}
+
+/// User-agent of the clients of sensor, `StackRox Fact/<version>
+/// (<component>)`.
+pub fn user_agent(component: &str) -> String {
+    format!("StackRox Fact/{VERSION} ({component})")
+}
+
+/// Attach the node identity to outgoing requests, along with the
+/// user-agent of the component making them.
+#[derive(Debug, Clone)]
+pub struct MetadataInterceptor {
+    user_agent: AsciiMetadataValue,
+}
+
+impl MetadataInterceptor {
+    pub fn new(component: &'static str) -> Self {
+        let user_agent = user_agent(component).parse().expect("invalid user-agent");
+        MetadataInterceptor { user_agent }
+    }
+}
+
+impl Interceptor for MetadataInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        let metadata = request.metadata_mut();
+        metadata.insert("user-agent", self.user_agent.clone());
+        for (key, value) in &get().metadata {
+            metadata.insert(key.clone(), value.clone());
+        }
+        Ok(request)
+    }
+}
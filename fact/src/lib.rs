@@ -4,25 +4,57 @@ use aya::{
     Btf,
 };
 use client::Client;
-use config::{AgentMode, FactConfig};
+use config::{AgentMode, Command, FactConfig};
 use event::Event;
+use transport::Transport;
 use log::{debug, info, warn};
 use tokio::{io::unix::AsyncFd, signal, task::yield_now};
 
 mod bpf;
+mod budget;
 mod certs;
 mod client;
+mod collector;
 pub mod config;
+mod delivery;
 mod event;
+mod facts;
+pub mod frame;
+mod health;
 mod host_info;
+mod http;
+mod identity;
+mod metrics;
+mod output;
+mod sbom;
 mod sensor_relay;
+mod signing;
+mod transport;
 mod vm_agent;
 mod vm_watcher;
 mod vsock;
+mod watch;
 
 use bpf::bindings::{event_t, path_cfg_t};
 
 pub async fn run(config: FactConfig) -> anyhow::Result<()> {
+    identity::init(&config)?;
+
+    match &config.command {
+        Some(Command::Scan(args)) => return vm_agent::scan(&config, &args.output).await,
+        Some(Command::Replay(args)) => return vm_agent::replay(&config, &args.file).await,
+        Some(Command::Sbom(args)) => return vm_agent::sbom(&config, args).await,
+        None => {}
+    }
+
+    if let Some(addr) = config.http_addr {
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr).await {
+                warn!("HTTP endpoint error: {e}");
+            }
+        });
+    }
+
     match config.mode {
         AgentMode::FileMonitor => run_file_monitor(config).await,
         AgentMode::VmAgent => vm_agent::run_vm_agent(&config).await,
@@ -43,6 +75,8 @@ async fn run_file_monitor(config: FactConfig) -> anyhow::Result<()> {
         debug!("remove limit on locked memory failed, ret is: {ret}");
     }
 
+    health::register(health::BPF);
+
     // Include the BPF object as raw bytes at compile-time and load it
     // at runtime.
     let mut bpf = aya::EbpfLoader::new()
@@ -72,24 +106,35 @@ async fn run_file_monitor(config: FactConfig) -> anyhow::Result<()> {
     let program: &mut Lsm = bpf.program_mut("trace_file_open").unwrap().try_into()?;
     program.load("file_open", &btf)?;
     program.attach()?;
+    health::set_ready(health::BPF);
 
     // Create the gRPC client
     let mut client = if let Some(url) = config.url.as_ref() {
-        Some(Client::start(url, config.certs)?)
+        Some(Client::start(url, Transport::try_from(&config)?)?)
     } else {
         None
     };
 
     // Gather events from the ring buffer and print them out.
-    tokio::spawn(async move {
+    let output = config.output;
+    health::spawn(health::RINGBUF_CONSUMER, async move {
+        health::set_ready(health::RINGBUF_CONSUMER);
         loop {
-            let mut guard = async_fd.readable_mut().await.unwrap();
+            let mut guard = async_fd.readable_mut().await?;
             let ringbuf = guard.get_inner_mut();
             while let Some(event) = ringbuf.next() {
                 let event: &event_t = unsafe { &*(event.as_ptr() as *const _) };
-                let event: Event = event.try_into().unwrap();
+                let event: Event = match event.try_into() {
+                    Ok(event) => event,
+                    Err(e) => {
+                        metrics::EVENT_DECODE_FAILURES_TOTAL.inc();
+                        warn!("Failed to decode event: {e}");
+                        continue;
+                    }
+                };
+                metrics::EVENTS_TOTAL.inc();
 
-                println!("{event:?}");
+                output::print_event(&event, output);
                 if let Some(client) = client.as_mut() {
                     let _ = client.send(event).await;
                 }
@@ -107,21 +152,9 @@ async fn run_file_monitor(config: FactConfig) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_vsock_listener(config: FactConfig) -> anyhow::Result<()> {
-    use sensor_relay::SensorRelay;
-    use vm_watcher::VmWatcher;
-    use vsock::{VsockServer, VmMessage};
-    use certs::Certs;
-    
-    info!("Starting VSOCK listener mode on port {}", config.vsock_port);
-    
-    // Set up shutdown signal
+/// Broadcast channel used to tell every task to stop, fired on Ctrl-C.
+fn shutdown_channel() -> tokio::sync::broadcast::Sender<()> {
     let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
-    let shutdown_rx1 = shutdown_tx.subscribe();
-    let shutdown_rx2 = shutdown_tx.subscribe();
-    let shutdown_rx3 = shutdown_tx.subscribe();
-    
-    // Set up signal handling
     tokio::spawn({
         let shutdown_tx = shutdown_tx.clone();
         async move {
@@ -130,176 +163,94 @@ async fn run_vsock_listener(config: FactConfig) -> anyhow::Result<()> {
             let _ = shutdown_tx.send(());
         }
     });
-    
-    // Load certificates if provided
-    let certs = if let Some(cert_path) = &config.certs {
-        Some(Certs::try_from(cert_path.clone())?)
-    } else {
-        None
-    };
-    
+    shutdown_tx
+}
+
+/// Start the VSOCK server along with the VM watcher and the relay
+/// forwarding VM messages to sensor.
+fn start_vsock_listener(
+    config: &FactConfig,
+    shutdown_tx: &tokio::sync::broadcast::Sender<()>,
+) -> anyhow::Result<()> {
+    use sensor_relay::SensorRelay;
+    use vm_watcher::VmWatcher;
+    use vsock::{VmMessage, VsockServer};
+
+    let transport = Transport::try_from(config)?;
+
     // Start VM watcher
-    let (mut vm_watcher, _vm_rx) = VmWatcher::new();
+    let mut vm_watcher = VmWatcher;
+    let shutdown_rx = shutdown_tx.subscribe();
     tokio::spawn(async move {
-        if let Err(e) = vm_watcher.start(shutdown_rx1).await {
+        if let Err(e) = vm_watcher.start(shutdown_rx).await {
             warn!("VM watcher error: {}", e);
         }
     });
-    
+
     // Create VSOCK server
-    let vsock_server = VsockServer::bind(config.vsock_port)?;
-    
+    let vsock_server = VsockServer::bind(config.vsock_port, &config.vsock)?;
+
     // Start sensor relay
-    let mut sensor_relay = SensorRelay::new(config.sensor_endpoint.clone(), certs);
+    let verifier = signing::Verifier::load(&config.signing, &config.state_dir);
+    let mut sensor_relay =
+        SensorRelay::new(config.sensor_endpoint.clone(), transport, config.output, verifier);
     let (vm_msg_tx, vm_msg_rx) = tokio::sync::mpsc::channel::<VmMessage>(100);
-    
-    tokio::spawn(async move {
-        if let Err(e) = sensor_relay.start(vm_msg_rx, shutdown_rx2).await {
-            warn!("Sensor relay error: {}", e);
-        }
+    let shutdown_rx = shutdown_tx.subscribe();
+    health::spawn(health::SENSOR_RELAY, async move {
+        sensor_relay.start(vm_msg_rx, shutdown_rx).await
     });
-    
+
     // Start VSOCK server
-    tokio::spawn(async move {
-        if let Err(e) = vsock_server.serve(shutdown_rx3).await {
-            warn!("VSOCK server error: {}", e);
-        }
+    let shutdown_rx = shutdown_tx.subscribe();
+    health::spawn(health::VSOCK_SERVER, async move {
+        vsock_server.serve(vm_msg_tx, shutdown_rx).await
     });
-    
+
+    Ok(())
+}
+
+async fn run_vsock_listener(config: FactConfig) -> anyhow::Result<()> {
+    info!("Starting VSOCK listener mode on port {}", config.vsock_port);
+
+    let shutdown_tx = shutdown_channel();
+    start_vsock_listener(&config, &shutdown_tx)?;
+
     // Wait for shutdown
     let _ = shutdown_tx.subscribe().recv().await;
     info!("VSOCK listener shutting down");
-    
+
     Ok(())
 }
 
 async fn run_hybrid_mode(config: FactConfig) -> anyhow::Result<()> {
-    use sensor_relay::SensorRelay;
-    use vm_watcher::VmWatcher;
-    use vsock::{VsockServer, VmMessage};
-    use certs::Certs;
-    
     info!("Starting hybrid mode (VM agent + VSOCK listener)");
-    
-    // Set up shutdown signal
-    let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
-    let shutdown_rx1 = shutdown_tx.subscribe();
-    let shutdown_rx2 = shutdown_tx.subscribe();
-    let shutdown_rx3 = shutdown_tx.subscribe();
-    let shutdown_rx4 = shutdown_tx.subscribe();
-    
-    // Set up signal handling
-    tokio::spawn({
-        let shutdown_tx = shutdown_tx.clone();
-        async move {
-            let _ = signal::ctrl_c().await;
-            info!("Received shutdown signal");
-            let _ = shutdown_tx.send(());
-        }
-    });
-    
-    // Load certificates if provided
-    let certs = if let Some(cert_path) = &config.certs {
-        Some(Certs::try_from(cert_path.clone())?)
-    } else {
-        None
-    };
-    
+
+    let shutdown_tx = shutdown_channel();
+
+    // If neither mode is explicitly enabled, enable both by default
+    let enable_all = !config.enable_vm_agent && !config.enable_vsock_server;
+    if enable_all {
+        info!("No specific mode enabled, starting both VM agent and VSOCK listener");
+    }
+
     // Start VM agent if enabled
-    if config.enable_vm_agent {
+    if config.enable_vm_agent || enable_all {
         info!("Starting VM agent functionality");
         let vm_config = config.clone();
-        tokio::spawn(async move {
-            if let Err(e) = vm_agent::run_vm_agent(&vm_config).await {
-                warn!("VM agent error: {}", e);
-            }
+        health::spawn(health::VM_AGENT, async move {
+            vm_agent::run_vm_agent(&vm_config).await
         });
     }
-    
+
     // Start VSOCK listener if enabled
-    if config.enable_vsock_server {
+    if config.enable_vsock_server || enable_all {
         info!("Starting VSOCK listener functionality on port {}", config.vsock_port);
-        
-        // Start VM watcher
-        let (mut vm_watcher, _vm_rx) = VmWatcher::new();
-        tokio::spawn(async move {
-            if let Err(e) = vm_watcher.start(shutdown_rx1).await {
-                warn!("VM watcher error: {}", e);
-            }
-        });
-        
-        // Create VSOCK server
-        let vsock_server = VsockServer::bind(config.vsock_port)?;
-        
-        // Start sensor relay
-        let mut sensor_relay = SensorRelay::new(config.sensor_endpoint.clone(), certs);
-        let (vm_msg_tx, vm_msg_rx) = tokio::sync::mpsc::channel::<VmMessage>(100);
-        
-        tokio::spawn(async move {
-            if let Err(e) = sensor_relay.start(vm_msg_rx, shutdown_rx2).await {
-                warn!("Sensor relay error: {}", e);
-            }
-        });
-        
-        // Start VSOCK server
-        tokio::spawn(async move {
-            if let Err(e) = vsock_server.serve(shutdown_rx3).await {
-                warn!("VSOCK server error: {}", e);
-            }
-        });
-    }
-    
-    // If neither mode is explicitly enabled, enable both by default
-    if !config.enable_vm_agent && !config.enable_vsock_server {
-        info!("No specific mode enabled, starting both VM agent and VSOCK listener");
-        
-        // Start VM agent
-        let vm_config = config.clone();
-        tokio::spawn(async move {
-            if let Err(e) = vm_agent::run_vm_agent(&vm_config).await {
-                warn!("VM agent error: {}", e);
-            }
-        });
-        
-        // Start VM watcher
-        let (mut vm_watcher, _vm_rx) = VmWatcher::new();
-        tokio::spawn(async move {
-            if let Err(e) = vm_watcher.start(shutdown_rx4).await {
-                warn!("VM watcher error: {}", e);
-            }
-        });
-        
-        // Create VSOCK server
-        let vsock_server = VsockServer::bind(config.vsock_port)?;
-        
-        // Start sensor relay
-        let certs = if let Some(cert_path) = &config.certs {
-            Some(Certs::try_from(cert_path.clone())?)
-        } else {
-            None
-        };
-        let mut sensor_relay = SensorRelay::new(config.sensor_endpoint.clone(), certs);
-        let (vm_msg_tx, vm_msg_rx) = tokio::sync::mpsc::channel::<VmMessage>(100);
-        
-        let shutdown_tx_clone = shutdown_tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = sensor_relay.start(vm_msg_rx, shutdown_tx_clone.subscribe()).await {
-                warn!("Sensor relay error: {}", e);
-            }
-        });
-        
-        // Start VSOCK server
-        let shutdown_tx_clone = shutdown_tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = vsock_server.serve(shutdown_tx_clone.subscribe()).await {
-                warn!("VSOCK server error: {}", e);
-            }
-        });
+        start_vsock_listener(&config, &shutdown_tx)?;
     }
-    
+
     // Wait for shutdown
     let _ = shutdown_tx.subscribe().recv().await;
     info!("Hybrid mode shutting down");
-    
+
     Ok(())
 }
@@ -0,0 +1,183 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+pub static REGISTRY: LazyLock<Registry> =
+    LazyLock::new(|| Registry::new_custom(Some("fact".into()), None).unwrap());
+
+fn register<T>(metric: T) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+fn int_counter(name: &str, help: &str) -> IntCounter {
+    register(IntCounter::new(name, help).unwrap())
+}
+
+fn int_counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(IntCounterVec::new(Opts::new(name, help), labels).unwrap())
+}
+
+fn int_gauge(name: &str, help: &str) -> IntGauge {
+    register(IntGauge::new(name, help).unwrap())
+}
+
+/// Buckets from 1ms to ~65s, good enough for network round trips and scans.
+fn duration_buckets() -> Vec<f64> {
+    exponential_buckets(0.001, 2.0, 17).unwrap()
+}
+
+// File monitor
+pub static EVENTS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter("events_total", "File activity events read from the ring buffer")
+});
+pub static EVENT_DECODE_FAILURES_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter(
+        "event_decode_failures_total",
+        "Ring buffer events that could not be decoded",
+    )
+});
+pub static EVENT_QUEUE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    let opts = HistogramOpts::new(
+        "event_queue_duration_seconds",
+        "Time file activity events waited for room in the queue to sensor",
+    )
+    .buckets(duration_buckets());
+    register(Histogram::with_opts(opts).unwrap())
+});
+
+// gRPC clients, labeled by the client sending the data
+pub static SENSOR_SEND_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    let opts = HistogramOpts::new(
+        "sensor_send_duration_seconds",
+        "Time taken to hand a message over to sensor",
+    )
+    .buckets(duration_buckets());
+    register(HistogramVec::new(opts, &["client"]).unwrap())
+});
+pub static SENSOR_SEND_FAILURES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    int_counter_vec(
+        "sensor_send_failures_total",
+        "Messages that could not be sent to sensor",
+        &["client"],
+    )
+});
+
+// TLS
+pub static TLS_CERTIFICATE_EXPIRY: LazyLock<IntGauge> = LazyLock::new(|| {
+    int_gauge(
+        "tls_certificate_expiry_timestamp_seconds",
+        "Expiry of the client certificate in use, as a Unix timestamp",
+    )
+});
+pub static TLS_CERTIFICATE_RELOADS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter(
+        "tls_certificate_reloads_total",
+        "Client certificates reloaded after being rotated",
+    )
+});
+
+// VSOCK server
+pub static VSOCK_CONNECTIONS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter("vsock_connections_total", "VSOCK connections accepted")
+});
+pub static VSOCK_ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    int_gauge("vsock_active_connections", "VSOCK connections currently open")
+});
+pub static VSOCK_MESSAGES_RECEIVED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter("vsock_messages_received_total", "Messages received from VMs")
+});
+pub static VSOCK_BYTES_RECEIVED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter("vsock_bytes_received_total", "Payload bytes received from VMs")
+});
+pub static VSOCK_REJECTED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    int_counter_vec(
+        "vsock_rejected_total",
+        "VSOCK connections and messages refused, by reason",
+        &["reason"],
+    )
+});
+
+// Sensor relay
+pub static RELAYED_MESSAGES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    int_counter_vec(
+        "relayed_messages_total",
+        "VM messages forwarded to sensor, by result",
+        &["result"],
+    )
+});
+pub static VM_REPORT_SIGNATURES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    int_counter_vec(
+        "vm_report_signatures_total",
+        "Signatures of the relayed index reports, by verification result",
+        &["result"],
+    )
+});
+
+// VM agent
+pub static VM_SCAN_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    let opts = HistogramOpts::new(
+        "vm_scan_duration_seconds",
+        "Time taken to collect the package inventory",
+    )
+    .buckets(duration_buckets());
+    register(Histogram::with_opts(opts).unwrap())
+});
+pub static VM_SCANS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    int_counter_vec("vm_scans_total", "Package scans run, by result", &["result"])
+});
+pub static VM_SCAN_CONSECUTIVE_FAILURES: LazyLock<IntGauge> = LazyLock::new(|| {
+    int_gauge(
+        "vm_scan_consecutive_failures",
+        "Package scans that failed or were partial in a row",
+    )
+});
+pub static VM_PACKAGES: LazyLock<IntGauge> = LazyLock::new(|| {
+    int_gauge("vm_packages", "Packages found by the last successful scan")
+});
+
+pub static VM_REPORTS_SKIPPED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    int_counter(
+        "vm_reports_skipped_total",
+        "Index reports not sent because the packages did not change",
+    )
+});
+
+/// Register every metric upfront, so series are exported with a zero
+/// value instead of being missing until their first update.
+pub fn init() {
+    LazyLock::force(&EVENTS_TOTAL);
+    LazyLock::force(&EVENT_DECODE_FAILURES_TOTAL);
+    LazyLock::force(&EVENT_QUEUE_DURATION);
+    LazyLock::force(&SENSOR_SEND_DURATION);
+    LazyLock::force(&SENSOR_SEND_FAILURES_TOTAL);
+    LazyLock::force(&TLS_CERTIFICATE_EXPIRY);
+    LazyLock::force(&TLS_CERTIFICATE_RELOADS_TOTAL);
+    LazyLock::force(&VSOCK_CONNECTIONS_TOTAL);
+    LazyLock::force(&VSOCK_ACTIVE_CONNECTIONS);
+    LazyLock::force(&VSOCK_MESSAGES_RECEIVED_TOTAL);
+    LazyLock::force(&VSOCK_BYTES_RECEIVED_TOTAL);
+    LazyLock::force(&VSOCK_REJECTED_TOTAL);
+    LazyLock::force(&RELAYED_MESSAGES_TOTAL);
+    LazyLock::force(&VM_REPORT_SIGNATURES_TOTAL);
+    LazyLock::force(&VM_SCAN_DURATION);
+    LazyLock::force(&VM_SCANS_TOTAL);
+    LazyLock::force(&VM_SCAN_CONSECUTIVE_FAILURES);
+    LazyLock::force(&VM_PACKAGES);
+    LazyLock::force(&VM_REPORTS_SKIPPED_TOTAL);
+}
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Render all registered metrics in the Prometheus text format.
+pub fn gather() -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,481 @@
+//! Stable JSON representation of the records fact prints to stdout.
+//!
+//! Every record is a single JSON object per line, tagged with the name
+//! of its schema and the schema version. The corresponding JSON schemas
+//! live in `fact/schema/`. Adding optional fields is backwards
+//! compatible, removing or changing the meaning of a field requires
+//! bumping [`SCHEMA_VERSION`].
+
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::ensure;
+use fact_api::{scanner::v4, virtualmachine::v1::IndexReport};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::OutputFormat,
+    event::Event,
+    identity::{self, Identity},
+};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub const FILE_ACTIVITY_SCHEMA: &str = "fact.file_activity";
+pub const VM_INDEX_REPORT_SCHEMA: &str = "fact.vm_index_report";
+
+#[derive(Debug, Serialize)]
+struct Record<'a, T: Serialize> {
+    schema: &'static str,
+    version: u32,
+    identity: &'static Identity,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+/// Schema of a record, checked before parsing the rest of it.
+#[derive(Debug, Deserialize)]
+struct RecordHeader {
+    schema: String,
+    version: u32,
+}
+
+fn to_json<T: Serialize>(schema: &'static str, data: &T) -> serde_json::Result<String> {
+    let record = Record {
+        schema,
+        version: SCHEMA_VERSION,
+        identity: identity::get(),
+        data,
+    };
+    serde_json::to_string(&record)
+}
+
+fn print_json<T: Serialize>(schema: &'static str, data: &T) {
+    match to_json(schema, data) {
+        Ok(line) => println!("{line}"),
+        Err(e) => warn!("Failed to serialize {schema} record: {e}"),
+    }
+}
+
+pub fn print_event(event: &Event, format: OutputFormat) {
+    match format {
+        OutputFormat::Text => println!("{event:?}"),
+        OutputFormat::Json => print_json(FILE_ACTIVITY_SCHEMA, event),
+    }
+}
+
+pub fn print_index_report(report: &IndexReport, format: OutputFormat, transport: &str) {
+    match format {
+        OutputFormat::Text => println!("Full IndexReport content ({transport}): {report:#?}"),
+        OutputFormat::Json => print_json(VM_INDEX_REPORT_SCHEMA, &VmIndexReport::from(report)),
+    }
+}
+
+/// Serialize an index report the same way it is printed.
+pub fn index_report_to_json(report: &IndexReport) -> serde_json::Result<String> {
+    to_json(VM_INDEX_REPORT_SCHEMA, &VmIndexReport::from(report))
+}
+
+/// Parse an index report printed as JSON. Fields that are not part of
+/// the schema, which fact never sets, are left empty.
+pub fn index_report_from_json(json: &str) -> anyhow::Result<IndexReport> {
+    let header: RecordHeader = serde_json::from_str(json)?;
+    ensure!(
+        header.schema == VM_INDEX_REPORT_SCHEMA,
+        "expected a {VM_INDEX_REPORT_SCHEMA} record, found {}",
+        header.schema
+    );
+    ensure!(
+        header.version == SCHEMA_VERSION,
+        "unsupported {VM_INDEX_REPORT_SCHEMA} version {}",
+        header.version
+    );
+    let report: VmIndexReport = serde_json::from_str(json)?;
+    Ok(report.into())
+}
+
+/// Format a time as `YYYY-MM-DDThh:mm:ssZ`, in UTC.
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Days to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VmIndexReport {
+    vsock_cid: String,
+    hash_id: String,
+    state: String,
+    success: bool,
+    err: String,
+    contents: Contents,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Contents {
+    packages: Vec<Package>,
+    distributions: Vec<Distribution>,
+    repositories: Vec<Repository>,
+    environments: BTreeMap<String, Vec<Environment>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Package {
+    id: String,
+    name: String,
+    version: String,
+    kind: String,
+    source: Option<Box<Package>>,
+    package_db: String,
+    repository_hint: String,
+    module: String,
+    arch: String,
+    cpe: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Distribution {
+    id: String,
+    did: String,
+    name: String,
+    version: String,
+    version_code_name: String,
+    version_id: String,
+    arch: String,
+    cpe: String,
+    pretty_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Repository {
+    id: String,
+    name: String,
+    key: String,
+    uri: String,
+    cpe: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Environment {
+    package_db: String,
+    introduced_in: String,
+    distribution_id: String,
+    repository_ids: Vec<String>,
+}
+
+impl From<&IndexReport> for VmIndexReport {
+    fn from(value: &IndexReport) -> Self {
+        let index = value.index_v4.clone().unwrap_or_default();
+        VmIndexReport {
+            vsock_cid: value.vsock_cid.clone(),
+            hash_id: index.hash_id,
+            state: index.state,
+            success: index.success,
+            err: index.err,
+            contents: index.contents.map(Contents::from).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<v4::Contents> for Contents {
+    fn from(value: v4::Contents) -> Self {
+        Contents {
+            packages: value.packages.into_iter().map(Package::from).collect(),
+            distributions: value
+                .distributions
+                .into_iter()
+                .map(Distribution::from)
+                .collect(),
+            repositories: value
+                .repositories
+                .into_iter()
+                .map(Repository::from)
+                .collect(),
+            environments: value
+                .environments
+                .into_iter()
+                .map(|(id, list)| {
+                    let envs = list.environments.into_iter().map(Environment::from);
+                    (id, envs.collect())
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<v4::Package> for Package {
+    fn from(value: v4::Package) -> Self {
+        Package {
+            id: value.id,
+            name: value.name,
+            version: value.version,
+            kind: value.kind,
+            source: value.source.map(|s| Box::new(Package::from(*s))),
+            package_db: value.package_db,
+            repository_hint: value.repository_hint,
+            module: value.module,
+            arch: value.arch,
+            cpe: value.cpe,
+        }
+    }
+}
+
+impl From<v4::Distribution> for Distribution {
+    fn from(value: v4::Distribution) -> Self {
+        Distribution {
+            id: value.id,
+            did: value.did,
+            name: value.name,
+            version: value.version,
+            version_code_name: value.version_code_name,
+            version_id: value.version_id,
+            arch: value.arch,
+            cpe: value.cpe,
+            pretty_name: value.pretty_name,
+        }
+    }
+}
+
+impl From<v4::Repository> for Repository {
+    fn from(value: v4::Repository) -> Self {
+        Repository {
+            id: value.id,
+            name: value.name,
+            key: value.key,
+            uri: value.uri,
+            cpe: value.cpe,
+        }
+    }
+}
+
+impl From<v4::Environment> for Environment {
+    fn from(value: v4::Environment) -> Self {
+        Environment {
+            package_db: value.package_db,
+            introduced_in: value.introduced_in,
+            distribution_id: value.distribution_id,
+            repository_ids: value.repository_ids,
+        }
+    }
+}
+
+impl From<VmIndexReport> for IndexReport {
+    fn from(value: VmIndexReport) -> Self {
+        IndexReport {
+            vsock_cid: value.vsock_cid,
+            index_v4: Some(v4::IndexReport {
+                hash_id: value.hash_id,
+                state: value.state,
+                success: value.success,
+                err: value.err,
+                contents: Some(value.contents.into()),
+            }),
+        }
+    }
+}
+
+impl From<Contents> for v4::Contents {
+    fn from(value: Contents) -> Self {
+        v4::Contents {
+            packages: value.packages.into_iter().map(v4::Package::from).collect(),
+            distributions: value
+                .distributions
+                .into_iter()
+                .map(v4::Distribution::from)
+                .collect(),
+            repositories: value
+                .repositories
+                .into_iter()
+                .map(v4::Repository::from)
+                .collect(),
+            environments: value
+                .environments
+                .into_iter()
+                .map(|(id, envs)| {
+                    let environments = envs.into_iter().map(v4::Environment::from).collect();
+                    (id, v4::environment::List { environments })
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Package> for v4::Package {
+    fn from(value: Package) -> Self {
+        v4::Package {
+            id: value.id,
+            name: value.name,
+            version: value.version,
+            kind: value.kind,
+            source: value.source.map(|s| Box::new(v4::Package::from(*s))),
+            package_db: value.package_db,
+            repository_hint: value.repository_hint,
+            module: value.module,
+            arch: value.arch,
+            cpe: value.cpe,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Distribution> for v4::Distribution {
+    fn from(value: Distribution) -> Self {
+        v4::Distribution {
+            id: value.id,
+            did: value.did,
+            name: value.name,
+            version: value.version,
+            version_code_name: value.version_code_name,
+            version_id: value.version_id,
+            arch: value.arch,
+            cpe: value.cpe,
+            pretty_name: value.pretty_name,
+        }
+    }
+}
+
+impl From<Repository> for v4::Repository {
+    fn from(value: Repository) -> Self {
+        v4::Repository {
+            id: value.id,
+            name: value.name,
+            key: value.key,
+            uri: value.uri,
+            cpe: value.cpe,
+        }
+    }
+}
+
+impl From<Environment> for v4::Environment {
+    fn from(value: Environment) -> Self {
+        v4::Environment {
+            package_db: value.package_db,
+            introduced_in: value.introduced_in,
+            distribution_id: value.distribution_id,
+            repository_ids: value.repository_ids,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::config::FactConfig;
+
+    fn report() -> IndexReport {
+        let source = v4::Package {
+            id: "1".to_string(),
+            name: "openssl".to_string(),
+            version: "3.0.7-27.el9".to_string(),
+            kind: "source".to_string(),
+            ..Default::default()
+        };
+        let package = v4::Package {
+            id: "2".to_string(),
+            name: "openssl-libs".to_string(),
+            version: "1:3.0.7-27.el9".to_string(),
+            kind: "binary".to_string(),
+            source: Some(Box::new(source)),
+            package_db: "sqlite:var/lib/rpm".to_string(),
+            repository_hint: "hash:sha256:0123".to_string(),
+            arch: "x86_64".to_string(),
+            ..Default::default()
+        };
+        let distribution = v4::Distribution {
+            id: "3".to_string(),
+            did: "rhel".to_string(),
+            name: "Red Hat Enterprise Linux".to_string(),
+            version_id: "9".to_string(),
+            cpe: "cpe:2.3:o:redhat:enterprise_linux:9:*:*:*:*:*:*:*".to_string(),
+            ..Default::default()
+        };
+        let repository = v4::Repository {
+            id: "4".to_string(),
+            name: "cpe:/o:redhat:enterprise_linux:9::baseos".to_string(),
+            key: "rhel-cpe-repository".to_string(),
+            uri: String::new(),
+            cpe: "cpe:2.3:o:redhat:enterprise_linux:9:*:baseos:*:*:*:*:*".to_string(),
+        };
+        let environment = v4::Environment {
+            package_db: "sqlite:var/lib/rpm".to_string(),
+            introduced_in: "sha256:abcd".to_string(),
+            distribution_id: "3".to_string(),
+            repository_ids: vec!["4".to_string()],
+        };
+        IndexReport {
+            vsock_cid: "42".to_string(),
+            index_v4: Some(v4::IndexReport {
+                hash_id: "sha256:abcd".to_string(),
+                state: "IndexFinished".to_string(),
+                success: false,
+                err: "dpkg: permission denied".to_string(),
+                contents: Some(v4::Contents {
+                    packages: vec![package],
+                    distributions: vec![distribution],
+                    repositories: vec![repository],
+                    environments: [(
+                        "2".to_string(),
+                        v4::environment::List {
+                            environments: vec![environment],
+                        },
+                    )]
+                    .into(),
+                    ..Default::default()
+                }),
+            }),
+        }
+    }
+
+    fn to_json(report: &IndexReport) -> String {
+        identity::init(&FactConfig::parse_from(["fact"])).unwrap();
+        index_report_to_json(report).unwrap()
+    }
+
+    #[test]
+    fn index_report_round_trip() {
+        let report = report();
+        let json = to_json(&report);
+        assert!(!json.contains('\n'));
+        assert_eq!(index_report_from_json(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn index_report_schema() {
+        let json = to_json(&report());
+        let mut record: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(record["schema"], VM_INDEX_REPORT_SCHEMA);
+        assert_eq!(record["version"], SCHEMA_VERSION);
+
+        record["version"] = (SCHEMA_VERSION + 1).into();
+        let err = index_report_from_json(&record.to_string()).unwrap_err();
+        assert!(err.to_string().contains("unsupported"), "{err}");
+
+        record["version"] = SCHEMA_VERSION.into();
+        record["schema"] = FILE_ACTIVITY_SCHEMA.into();
+        let err = index_report_from_json(&record.to_string()).unwrap_err();
+        assert!(err.to_string().contains(FILE_ACTIVITY_SCHEMA), "{err}");
+
+        assert!(index_report_from_json(r#"{"version": 1}"#).is_err());
+    }
+}
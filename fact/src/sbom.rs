@@ -0,0 +1,477 @@
+//! Software bills of materials of the packages collected by the VM
+//! agent, as SPDX 2.3 or CycloneDX 1.5 JSON documents.
+//!
+//! Packages are identified by their package URL (PURL), derived from the
+//! database they were found in, and their CPE when they have one.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::{create_dir_all, rename, write},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use fact_api::scanner::v4::{Contents, Distribution, Package, Repository};
+use serde_json::{json, Value};
+
+use crate::{collector::WILDCARD_CPE, config::SbomFormat, output::rfc3339};
+
+const TOOL_NAME: &str = "fact";
+const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+impl SbomFormat {
+    /// Name of the file periodic SBOMs are written to.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => "sbom.spdx.json",
+            SbomFormat::Cyclonedx => "sbom.cdx.json",
+        }
+    }
+}
+
+/// Build the SBOM of `contents`, describing the machine called `name`.
+pub fn generate(format: SbomFormat, contents: &Contents, name: &str) -> Value {
+    let sbom = Sbom::new(contents);
+    match format {
+        SbomFormat::Spdx => sbom.spdx(name),
+        SbomFormat::Cyclonedx => sbom.cyclonedx(name),
+    }
+}
+
+/// Write the SBOM to `path`, replacing any previous one at once.
+pub fn save(path: &Path, sbom: &Value) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    write(&tmp, serde_json::to_vec_pretty(sbom)?)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    rename(&tmp, path).with_context(|| format!("Failed to rename {}", tmp.display()))?;
+    Ok(())
+}
+
+struct Sbom<'a> {
+    contents: &'a Contents,
+    distribution: Option<&'a Distribution>,
+    repositories: HashMap<&'a str, &'a Repository>,
+    created: String,
+}
+
+impl<'a> Sbom<'a> {
+    fn new(contents: &'a Contents) -> Self {
+        Sbom {
+            contents,
+            distribution: contents.distributions.first(),
+            repositories: contents
+                .repositories
+                .iter()
+                .map(|repo| (repo.id.as_str(), repo))
+                .collect(),
+            created: rfc3339(SystemTime::now()),
+        }
+    }
+
+    /// Names of the repositories a package was installed from.
+    fn repositories_of(&self, pkg: &Package) -> Vec<&'a str> {
+        let Some(list) = self.contents.environments.get(&pkg.id) else {
+            return Vec::new();
+        };
+        let mut names: Vec<&str> = list
+            .environments
+            .iter()
+            .flat_map(|env| &env.repository_ids)
+            .filter_map(|id| self.repositories.get(id.as_str()))
+            .map(|repo| repo.name.as_str())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn spdx(&self, name: &str) -> Value {
+        let mut packages = Vec::new();
+        let mut relationships = Vec::new();
+
+        let root = match self.distribution {
+            Some(dist) => {
+                let mut os = json!({
+                    "name": dist.did,
+                    "SPDXID": "SPDXRef-OperatingSystem",
+                    "versionInfo": dist.version,
+                    "description": dist.pretty_name,
+                    "downloadLocation": "NOASSERTION",
+                    "filesAnalyzed": false,
+                    "primaryPackagePurpose": "OPERATING-SYSTEM",
+                });
+                if let Some(cpe) = cpe(&dist.cpe) {
+                    os["externalRefs"] = json!([spdx_ref("SECURITY", "cpe23Type", cpe)]);
+                }
+                packages.push(os);
+                relationships.push(json!({
+                    "spdxElementId": "SPDXRef-DOCUMENT",
+                    "relationshipType": "DESCRIBES",
+                    "relatedSpdxElement": "SPDXRef-OperatingSystem",
+                }));
+                "SPDXRef-OperatingSystem"
+            }
+            None => "SPDXRef-DOCUMENT",
+        };
+
+        for (i, pkg) in self.contents.packages.iter().enumerate() {
+            let id = format!("SPDXRef-Package-{i}");
+            let mut refs = Vec::new();
+            if let Some(purl) = purl(pkg, self.distribution) {
+                refs.push(spdx_ref("PACKAGE-MANAGER", "purl", &purl));
+            }
+            if let Some(cpe) = cpe(&pkg.cpe) {
+                refs.push(spdx_ref("SECURITY", "cpe23Type", cpe));
+            }
+            let mut package = json!({
+                "name": pkg.name,
+                "SPDXID": id,
+                "versionInfo": pkg.version,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "externalRefs": refs,
+            });
+            if let Some(source) = &pkg.source {
+                package["sourceInfo"] = json!(format!(
+                    "built from source package {} {}",
+                    source.name, source.version
+                ));
+            }
+            let repos = self.repositories_of(pkg);
+            if !repos.is_empty() {
+                package["comment"] = json!(format!("Installed from {}", repos.join(", ")));
+            }
+            packages.push(package);
+            let relationship = if root == "SPDXRef-DOCUMENT" {
+                "DESCRIBES"
+            } else {
+                "CONTAINS"
+            };
+            relationships.push(json!({
+                "spdxElementId": root,
+                "relationshipType": relationship,
+                "relatedSpdxElement": id,
+            }));
+        }
+
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": name,
+            "documentNamespace": format!("https://stackrox.io/spdx/{}-{}", encode(name), uuid::Uuid::new_v4()),
+            "creationInfo": {
+                "created": self.created,
+                "creators": [format!("Tool: {TOOL_NAME}-{TOOL_VERSION}")],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+
+    fn cyclonedx(&self, name: &str) -> Value {
+        let mut metadata = json!({
+            "timestamp": self.created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": TOOL_NAME,
+                    "version": TOOL_VERSION,
+                }],
+            },
+        });
+        if let Some(dist) = self.distribution {
+            let mut os = json!({
+                "type": "operating-system",
+                "bom-ref": "operating-system",
+                "name": dist.did,
+                "version": dist.version,
+                "description": dist.pretty_name,
+                "properties": [property("fact:hostname", name)],
+            });
+            if let Some(cpe) = cpe(&dist.cpe) {
+                os["cpe"] = json!(cpe);
+            }
+            metadata["component"] = os;
+        }
+
+        let components: Vec<Value> = self
+            .contents
+            .packages
+            .iter()
+            .map(|pkg| {
+                let mut component = json!({
+                    "type": "library",
+                    "bom-ref": pkg.id,
+                    "name": pkg.name,
+                    "version": pkg.version,
+                });
+                if let Some(purl) = purl(pkg, self.distribution) {
+                    component["purl"] = json!(purl);
+                }
+                if let Some(cpe) = cpe(&pkg.cpe) {
+                    component["cpe"] = json!(cpe);
+                }
+                let mut properties = vec![property("fact:package_db", &pkg.package_db)];
+                if let Some(source) = &pkg.source {
+                    let source = format!("{} {}", source.name, source.version);
+                    properties.push(property("fact:source_package", &source));
+                }
+                if !pkg.module.is_empty() {
+                    properties.push(property("fact:module", &pkg.module));
+                }
+                for repo in self.repositories_of(pkg) {
+                    properties.push(property("fact:repository", repo));
+                }
+                component["properties"] = json!(properties);
+                component
+            })
+            .collect();
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "serialNumber": format!("urn:uuid:{}", uuid::Uuid::new_v4()),
+            "version": 1,
+            "metadata": metadata,
+            "components": components,
+        })
+    }
+}
+
+fn spdx_ref(category: &str, kind: &str, locator: &str) -> Value {
+    json!({
+        "referenceCategory": category,
+        "referenceType": kind,
+        "referenceLocator": locator,
+    })
+}
+
+fn property(name: &str, value: &str) -> Value {
+    json!({ "name": name, "value": value })
+}
+
+/// CPEs worth reporting, language packages only have a wildcard one.
+fn cpe(cpe: &str) -> Option<&str> {
+    Some(cpe).filter(|cpe| !cpe.is_empty() && *cpe != WILDCARD_CPE)
+}
+
+/// Package URL of a package, following the PURL types of its ecosystem.
+/// Distribution packages are qualified with the distribution they were
+/// built for.
+fn purl(pkg: &Package, distribution: Option<&Distribution>) -> Option<String> {
+    let ecosystem = pkg.package_db.split_once(':').map(|(db, _)| db);
+    match ecosystem {
+        Some("python") => {
+            // Normalized as per PEP 503
+            let name = pkg.name.to_lowercase().replace(['_', '.'], "-");
+            return Some(format_purl("pypi", "", &name, &pkg.version, &[]));
+        }
+        Some("nodejs") => {
+            let (scope, name) = pkg.name.rsplit_once('/').unwrap_or(("", &pkg.name));
+            return Some(format_purl("npm", scope, name, &pkg.version, &[]));
+        }
+        Some("maven") => {
+            let (group, artifact) = pkg.name.split_once(':')?;
+            return Some(format_purl("maven", group, artifact, &pkg.version, &[]));
+        }
+        Some("go") => {
+            let (path, name) = pkg.name.rsplit_once('/').unwrap_or(("", &pkg.name));
+            return Some(format_purl("golang", path, name, &pkg.version, &[]));
+        }
+        _ => {}
+    }
+
+    let dist = distribution?;
+    let distro = format!("{}-{}", dist.did, dist.version);
+    if pkg.package_db.starts_with("var/lib/dpkg") {
+        let qualifiers = [("arch", pkg.arch.as_str()), ("distro", &distro)];
+        Some(format_purl(
+            "deb",
+            &dist.did,
+            &pkg.name,
+            &pkg.version,
+            &qualifiers,
+        ))
+    } else if pkg.package_db.starts_with("lib/apk") {
+        let qualifiers = [("arch", pkg.arch.as_str()), ("distro", &distro)];
+        Some(format_purl(
+            "apk",
+            &dist.did,
+            &pkg.name,
+            &pkg.version,
+            &qualifiers,
+        ))
+    } else {
+        // The epoch is a qualifier of rpm PURLs, not part of the version
+        let (epoch, version) = pkg.version.split_once(':').unwrap_or(("", &pkg.version));
+        let namespace = match dist.did.as_str() {
+            "rhel" => "redhat",
+            did => did,
+        };
+        let qualifiers = [
+            ("arch", pkg.arch.as_str()),
+            ("distro", &distro),
+            ("epoch", epoch),
+        ];
+        Some(format_purl(
+            "rpm",
+            namespace,
+            &pkg.name,
+            version,
+            &qualifiers,
+        ))
+    }
+}
+
+/// `pkg:type/namespace/name@version?qualifiers`, the qualifiers being
+/// sorted by key already and left out when empty.
+fn format_purl(
+    kind: &str,
+    namespace: &str,
+    name: &str,
+    version: &str,
+    qualifiers: &[(&str, &str)],
+) -> String {
+    let mut purl = format!("pkg:{kind}/");
+    for segment in namespace.split('/').filter(|s| !s.is_empty()) {
+        purl.push_str(&encode(segment));
+        purl.push('/');
+    }
+    purl.push_str(&encode(name));
+    if !version.is_empty() {
+        purl.push('@');
+        purl.push_str(&encode(version));
+    }
+    let qualifiers: Vec<String> = qualifiers
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{key}={}", encode(value)))
+        .collect();
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    purl
+}
+
+/// Percent-encode everything but unreserved characters.
+fn encode(s: &str) -> String {
+    s.bytes().fold(String::new(), |mut encoded, b| {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+        encoded
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, arch: &str, package_db: &str) -> Package {
+        Package {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.to_string(),
+            package_db: package_db.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn distribution(did: &str, version: &str) -> Distribution {
+        Distribution {
+            did: did.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(encode("abc-1.2_3~4"), "abc-1.2_3~4");
+        assert_eq!(encode("1:2.3+dfsg"), "1%3A2.3%2Bdfsg");
+        assert_eq!(encode("a b/c@d?e#f"), "a%20b%2Fc%40d%3Fe%23f");
+        assert_eq!(encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn language_purls() {
+        let purl = |name, version, db| purl(&package(name, version, "", db), None);
+        assert_eq!(
+            purl(
+                "Flask_SQLAlchemy",
+                "3.1.1",
+                "python:usr/lib/python3.12/site-packages"
+            )
+            .unwrap(),
+            "pkg:pypi/flask-sqlalchemy@3.1.1"
+        );
+        assert_eq!(
+            purl("@angular/core", "17.0.0", "nodejs:srv/app/node_modules").unwrap(),
+            "pkg:npm/%40angular/core@17.0.0"
+        );
+        assert_eq!(
+            purl(
+                "org.apache.logging.log4j:log4j-core",
+                "2.17.1",
+                "maven:opt/app.jar"
+            )
+            .unwrap(),
+            "pkg:maven/org.apache.logging.log4j/log4j-core@2.17.1"
+        );
+        assert_eq!(
+            purl(
+                "github.com/docker/docker",
+                "v24.0.7+incompatible",
+                "go:usr/bin/app"
+            )
+            .unwrap(),
+            "pkg:golang/github.com/docker/docker@v24.0.7%2Bincompatible"
+        );
+        assert_eq!(purl("no-group", "1.0", "maven:opt/app.jar"), None);
+    }
+
+    #[test]
+    fn distribution_purls() {
+        let rhel = distribution("rhel", "9");
+        let pkg = package("bash", "1:5.1.8-9.el9", "x86_64", "sqlite:var/lib/rpm");
+        assert_eq!(
+            purl(&pkg, Some(&rhel)).unwrap(),
+            "pkg:rpm/redhat/bash@5.1.8-9.el9?arch=x86_64&distro=rhel-9&epoch=1"
+        );
+        let pkg = package("bash", "5.1.8-9.el9", "x86_64", "sqlite:var/lib/rpm");
+        assert_eq!(
+            purl(&pkg, Some(&rhel)).unwrap(),
+            "pkg:rpm/redhat/bash@5.1.8-9.el9?arch=x86_64&distro=rhel-9"
+        );
+
+        let debian = distribution("debian", "12");
+        let pkg = package(
+            "libssl3",
+            "3.0.11-1~deb12u2",
+            "amd64",
+            "var/lib/dpkg/status",
+        );
+        assert_eq!(
+            purl(&pkg, Some(&debian)).unwrap(),
+            "pkg:deb/debian/libssl3@3.0.11-1~deb12u2?arch=amd64&distro=debian-12"
+        );
+
+        let alpine = distribution("alpine", "v3.19");
+        let pkg = package("libcrypto3", "3.1.4-r5", "x86_64", "lib/apk/db/installed");
+        assert_eq!(
+            purl(&pkg, Some(&alpine)).unwrap(),
+            "pkg:apk/alpine/libcrypto3@3.1.4-r5?arch=x86_64&distro=alpine-v3.19"
+        );
+
+        assert_eq!(purl(&pkg, None), None);
+    }
+}
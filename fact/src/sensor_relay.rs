@@ -1,60 +1,78 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::{Context, Result};
 use log::{debug, info, warn};
-use tokio::sync::mpsc;
-use tonic::{
-    metadata::MetadataValue,
-    service::{interceptor::InterceptedService, Interceptor},
-    transport::{Channel, ClientTlsConfig},
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Instant},
 };
-use std::str::FromStr;
+use tonic::{service::interceptor::InterceptedService, transport::Channel};
 
 use crate::{
-    certs::Certs,
+    config::{OutputFormat, SignaturePolicy},
+    frame::MessageType,
+    health,
+    identity::MetadataInterceptor,
+    metrics, output,
+    signing::{Verification, Verifier},
+    transport::Transport,
     vsock::VmMessage,
 };
 
 use fact_api::{
     sensor::{
         virtual_machine_index_report_service_client::VirtualMachineIndexReportServiceClient,
-        UpsertVirtualMachineIndexReportRequest,
+        virtual_machine_service_client::VirtualMachineServiceClient,
+        UpsertVirtualMachineIndexReportRequest, UpsertVirtualMachineRequest,
     },
+    storage::VirtualMachine,
     virtualmachine::v1::IndexReport,
 };
 
-#[derive(Debug, Clone)]
-struct UserAgentInterceptor {}
+const COMPONENT: &str = "vm-relay";
 
-impl Interceptor for UserAgentInterceptor {
-    fn call(
-        &mut self,
-        mut request: tonic::Request<()>,
-    ) -> Result<tonic::Request<()>, tonic::Status> {
-        request.metadata_mut().insert(
-            "user-agent",
-            MetadataValue::from_str("StackRox Fact VM Relay").unwrap(),
-        );
-        Ok(request)
-    }
-}
+/// Delay before reconnecting to sensor, doubled after every failed
+/// attempt up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 /// Relay for forwarding VM data to sensor
 pub struct SensorRelay {
     endpoint: String,
-    certs: Option<Certs>,
-    client: Option<VirtualMachineIndexReportServiceClient<InterceptedService<Channel, UserAgentInterceptor>>>,
+    transport: Transport,
+    output: OutputFormat,
+    verifier: Verifier,
+    client: Option<VirtualMachineIndexReportServiceClient<InterceptedService<Channel, MetadataInterceptor>>>,
+    vm_client: Option<VirtualMachineServiceClient<InterceptedService<Channel, MetadataInterceptor>>>,
+    /// Last VirtualMachine sent for each CID, along with which the notes
+    /// of its reports are sent
+    vms: HashMap<String, VirtualMachine>,
 }
 
 impl SensorRelay {
     /// Create a new sensor relay
-    pub fn new(endpoint: String, certs: Option<Certs>) -> Self {
+    pub fn new(
+        endpoint: String,
+        transport: Transport,
+        output: OutputFormat,
+        verifier: Verifier,
+    ) -> Self {
         SensorRelay {
             endpoint,
-            certs,
+            transport,
+            output,
+            verifier,
             client: None,
+            vm_client: None,
+            vms: HashMap::new(),
         }
     }
     
-    /// Start the sensor relay service
+    /// Start the sensor relay service. Failing to reach sensor does not
+    /// stop it, messages are left in `vm_rx` until it is connected again.
     pub async fn start(
         &mut self,
         mut vm_rx: mpsc::Receiver<VmMessage>,
@@ -62,8 +80,8 @@ impl SensorRelay {
     ) -> Result<()> {
         info!("Starting sensor relay to {}", self.endpoint);
         
-        // Connect to sensor
-        self.connect().await?;
+        let mut reconnect_at = Some(Instant::now());
+        let mut delay = RECONNECT_DELAY;
         
         // Main relay loop
         loop {
@@ -72,15 +90,42 @@ impl SensorRelay {
                     info!("Sensor relay shutting down");
                     break;
                 }
-                msg = vm_rx.recv() => {
+                _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                    match self.connect().await {
+                        Ok(()) => {
+                            reconnect_at = None;
+                            delay = RECONNECT_DELAY;
+                        }
+                        Err(e) => {
+                            warn!("{e:#}, retrying in {delay:?}");
+                            health::set_not_ready(health::SENSOR_RELAY, format!("{e:#}"));
+                            reconnect_at = Some(Instant::now() + delay);
+                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
+                }
+                msg = vm_rx.recv(), if reconnect_at.is_none() => {
                     match msg {
                         Some(vm_msg) => {
-                            if let Err(e) = self.forward_vm_message(vm_msg).await {
+                            let result = match vm_msg.kind {
+                                MessageType::Facts => self.forward_facts(vm_msg).await,
+                                _ => self.forward_vm_message(vm_msg).await,
+                            };
+                            let label = match result {
+                                Ok(true) => "success",
+                                Ok(false) => "rejected",
+                                Err(_) => "failure",
+                            };
+                            metrics::RELAYED_MESSAGES_TOTAL
+                                .with_label_values(&[label])
+                                .inc();
+                            if let Err(e) = result {
                                 warn!("Failed to forward VM message: {}", e);
-                                // Try to reconnect on error
-                                if let Err(e) = self.connect().await {
-                                    warn!("Failed to reconnect to sensor: {}", e);
-                                }
+                                health::set_not_ready(health::SENSOR_RELAY, format!("{e:#}"));
+                                // Reconnect on error
+                                self.client = None;
+                                self.vm_client = None;
+                                reconnect_at = Some(Instant::now());
                             }
                         }
                         None => {
@@ -99,40 +144,59 @@ impl SensorRelay {
     async fn connect(&mut self) -> Result<()> {
         info!("Connecting to sensor at {}", self.endpoint);
         
-        let mut channel = Channel::from_shared(self.endpoint.clone())?;
-        
-        // Configure TLS if certificates are provided
-        if let Some(certs) = &self.certs {
-            let tls = ClientTlsConfig::new()
-                .domain_name("sensor.stackrox.svc")
-                .ca_certificate(certs.ca.clone())
-                .identity(certs.identity.clone());
-            channel = channel.tls_config(tls)?;
-        }
-        
-        let channel = channel.connect().await
+        let channel = self.transport.connect(&self.endpoint).await
             .context("Failed to connect to sensor")?;
         
         let client = VirtualMachineIndexReportServiceClient::with_interceptor(
-            channel,
-            UserAgentInterceptor {},
+            channel.clone(),
+            MetadataInterceptor::new(COMPONENT),
         );
         
         self.client = Some(client);
+        self.vm_client = Some(VirtualMachineServiceClient::with_interceptor(
+            channel,
+            MetadataInterceptor::new(COMPONENT),
+        ));
+        health::set_ready(health::SENSOR_RELAY);
         info!("Connected to sensor successfully");
         Ok(())
     }
     
-    /// Forward a VM message to the sensor
-    async fn forward_vm_message(&mut self, vm_msg: VmMessage) -> Result<()> {
+    /// Forward a VM message to the sensor, unless its report is rejected
+    /// because it is invalid or because of its signature. Errors are
+    /// failures to reach sensor.
+    async fn forward_vm_message(&mut self, vm_msg: VmMessage) -> Result<bool> {
         debug!("Forwarding VM message from {}: {} bytes", vm_msg.vm_id, vm_msg.data.len());
         
-        let client = self.client.as_mut()
-            .context("Sensor client not connected")?;
-        
         // Deserialize the IndexReport data from protobuf
-        let index_report = prost::Message::decode(vm_msg.data.as_slice())
-            .context("Failed to decode IndexReport protobuf data")?;
+        let mut index_report: IndexReport = match prost::Message::decode(vm_msg.data.as_slice()) {
+            Ok(index_report) => index_report,
+            Err(e) => {
+                warn!("Rejecting the report of {}, failed to decode it: {e}", vm_msg.vm_id);
+                return Ok(false);
+            }
+        };
+
+        // The CID of the connection is attested by the hypervisor, unlike
+        // the one in the report
+        if let Some(cid) = vm_msg.cid.map(|cid| cid.to_string()) {
+            if index_report.vsock_cid != cid {
+                warn!(
+                    "{} reported '{}' as its CID, using the CID of its connection",
+                    vm_msg.vm_id, index_report.vsock_cid
+                );
+                index_report.vsock_cid = cid;
+            }
+        }
+
+        let Some(verification) = self.verify(&vm_msg, &index_report.vsock_cid) else {
+            return Ok(false);
+        };
+
+        // Relayed reports are only echoed when machine readable output is requested
+        if self.output == OutputFormat::Json {
+            output::print_index_report(&index_report, self.output, "relay");
+        }
         
         // Create the upsert request
         let request = UpsertVirtualMachineIndexReportRequest {
@@ -140,38 +204,121 @@ impl SensorRelay {
         };
         
         // Send to sensor
-        client.upsert_virtual_machine_index_report(request).await
-            .context("Failed to send IndexReport to sensor")?;
+        let client = self.client.as_mut()
+            .context("Sensor client not connected")?;
+        let timer = metrics::SENSOR_SEND_DURATION
+            .with_label_values(&["relay"])
+            .start_timer();
+        let cid = request.index_report.as_ref().map(|report| report.vsock_cid.clone());
+        let request = self.transport.request(request);
+        let res = client.upsert_virtual_machine_index_report(request).await;
+        timer.observe_duration();
+        if res.is_err() {
+            metrics::SENSOR_SEND_FAILURES_TOTAL
+                .with_label_values(&["relay"])
+                .inc();
+        }
+        res.context("Failed to send IndexReport to sensor")?;
+
+        // Sent even for verified reports, clearing the notes of previous ones
+        if let Err(e) = self.send_notes(cid.unwrap_or_default(), verification).await {
+            warn!("Failed to send the notes of {}: {e:#}", vm_msg.vm_id);
+        }
         
         debug!("Successfully forwarded VM message from {}", vm_msg.vm_id);
-        Ok(())
+        Ok(true)
     }
     
-    /// Send a test IndexReport message (for development/testing)
-    pub async fn send_test_vm(&mut self, vm_id: String) -> Result<()> {
-        let client = self.client.as_mut()
-            .context("Sensor client not connected")?;
-        
-        let index_report = IndexReport {
-            vsock_cid: vm_id.clone(),
-            index_v4: Some(fact_api::scanner::v4::IndexReport {
-                success: true,
-                contents: Some(fact_api::scanner::v4::Contents {
-                    packages: vec![], // Empty packages for test
-                    ..Default::default()
-                }),
-                ..Default::default()
-            }),
+    /// Forward the facts of a VM to the sensor, unless they are rejected
+    /// because they are invalid or because of their signature. Errors are
+    /// failures to reach sensor.
+    async fn forward_facts(&mut self, vm_msg: VmMessage) -> Result<bool> {
+        debug!("Forwarding the facts of {}: {} bytes", vm_msg.vm_id, vm_msg.data.len());
+
+        let mut virtual_machine: VirtualMachine = match prost::Message::decode(vm_msg.data.as_slice()) {
+            Ok(virtual_machine) => virtual_machine,
+            Err(e) => {
+                warn!("Rejecting the facts of {}, failed to decode them: {e}", vm_msg.vm_id);
+                return Ok(false);
+            }
         };
-        
-        let request = UpsertVirtualMachineIndexReportRequest {
-            index_report: Some(index_report),
+
+        // Same as for reports, the CID of the connection is the one to trust
+        if let Some(cid) = vm_msg.cid.map(|cid| cid.to_string()) {
+            match virtual_machine.facts.insert("vsock_cid".to_string(), cid.clone()) {
+                Some(reported) if reported != cid => warn!(
+                    "{} reported '{reported}' as its CID, using the CID of its connection",
+                    vm_msg.vm_id
+                ),
+                _ => {}
+            }
+        }
+        let cid = virtual_machine.facts.get("vsock_cid").cloned().unwrap_or_default();
+
+        let Some(verification) = self.verify(&vm_msg, &cid) else {
+            return Ok(false);
         };
-        
-        client.upsert_virtual_machine_index_report(request).await
-            .context("Failed to send test IndexReport to sensor")?;
-        
-        info!("Sent test IndexReport message for {}", vm_id);
+        virtual_machine.notes = notes(verification);
+        self.vms.insert(cid, virtual_machine.clone());
+        self.send_vm(virtual_machine).await?;
+
+        debug!("Successfully forwarded the facts of {}", vm_msg.vm_id);
+        Ok(true)
+    }
+
+    /// Verify the signature of a message, none if it is rejected because
+    /// of it.
+    fn verify(&mut self, vm_msg: &VmMessage, cid: &str) -> Option<Verification> {
+        let verification = self.verifier.verify(cid, &vm_msg.data);
+        metrics::VM_REPORT_SIGNATURES_TOTAL
+            .with_label_values(&[verification.as_str()])
+            .inc();
+        if verification != Verification::Verified {
+            if self.verifier.policy == SignaturePolicy::Reject {
+                warn!(
+                    "Rejecting {:?} from {}, its signature is {}",
+                    vm_msg.kind,
+                    vm_msg.vm_id,
+                    verification.as_str()
+                );
+                return None;
+            }
+            debug!(
+                "Signature of {:?} from {}: {}",
+                vm_msg.kind,
+                vm_msg.vm_id,
+                verification.as_str()
+            );
+        }
+        Some(verification)
+    }
+
+    /// Let sensor know whether the last report of a VM was signed, with the
+    /// notes of the VM, which is identified by its CID.
+    async fn send_notes(&mut self, cid: String, verification: Verification) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let virtual_machine = self.vms.entry(cid.clone()).or_insert_with(|| VirtualMachine {
+            facts: [("vsock_cid".to_string(), cid)].into(),
+            ..Default::default()
+        });
+        virtual_machine.notes = notes(verification);
+        virtual_machine.last_updated = Some(prost_types::Timestamp {
+            seconds: now.as_secs() as i64,
+            nanos: now.subsec_nanos() as i32,
+        });
+        let virtual_machine = virtual_machine.clone();
+        self.send_vm(virtual_machine).await
+    }
+
+    async fn send_vm(&mut self, virtual_machine: VirtualMachine) -> Result<()> {
+        let client = self.vm_client.as_mut()
+            .context("Sensor client not connected")?;
+
+        let request = self.transport.request(UpsertVirtualMachineRequest {
+            virtual_machine: Some(virtual_machine),
+        });
+        client.upsert_virtual_machine(request).await
+            .context("Failed to send the VM to sensor")?;
         Ok(())
     }
 }
@@ -180,4 +327,8 @@ impl Drop for SensorRelay {
     fn drop(&mut self) {
         info!("Sensor relay to {} shutting down", self.endpoint);
     }
-}
\ No newline at end of file
+}
+
+fn notes(verification: Verification) -> Vec<i32> {
+    verification.note().into_iter().map(|note| note as i32).collect()
+}
@@ -0,0 +1,448 @@
+//! Signatures of index reports. The VM agent signs every report with its
+//! Ed25519 key, and the VSOCK listener verifies them against the key
+//! known for each VM before relaying them.
+//!
+//! VMs are identified by their SMBIOS product UUID, which is stable across
+//! restarts, unlike their CID that KubeVirt hands out again once a VM is
+//! gone. Only VMs whose agent cannot read their UUID are keyed by CID.
+//! The UUID is reported by the VM itself though, so the CID a key was
+//! registered from is pinned to the UUID of the VM, and a VM on that CID
+//! cannot register a key for another UUID, until the pinned VM shows up
+//! on another CID.
+//!
+//! Over VSOCK, the signature is appended to the serialized report, or
+//! facts, as protobuf fields neither `IndexReport` nor `VirtualMachine`
+//! use, so listeners that do not verify signatures still decode them. The
+//! facts are only signed over VSOCK. Over gRPC, it is sent as
+//! the `x-fact-signature-bin` and `x-fact-public-key-bin` metadata.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{create_dir_all, read, read_to_string, rename, write, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use fact_api::{sensor::UpsertVirtualMachineIndexReportRequest, storage::virtual_machine::Note};
+use log::{info, warn};
+use prost::Message;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use tonic::metadata::MetadataValue;
+
+use crate::{
+    config::{SignaturePolicy, SigningConfig, VmKeyPolicy},
+    facts, host_info,
+};
+
+const SIGNING_KEY_FILE: &str = "vm-signing-key.pk8";
+const VM_KEYS_FILE: &str = "vm-keys.json";
+const VM_CIDS_FILE: &str = "vm-cids.json";
+
+/// Signature appended to a serialized index report.
+#[derive(Clone, PartialEq, Message)]
+struct ReportSignature {
+    /// Ed25519 signature of the serialized report
+    #[prost(bytes = "vec", tag = "1001")]
+    signature: Vec<u8>,
+    #[prost(bytes = "vec", tag = "1002")]
+    public_key: Vec<u8>,
+    /// SMBIOS product UUID of the VM
+    #[prost(string, tag = "1003")]
+    vm_uuid: String,
+}
+
+pub struct Signer {
+    key_pair: Ed25519KeyPair,
+    vm_uuid: Option<String>,
+}
+
+impl Signer {
+    /// Load the signing key of the VM, `--signing-key` or the one in the
+    /// state directory, which is generated on first run.
+    pub fn load(cfg: &SigningConfig, state_dir: &Path) -> anyhow::Result<Self> {
+        let (path, generate) = match &cfg.signing_key {
+            Some(path) => (path.clone(), false),
+            None => (state_dir.join(SIGNING_KEY_FILE), true),
+        };
+        match read(&path) {
+            // Also accepts keys without their public key, as written by
+            // openssl genpkey -algorithm ed25519 -outform DER
+            Ok(pkcs8) => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+                .map_err(|e| anyhow!("Invalid signing key {}: {e}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound && generate => Self::generate(&path),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+        .map(|key_pair| {
+            let vm_uuid = facts::product_uuid(host_info::get_host_mount());
+            if vm_uuid.is_none() {
+                info!("Unable to read the product UUID of the VM, its key is trusted by CID");
+            }
+            Signer { key_pair, vm_uuid }
+        })
+    }
+
+    fn generate(path: &Path) -> anyhow::Result<Ed25519KeyPair> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate a signing key"))?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(pkcs8.as_ref()))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|e| anyhow!("Invalid generated signing key: {e}"))?;
+        info!(
+            "Generated the signing key {}, with public key {}",
+            path.display(),
+            hex(key_pair.public_key().as_ref())
+        );
+        Ok(key_pair)
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(data).as_ref().to_vec()
+    }
+
+    /// Serialize a report, or the facts of the VM, with its signature, to
+    /// be sent over VSOCK.
+    pub fn seal(&self, message: &impl Message) -> Vec<u8> {
+        let mut data = message.encode_to_vec();
+        let signature = ReportSignature {
+            signature: self.sign(&data),
+            public_key: self.key_pair.public_key().as_ref().to_vec(),
+            vm_uuid: self.vm_uuid.clone().unwrap_or_default(),
+        };
+        data.extend(signature.encode_to_vec());
+        data
+    }
+
+    /// Attach the signature of the report of a gRPC request to it.
+    pub fn annotate(&self, request: &mut tonic::Request<UpsertVirtualMachineIndexReportRequest>) {
+        let Some(report) = &request.get_ref().index_report else {
+            return;
+        };
+        let signature = self.sign(&report.encode_to_vec());
+        let metadata = request.metadata_mut();
+        metadata.insert_bin(
+            "x-fact-signature-bin",
+            MetadataValue::from_bytes(&signature),
+        );
+        metadata.insert_bin(
+            "x-fact-public-key-bin",
+            MetadataValue::from_bytes(self.key_pair.public_key().as_ref()),
+        );
+    }
+}
+
+/// Outcome of the verification of a relayed report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Verified,
+    /// The report is not signed
+    Missing,
+    /// No key is known for the VM, and new keys are not trusted
+    UnknownKey,
+    /// The signature does not match the report or the key of the VM
+    Invalid,
+}
+
+impl Verification {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verification::Verified => "verified",
+            Verification::Missing => "missing",
+            Verification::UnknownKey => "unknown-key",
+            Verification::Invalid => "invalid",
+        }
+    }
+
+    /// Note on the VM of a report relayed with this outcome. Forged
+    /// signatures are not reported as missing ones, but as signatures that
+    /// cannot be verified.
+    pub fn note(&self) -> Option<Note> {
+        match self {
+            Verification::Verified => None,
+            Verification::Missing => Some(Note::MissingSignature),
+            Verification::UnknownKey | Verification::Invalid => {
+                Some(Note::MissingSignatureVerificationData)
+            }
+        }
+    }
+}
+
+/// Public keys of the VMs, by product UUID or VSOCK CID, kept in the state
+/// directory.
+pub struct Verifier {
+    path: PathBuf,
+    keys: BTreeMap<String, String>,
+    cids_path: PathBuf,
+    /// Product UUID, or CID, of the VM each CID was last verified from
+    cids: BTreeMap<String, String>,
+    key_policy: VmKeyPolicy,
+    pub policy: SignaturePolicy,
+}
+
+impl Verifier {
+    pub fn load(cfg: &SigningConfig, state_dir: &Path) -> Self {
+        let path = cfg
+            .vm_keys
+            .clone()
+            .unwrap_or_else(|| state_dir.join(VM_KEYS_FILE));
+        let keys = load(&path);
+        info!(
+            "Verifying report signatures against {} known VM keys",
+            keys.len()
+        );
+        let cids_path = state_dir.join(VM_CIDS_FILE);
+        Verifier {
+            cids: load(&cids_path),
+            cids_path,
+            path,
+            keys,
+            key_policy: cfg.vm_key_policy,
+            policy: cfg.signature_policy,
+        }
+    }
+
+    /// Verify the signature of a message received from the VM with the
+    /// CID `cid`, as sent by [`Signer::seal`].
+    pub fn verify(&mut self, cid: &str, data: &[u8]) -> Verification {
+        let Ok(signature) = ReportSignature::decode(data) else {
+            return Verification::Invalid;
+        };
+        if signature.signature.is_empty() {
+            return Verification::Missing;
+        }
+        // The signed report is everything before the signature
+        let trailer = signature.encode_to_vec();
+        let Some(signed) = data.strip_suffix(trailer.as_slice()) else {
+            return Verification::Invalid;
+        };
+
+        let public_key = hex(&signature.public_key);
+        let vm = match signature.vm_uuid.as_str() {
+            "" => cid,
+            uuid => uuid,
+        };
+        let known = self.keys.get(vm);
+        match known {
+            Some(key) if *key != public_key => {
+                warn!("VM {vm} signed its message with an unknown key {public_key}");
+                return Verification::Invalid;
+            }
+            None if self.key_policy == VmKeyPolicy::KnownOnly => return Verification::UnknownKey,
+            None => {
+                // Keys of other VMs are only registered once the VM pinned
+                // to the CID has moved on
+                if let Some(pinned) = self.cids.get(cid).filter(|pinned| {
+                    pinned.as_str() != vm && self.keys.contains_key(pinned.as_str())
+                }) {
+                    warn!("VM {vm} presented a new key from CID {cid}, which is pinned to VM {pinned}");
+                    return Verification::Invalid;
+                }
+            }
+            _ => {}
+        }
+        if UnparsedPublicKey::new(&ED25519, &signature.public_key)
+            .verify(signed, &signature.signature)
+            .is_err()
+        {
+            return Verification::Invalid;
+        }
+        if known.is_none() {
+            info!("Registering the key {public_key} of VM {vm}");
+            self.keys.insert(vm.to_string(), public_key);
+            if let Err(e) = save(&self.path, &self.keys) {
+                warn!("Failed to save the VM keys: {e:#}");
+            }
+        }
+        self.pin(cid, vm);
+        Verification::Verified
+    }
+
+    /// Pin the CID a VM was verified from to it, releasing the CID it was
+    /// on before.
+    fn pin(&mut self, cid: &str, vm: &str) {
+        if self.cids.get(cid).is_some_and(|pinned| pinned == vm) {
+            return;
+        }
+        self.cids.retain(|_, pinned| pinned != vm);
+        self.cids.insert(cid.to_string(), vm.to_string());
+        if let Err(e) = save(&self.cids_path, &self.cids) {
+            warn!("Failed to save the CIDs of the VMs: {e:#}");
+        }
+    }
+}
+
+fn load(path: &Path) -> BTreeMap<String, String> {
+    match read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .inspect_err(|e| warn!("Ignoring invalid {}: {e}", path.display()))
+            .unwrap_or_default(),
+        Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!("Failed to read {}: {e}", path.display());
+            BTreeMap::new()
+        }
+    }
+}
+
+fn save(path: &Path, map: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    write(&tmp, serde_json::to_vec_pretty(map)?)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    rename(&tmp, path).with_context(|| format!("Failed to rename {}", tmp.display()))?;
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use fact_api::storage::VirtualMachine;
+
+    use super::*;
+
+    fn signer(vm_uuid: &str) -> Signer {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Signer {
+            key_pair: Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap(),
+            vm_uuid: Some(vm_uuid.to_string()).filter(|uuid| !uuid.is_empty()),
+        }
+    }
+
+    fn verifier(name: &str, key_policy: VmKeyPolicy) -> Verifier {
+        let dir = std::env::temp_dir().join(format!("fact-signing-{name}-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        Verifier {
+            path: dir.join(VM_KEYS_FILE),
+            keys: BTreeMap::new(),
+            cids_path: dir.join(VM_CIDS_FILE),
+            cids: BTreeMap::new(),
+            key_policy,
+            policy: SignaturePolicy::Annotate,
+        }
+    }
+
+    fn message() -> VirtualMachine {
+        VirtualMachine {
+            facts: [("hostname".to_string(), "vm".to_string())].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut verifier = verifier("round-trip", VmKeyPolicy::TrustOnFirstUse);
+        let signer = signer("uuid-a");
+        let data = signer.seal(&message());
+        assert_eq!(verifier.verify("3", &data), Verification::Verified);
+        assert_eq!(verifier.verify("3", &data), Verification::Verified);
+        assert_eq!(VirtualMachine::decode(data.as_slice()).unwrap(), message());
+
+        // Registered keys outlive the listener
+        let reloaded = load(&verifier.path);
+        assert_eq!(reloaded, verifier.keys);
+        assert!(reloaded.contains_key("uuid-a"));
+    }
+
+    #[test]
+    fn unsigned() {
+        let mut verifier = verifier("unsigned", VmKeyPolicy::TrustOnFirstUse);
+        let data = message().encode_to_vec();
+        assert_eq!(verifier.verify("3", &data), Verification::Missing);
+        assert_eq!(Verification::Missing.note(), Some(Note::MissingSignature));
+    }
+
+    #[test]
+    fn tampered() {
+        let mut verifier = verifier("tampered", VmKeyPolicy::TrustOnFirstUse);
+        let mut data = signer("uuid-a").seal(&message());
+        let hostname = data.windows(2).position(|w| w == b"vm").unwrap();
+        data[hostname] = b'w';
+        assert_eq!(verifier.verify("3", &data), Verification::Invalid);
+        assert!(verifier.keys.is_empty());
+        assert_eq!(
+            Verification::Invalid.note(),
+            Some(Note::MissingSignatureVerificationData)
+        );
+    }
+
+    #[test]
+    fn key_change() {
+        let mut verifier = verifier("key-change", VmKeyPolicy::TrustOnFirstUse);
+        let data = signer("uuid-a").seal(&message());
+        assert_eq!(verifier.verify("3", &data), Verification::Verified);
+        let data = signer("uuid-a").seal(&message());
+        assert_eq!(verifier.verify("3", &data), Verification::Invalid);
+        assert_eq!(verifier.verify("4", &data), Verification::Invalid);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let mut verifier = verifier("unknown-key", VmKeyPolicy::KnownOnly);
+        let signer = signer("uuid-a");
+        let data = signer.seal(&message());
+        assert_eq!(verifier.verify("3", &data), Verification::UnknownKey);
+        assert!(verifier.keys.is_empty());
+
+        verifier.keys.insert(
+            "uuid-a".to_string(),
+            hex(signer.key_pair.public_key().as_ref()),
+        );
+        assert_eq!(verifier.verify("3", &data), Verification::Verified);
+    }
+
+    #[test]
+    fn pinned_cid() {
+        let mut verifier = verifier("pinned-cid", VmKeyPolicy::TrustOnFirstUse);
+        let (a, b) = (signer("uuid-a"), signer("uuid-b"));
+        assert_eq!(
+            verifier.verify("3", &a.seal(&message())),
+            Verification::Verified
+        );
+
+        // Another VM, or no VM at all, cannot be claimed from the CID of A
+        assert_eq!(
+            verifier.verify("3", &b.seal(&message())),
+            Verification::Invalid
+        );
+        assert_eq!(
+            verifier.verify("3", &signer("").seal(&message())),
+            Verification::Invalid
+        );
+        assert!(!verifier.keys.contains_key("uuid-b"));
+
+        // Once A is on another CID, its former one is released
+        assert_eq!(
+            verifier.verify("4", &a.seal(&message())),
+            Verification::Verified
+        );
+        assert_eq!(
+            verifier.verify("3", &b.seal(&message())),
+            Verification::Verified
+        );
+        assert_eq!(load(&verifier.cids_path), verifier.cids);
+    }
+}
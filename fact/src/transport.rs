@@ -0,0 +1,119 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Uri};
+use tower::service_fn;
+
+use crate::{
+    certs::{CertPaths, CertStore},
+    config::FactConfig,
+};
+
+/// Connection settings shared by every gRPC client talking to sensor.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    certs: Option<CertStore>,
+    server_name: String,
+    connect_timeout: Duration,
+    request_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
+}
+
+fn secs(secs: u64) -> Option<Duration> {
+    (secs != 0).then(|| Duration::from_secs(secs))
+}
+
+impl TryFrom<&FactConfig> for Transport {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &FactConfig) -> Result<Self, Self::Error> {
+        let grpc = &config.grpc;
+        let certs = match CertPaths::from_config(config)? {
+            Some(_) if grpc.insecure_plaintext => {
+                warn!("Certificates are configured, but plaintext was requested, not using TLS");
+                None
+            }
+            Some(paths) => Some(CertStore::new(paths)?),
+            None => {
+                debug!("No certificates configured, connecting to sensor without TLS");
+                None
+            }
+        };
+
+        Ok(Transport {
+            certs,
+            server_name: grpc.tls_server_name.clone(),
+            connect_timeout: Duration::from_secs(grpc.connect_timeout),
+            request_timeout: secs(grpc.request_timeout),
+            keepalive_interval: secs(grpc.keepalive_interval),
+            keepalive_timeout: Duration::from_secs(grpc.keepalive_timeout),
+        })
+    }
+}
+
+impl Transport {
+    /// Apply the connection settings to an endpoint.
+    fn configure(&self, mut endpoint: Endpoint) -> anyhow::Result<Endpoint> {
+        endpoint = endpoint.connect_timeout(self.connect_timeout);
+
+        if let Some(interval) = self.keepalive_interval {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_timeout(self.keepalive_timeout)
+                .keep_alive_while_idle(true);
+        }
+
+        if let Some(certs) = self.certs.as_ref().map(CertStore::current) {
+            let tls = ClientTlsConfig::new()
+                .domain_name(self.server_name.clone())
+                .ca_certificate(certs.ca.clone())
+                .identity(certs.identity.clone());
+            endpoint = endpoint.tls_config(tls)?;
+        }
+
+        Ok(endpoint)
+    }
+
+    /// Connect to sensor, `url` being either an HTTP(S) URL or the path
+    /// to a Unix domain socket, e.g. `unix:///run/sensor/grpc.sock`.
+    pub async fn connect(&self, url: &str) -> anyhow::Result<Channel> {
+        let channel = match url.strip_prefix("unix://").or_else(|| url.strip_prefix("unix:")) {
+            Some(path) => self.connect_unix(path.into()).await,
+            None => {
+                let endpoint = Channel::from_shared(url.to_owned())
+                    .with_context(|| format!("Invalid sensor URL '{url}'"))?;
+                Ok(self.configure(endpoint)?.connect().await?)
+            }
+        };
+        channel.with_context(|| format!("Failed to connect to {url}"))
+    }
+
+    async fn connect_unix(&self, path: PathBuf) -> anyhow::Result<Channel> {
+        // tonic refuses TLS on its own Unix socket endpoints, so connect
+        // the socket ourselves and let an endpoint named after the server
+        // carry the TLS settings.
+        let scheme = if self.certs.is_some() { "https" } else { "http" };
+        let endpoint = Endpoint::from_shared(format!("{scheme}://{}", self.server_name))?;
+        let channel = self
+            .configure(endpoint)?
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let path = path.clone();
+                async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+            }))
+            .await?;
+        Ok(channel)
+    }
+
+    /// Wrap a message for a unary call, applying the request timeout.
+    pub fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(timeout) = self.request_timeout {
+            request.set_timeout(timeout);
+        }
+        request
+    }
+}
@@ -1,37 +1,59 @@
-use std::{env, fs::read_to_string, path::PathBuf, process::Command, str::FromStr, sync::LazyLock, collections::HashMap};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::{read, read_to_string, write},
+    hash::{BuildHasher, Hasher, RandomState},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex, Once, PoisonError,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Context;
-use crate::certs::Certs;
-use crate::config::FactConfig;
+use anyhow::{anyhow, bail, Context};
+use crate::config::{FactConfig, OutputFormat, SbomArgs, SbomConfig, ScanConfig};
 use fact_api::{
     sensor::{
         virtual_machine_index_report_service_client::VirtualMachineIndexReportServiceClient,
-        UpsertVirtualMachineIndexReportRequest,
+        virtual_machine_service_client::VirtualMachineServiceClient,
+        UpsertVirtualMachineIndexReportRequest, UpsertVirtualMachineRequest,
     },
+    storage::{virtual_machine_scan::Note, VirtualMachine, VirtualMachineScan},
     virtualmachine::v1::IndexReport,
-    scanner::v4::{Contents, Package, Distribution, Repository, Environment, environment},
+    scanner::v4::{Contents, Distribution, Environment, Package, environment},
 };
 use tokio::{
     sync::mpsc,
-    time::{interval, Duration},
+    time::{sleep, timeout, timeout_at, Duration, Instant},
     select,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
+use nix::sys::inotify::{AddWatchFlags, InotifyEvent};
 use prost::Message;
-use tonic::{
-    metadata::MetadataValue,
-    service::{interceptor::InterceptedService, Interceptor},
-    transport::{Channel, ClientTlsConfig},
+use ring::digest;
+use tonic::{service::interceptor::InterceptedService, transport::Channel};
+use crate::{
+    budget::{self, ScanBudget, Usage},
+    collector::{self, Collector},
+    delivery::Delivery,
+    facts::{self, Facts},
+    health, host_info,
+    identity::{self, MetadataInterceptor},
+    metrics, output, sbom,
+    signing::Signer,
+    transport::Transport,
+    vsock::{self, VsockClient},
+    watch::Watcher,
 };
-use crate::vsock::VsockClient;
 
-static HOST_MOUNT: LazyLock<PathBuf> =
-    LazyLock::new(|| env::var("FACT_HOST_MOUNT").unwrap_or_default().into());
+const COMPONENT: &str = "vm-agent";
 
 static HOSTNAME: LazyLock<String> = LazyLock::new(|| {
-    let hostname_paths = ["/etc/hostname", "/proc/sys/kernel/hostname"];
+    let hostname_paths = ["etc/hostname", "proc/sys/kernel/hostname"];
     for p in hostname_paths {
-        let p = HOST_MOUNT.join(p);
+        let p = host_info::get_host_mount().join(p);
         if p.exists() {
             return read_to_string(p).unwrap().trim().to_string();
         }
@@ -39,13 +61,25 @@ static HOSTNAME: LazyLock<String> = LazyLock::new(|| {
     "no-hostname".to_string()
 });
 
+/// CID of the VM, identifying it to the VSOCK listener and sensor
+static VSOCK_CID: LazyLock<Option<u32>> = LazyLock::new(|| match vsock::local_cid() {
+    Ok(cid) => {
+        info!("Identified by VSOCK CID {cid}");
+        Some(cid)
+    }
+    Err(e) => {
+        warn!("Identified by hostname, the VSOCK CID is unknown: {e:#}");
+        None
+    }
+});
+
 static OS_RELEASE: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
     parse_os_release()
 });
 
 static SYSTEM_CPE: LazyLock<String> = LazyLock::new(|| {
     if let Some(cpe_22) = OS_RELEASE.get("CPE_NAME") {
-        convert_cpe_22_to_23(cpe_22)
+        collector::cpe_from_uri(cpe_22)
     } else {
         String::new()
     }
@@ -56,7 +90,7 @@ static DISTRIBUTION: LazyLock<Distribution> = LazyLock::new(|| {
 });
 
 fn parse_os_release() -> HashMap<String, String> {
-    let os_release_path = HOST_MOUNT.join("/etc/os-release");
+    let os_release_path = host_info::get_host_mount().join("etc/os-release");
     let mut fields = HashMap::new();
     
     if os_release_path.exists() {
@@ -73,28 +107,6 @@ fn parse_os_release() -> HashMap<String, String> {
     fields
 }
 
-fn convert_cpe_22_to_23(cpe_22: &str) -> String {
-    // Convert CPE 2.2 format (cpe:/part:vendor:product:version) to CPE 2.3 format
-    // CPE 2.3: cpe:2.3:part:vendor:product:version:update:edition:language:sw_edition:target_sw:target_hw:other
-    
-    if !cpe_22.starts_with("cpe:/") {
-        return cpe_22.to_string(); // Return as-is if not CPE 2.2 format
-    }
-    
-    let parts: Vec<&str> = cpe_22.trim_start_matches("cpe:/").split(':').collect();
-    if parts.len() < 4 {
-        return cpe_22.to_string(); // Return as-is if malformed
-    }
-    
-    let part = parts[0]; // o, a, h (operating system, application, hardware)
-    let vendor = parts[1];
-    let product = parts[2];
-    let version = parts[3];
-    
-    // CPE 2.3 format with wildcard (*) for unspecified fields
-    format!("cpe:2.3:{}:{}:{}:{}:*:*:*:*:*:*:*", part, vendor, product, version)
-}
-
 fn create_distribution_from_os_release(fields: &HashMap<String, String>) -> Distribution {
     
     // Get system architecture
@@ -109,12 +121,17 @@ fn create_distribution_from_os_release(fields: &HashMap<String, String>) -> Dist
     let id = fields.get("ID").cloned().unwrap_or_else(|| "unknown".to_string());
     let version = fields.get("VERSION_ID").cloned().unwrap_or_else(|| "unknown".to_string());
     
-    // Extract major version only (e.g., "43.1" -> "43", "9.6" -> "9")
-    let major_version = version.split('.').next().unwrap_or(&version).to_string();
+    // Extract major version only (e.g., "43.1" -> "43", "9.6" -> "9"),
+    // except for Ubuntu where the full version is the release (e.g. "22.04")
+    let major_version = if id == "ubuntu" {
+        version.clone()
+    } else {
+        version.split('.').next().unwrap_or(&version).to_string()
+    };
     
     // Convert CPE 2.2 to CPE 2.3 format
     let cpe_23 = if let Some(cpe_22) = fields.get("CPE_NAME") {
-        convert_cpe_22_to_23(cpe_22)
+        collector::cpe_from_uri(cpe_22)
     } else {
         String::new()
     };
@@ -132,220 +149,512 @@ fn create_distribution_from_os_release(fields: &HashMap<String, String>) -> Dist
     }
 }
 
-#[derive(Debug, Clone)]
-struct UserAgentInterceptor {}
-
-impl Interceptor for UserAgentInterceptor {
-    fn call(
-        &mut self,
-        mut request: tonic::Request<()>,
-    ) -> Result<tonic::Request<()>, tonic::Status> {
-        request.metadata_mut().insert(
-            "user-agent",
-            MetadataValue::from_str("Rox Admission Controller").unwrap(),
-        );
-        Ok(request)
+/// Scanner V4 expects the digest of the layer that introduced each
+/// package. A VM has no layers, so packages are introduced by the set
+/// of packages found by their collector, which changes with any update.
+fn introduced_in(pkgs: &[Package]) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    hash_sorted(&mut ctx, pkgs, |pkg| &pkg.id);
+    sha256_id(ctx)
+}
+
+/// Digest of the index report of a scan, which does not depend on the
+/// order packages were found in. Whether the scan succeeded and its notes
+/// are part of it, so a complete scan is sent after a partial one finding
+/// the same packages.
+fn report_digest(scan: &Scan) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[(scan.failed == 0) as u8]);
+    let mut notes: Vec<i32> = scan.notes.iter().map(|note| *note as i32).collect();
+    notes.sort_unstable();
+    ctx.update(&(notes.len() as u64).to_be_bytes());
+    for note in notes {
+        ctx.update(&note.to_be_bytes());
+    }
+    let contents = &scan.contents;
+    hash_sorted(&mut ctx, &contents.packages, |pkg| &pkg.id);
+    hash_sorted(&mut ctx, &contents.distributions, |dist| &dist.id);
+    hash_sorted(&mut ctx, &contents.repositories, |repo| &repo.id);
+    let mut environments: Vec<_> = contents.environments.iter().collect();
+    environments.sort_by_key(|(id, _)| *id);
+    for (id, envs) in environments {
+        ctx.update(&id.encode_length_delimited_to_vec());
+        ctx.update(&envs.encode_length_delimited_to_vec());
     }
+    sha256_id(ctx)
 }
 
-struct VmAgent {
+fn hash_sorted<T: Message>(ctx: &mut digest::Context, items: &[T], key: impl Fn(&T) -> &String) {
+    let mut items: Vec<&T> = items.iter().collect();
+    items.sort_by_key(|item| key(item));
+    // Every item is length prefixed, so items never run into each other
+    ctx.update(&(items.len() as u64).to_be_bytes());
+    for item in items {
+        ctx.update(&item.encode_length_delimited_to_vec());
+    }
+}
+
+fn sha256_id(ctx: digest::Context) -> String {
+    let hex = ctx.finish().as_ref().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    format!("sha256:{hex}")
+}
+
+/// Index report of a scan, failing only when every collector failed.
+fn index_report(hash_id: String, scan: Scan) -> IndexReport {
+    let index_v4 = fact_api::scanner::v4::IndexReport {
+        hash_id,
+        // Partial scans are not successful, sensor would otherwise take
+        // the missing packages as removed
+        success: scan.failed == 0,
+        err: scan.errors.join("; "),
+        contents: Some(scan.contents),
+        ..Default::default()
+    };
+
+    IndexReport {
+        vsock_cid: VSOCK_CID.map_or_else(|| HOSTNAME.to_string(), |cid| cid.to_string()),
+        index_v4: Some(index_v4),
+    }
+}
+
+/// Packages found by a scan, and what is missing from them.
+struct Scan {
+    contents: Contents,
+    /// Notes on the data missing because of the collectors that failed
+    notes: Vec<Note>,
+    /// Errors of the collectors that failed
+    errors: Vec<String>,
+    collectors: usize,
+    failed: usize,
+}
+
+/// Consecutive failed scans after which the agent is not ready, a single
+/// failure being often transient, e.g. the package manager holding a
+/// lock on its database.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Where index reports are sent to, and how.
+struct Sender {
     url: Option<String>,
-    cmd: Command,
-    certs: Option<Certs>,
-    user_agent: UserAgentInterceptor,
     use_vsock: bool,
+    transport: Transport,
+    output: OutputFormat,
+    signer: Option<Signer>,
+}
+
+/// Time a scan has to notice it is over budget once its timeout passed,
+/// before it is given up on.
+const SCAN_GRACE: Duration = Duration::from_secs(30);
+
+/// Collects the packages of the machine, within the limits of scans.
+struct Scanner {
+    /// Locked by the scan in progress
+    collectors: Arc<Mutex<Vec<Box<dyn Collector>>>>,
+    /// Package databases of the collectors
+    watched_paths: Vec<PathBuf>,
+    limits: ScanConfig,
+    /// Set on shutdown, to abandon the scan in progress
+    cancelled: Arc<AtomicBool>,
+}
+
+struct VmAgent {
+    scanner: Scanner,
+    sender: Sender,
+    scan_ttl: Duration,
+    delivery: Delivery,
+    sbom: SbomConfig,
+    /// Digest of the contents the SBOMs were last written for
+    sbom_digest: Option<String>,
+    /// Facts and scan notes last sent to sensor
+    facts_sent: Option<(Facts, Vec<Note>)>,
+    /// Scans that failed or were partial in a row
+    failures: u32,
 }
 
 impl VmAgent {
-    async fn run(&mut self) -> anyhow::Result<()> {
-        info!("Collecting package information...");
-        let pkgs = self.cmd.output().context("Failed to run rpm command")?;
-        let stdout =
-            std::str::from_utf8(pkgs.stdout.as_slice()).context("Failed to parse rpm output")?;
-
-        info!("Parsing...");
-        let pkgs = stdout
-            .lines()
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.split('|').collect();
-                if parts.len() >= 4 {
-                    let version = format!("{}-{}", parts[1], parts[2]); // Combine version and release
-                    let package_name = parts[0];
-                    let arch = parts[3];
-                    
-                    // Create source package info (required by Scanner V4)
-                    let source_package = Package {
-                        name: package_name.to_string(),
-                        version: version.clone(),
-                        kind: "source".to_string(),
-                        cpe: "cpe:2.3:*:*:*:*:*:*:*:*:*:*:*".to_string(),
-                        ..Default::default()
-                    };
-                    
-                    Some(Package {
-                        id: format!("{}-{}", package_name, version),
-                        name: package_name.to_string(),
-                        version: version.clone(),
-                        kind: "binary".to_string(),  // Required: specify this is a binary package
-                        source: Some(Box::new(source_package)),  // Required: source package info
-                        package_db: "sqlite:usr/share/rpm".to_string(),  // Required: RPM database identifier
-                        repository_hint: format!("rpm:{}:{}", arch, version),  // Required: repository hint
-                        arch: arch.to_string(),
-                        cpe: "cpe:2.3:*:*:*:*:*:*:*:*:*:*:*".to_string(),  // Required: package CPE format (not OS CPE)
-                        ..Default::default()
-                    })
-                } else {
-                    None
+    /// Run a scan, recording its outcome in the agent health. Failures
+    /// only make the agent not ready once they keep happening.
+    async fn scan(&mut self) {
+        let res = self.run().await;
+        if self.scanner.cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+        match res {
+            Ok(()) => {
+                self.failures = 0;
+                health::set_ready_for(health::VM_SCAN, self.scan_ttl);
+            }
+            Err(e) => {
+                self.failures += 1;
+                warn!("Scan failed ({} in a row): {e:#}", self.failures);
+                if self.failures >= MAX_CONSECUTIVE_FAILURES {
+                    let message = format!("{} scans failed in a row, last: {e:#}", self.failures);
+                    health::set_not_ready(health::VM_SCAN, message);
                 }
-            })
-            .collect::<Vec<_>>();
-        debug!("{pkgs:?}");
+            }
+        }
+        metrics::VM_SCAN_CONSECUTIVE_FAILURES.set(self.failures.into());
+    }
+
+    /// Collect the packages and report them, along with the facts of the
+    /// VM. Partial scans are reported too, but still count as failures.
+    async fn run(&mut self) -> anyhow::Result<()> {
+        // Only scans that completed are timed
+        let timer = metrics::VM_SCAN_DURATION.start_timer();
+        let scan = match self.scanner.collect().await {
+            Ok(scan) => scan,
+            Err(e) => {
+                timer.stop_and_discard();
+                metrics::VM_SCANS_TOTAL.with_label_values(&["failure"]).inc();
+                return Err(e);
+            }
+        };
+        if self.scanner.cancelled.load(Ordering::Relaxed) {
+            timer.stop_and_discard();
+            bail!("scan cancelled");
+        }
+        timer.observe_duration();
+        let result = match scan.failed {
+            0 => "success",
+            failed if failed < scan.collectors => "partial",
+            _ => "failure",
+        };
+        metrics::VM_SCANS_TOTAL.with_label_values(&[result]).inc();
+        if scan.failed == 0 {
+            metrics::VM_PACKAGES.set(scan.contents.packages.len() as i64);
+        }
+
+        let digest = report_digest(&scan);
+        // SBOMs cannot tell packages are missing, partial ones are not
+        // written
+        if scan.failed == 0 {
+            self.write_sboms(&scan.contents, &digest);
+        }
+        let errors = (scan.failed > 0).then(|| scan.errors.join("; "));
+        let notes = scan.notes.clone();
+        let due = self.delivery.is_due(&digest);
+        let sent = if due {
+            self.send_report(digest, scan).await
+        } else {
+            info!("Packages unchanged since the last report ({digest}), skipping");
+            metrics::VM_REPORTS_SKIPPED_TOTAL.inc();
+            Ok(())
+        };
+        self.send_facts(due && sent.is_ok(), notes).await;
+        sent?;
+        match errors {
+            Some(errors) => bail!("partial scan, {errors}"),
+            None => Ok(()),
+        }
+    }
 
+    async fn send_report(&mut self, digest: String, scan: Scan) -> anyhow::Result<()> {
         info!("Sending updates...");
 
-        if self.use_vsock {
-            self.send_vsock(pkgs).await?;
-        } else if let Some(url) = &self.url {
-            self.send_grpc(url.to_string(), pkgs).await?;
+        let report = index_report(digest.clone(), scan);
+        let start = Instant::now();
+        let res = if self.sender.use_vsock {
+            self.sender.send_vsock(report).await
+        } else if let Some(url) = &self.sender.url {
+            self.sender.send_grpc(url.to_string(), report).await
+        } else {
+            return Ok(());
+        };
+        metrics::SENSOR_SEND_DURATION
+            .with_label_values(&["vm_agent"])
+            .observe(start.elapsed().as_secs_f64());
+        match &res {
+            Ok(()) => self.delivery.record(&digest),
+            Err(_) => metrics::SENSOR_SEND_FAILURES_TOTAL
+                .with_label_values(&["vm_agent"])
+                .inc(),
         }
-        Ok(())
+        res
     }
 
-    async fn create_client(
-        &self,
-        url: String,
-    ) -> anyhow::Result<
-        VirtualMachineIndexReportServiceClient<InterceptedService<Channel, UserAgentInterceptor>>,
-    > {
-        let mut channel = Channel::from_shared(url)?;
+    /// Send the facts of the VM and the notes of the last scan along with
+    /// the index report, or whenever they changed. Sensors without the
+    /// VirtualMachine service still get the index report, failures are
+    /// only logged.
+    async fn send_facts(&mut self, report_sent: bool, notes: Vec<Note>) {
+        if self.sender.url.is_none() && !self.sender.use_vsock {
+            return;
+        }
+        let sent = (facts::gather(&self.scanner.watched_paths), notes);
+        if !report_sent && self.facts_sent.as_ref() == Some(&sent) {
+            return;
+        }
+        let (facts, notes) = &sent;
+        match self.sender.send_vm(facts.clone(), notes).await {
+            Ok(()) => self.facts_sent = Some(sent),
+            Err(e) => {
+                warn!("Failed to send the facts of the VM: {e:#}");
+                metrics::SENSOR_SEND_FAILURES_TOTAL
+                    .with_label_values(&["vm_agent"])
+                    .inc();
+            }
+        }
+    }
 
-        if let Some(certs) = &self.certs {
-            let tls = ClientTlsConfig::new()
-                .domain_name("sensor.stackrox.svc")
-                .ca_certificate(certs.ca.clone())
-                .identity(certs.identity.clone());
-            channel = channel.tls_config(tls)?;
+    /// Write the SBOMs of the VM to the SBOM directory, if configured, when
+    /// the packages changed. Failing to write them does not fail the scan.
+    fn write_sboms(&mut self, contents: &Contents, digest: &str) {
+        let Some(dir) = &self.sbom.dir else {
+            return;
+        };
+        if self.sbom_digest.as_deref() == Some(digest) {
+            return;
+        }
+        let mut written = true;
+        for format in &self.sbom.formats {
+            let path = dir.join(format.file_name());
+            let document = sbom::generate(*format, contents, &HOSTNAME);
+            match sbom::save(&path, &document) {
+                Ok(()) => debug!("Wrote {}", path.display()),
+                Err(e) => {
+                    warn!("Failed to write the SBOM: {e:#}");
+                    written = false;
+                }
+            }
+        }
+        if written {
+            self.sbom_digest = Some(digest.to_string());
         }
+    }
+}
 
-        let channel = channel.connect().await?;
-        let client =
-            VirtualMachineIndexReportServiceClient::with_interceptor(channel, self.user_agent.clone());
-        Ok(client)
+impl Scanner {
+    fn new(cfg: &FactConfig) -> Self {
+        let collector = collector::select(cfg, &OS_RELEASE, &SYSTEM_CPE);
+        info!("Using the {} package collector", collector.name());
+        let mut collectors = vec![collector];
+        if cfg.language.enabled {
+            collectors.push(Box::new(collector::Language::new(&cfg.language)));
+        }
+        let watched_paths = collectors
+            .iter()
+            .flat_map(|collector| collector.watched_paths())
+            .collect();
+        Scanner {
+            collectors: Arc::new(Mutex::new(collectors)),
+            watched_paths,
+            limits: cfg.scan.clone(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    async fn send_grpc(&self, url: String, pkgs: Vec<Package>) -> anyhow::Result<()> {
-        let mut client = self.create_client(url).await?;
+    /// Collect the packages from every collector, in a thread of their
+    /// own running with the priority and within the limits of scans,
+    /// without holding up the runtime meanwhile.
+    async fn collect(&self) -> anyhow::Result<Scan> {
+        let collectors = self.collectors.clone();
+        let limits = self.limits.clone();
+        let cancelled = self.cancelled.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            // Waits for a previous scan that outlived its timeout
+            let mut collectors = collectors.lock().unwrap_or_else(PoisonError::into_inner);
+            let collectors: &mut Vec<_> = &mut collectors;
+            let budget = ScanBudget::new(&limits, cancelled);
+            // Threads of the blocking pool are reused, the priority is
+            // lowered in a thread of the scan's own
+            thread::scope(|s| {
+                s.spawn(|| {
+                    budget::lower_priority(&limits);
+                    let usage = Usage::now();
+                    let scan = collect_contents(collectors, &budget);
+                    usage.log_since();
+                    scan
+                })
+                .join()
+            })
+        });
+        let res = match self.limits.timeout {
+            0 => task.await,
+            secs => timeout(Duration::from_secs(secs) + SCAN_GRACE, task)
+                .await
+                .map_err(|_| anyhow!("Package scan did not stop after {secs}s"))?,
+        };
+        match res {
+            Ok(Ok(scan)) => Ok(scan),
+            _ => bail!("Package scan panicked"),
+        }
+    }
+}
 
-        // Create repository information (required by Scanner V4)
-        let repository = Repository {
-            id: "0".to_string(),
-            name: format!("cpe:/{}", SYSTEM_CPE.clone()),
-            key: "rhel-cpe-repository".to_string(),
-            cpe: SYSTEM_CPE.clone(),
-            ..Default::default()
+/// Collect the packages from every collector, along with the
+/// distribution and the repositories they come from. A failing collector
+/// only leaves its packages out of the scan.
+fn collect_contents(collectors: &mut [Box<dyn Collector>], budget: &ScanBudget) -> Scan {
+    let mut packages = Vec::new();
+    let mut repositories = Vec::new();
+    let mut environments = HashMap::new();
+    let mut notes = Vec::new();
+    let mut errors = Vec::new();
+
+    for collector in collectors.iter_mut() {
+        info!("Collecting {} package information...", collector.name());
+        let pkgs = match collector.collect(budget) {
+            Ok(pkgs) => pkgs,
+            Err(e) => {
+                warn!("Failed to collect {} packages: {e:#}", collector.name());
+                errors.push(format!("{}: {e:#}", collector.name()));
+                notes.push(collector.failure_note());
+                continue;
+            }
         };
 
         // Create environment mapping (required by Scanner V4)
-        let mut environments = std::collections::HashMap::new();
-        for (idx, pkg) in pkgs.iter().enumerate() {
+        let introduced_in = introduced_in(&pkgs);
+        for pkg in &pkgs {
             let env = Environment {
-                package_db: "sqlite:usr/share/rpm".to_string(),
-                introduced_in: "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_string(),
-                repository_ids: vec!["0".to_string()],
+                package_db: pkg.package_db.clone(),
+                introduced_in: introduced_in.clone(),
+                repository_ids: collector.repository_ids(pkg),
                 ..Default::default()
             };
-            environments.insert(idx.to_string(), environment::List {
-                environments: vec![env],
-            });
+            environments.insert(pkg.id.clone(), environment::List { environments: vec![env] });
         }
+        packages.extend(pkgs);
+        repositories.extend(collector.repositories());
+    }
+
+    if !errors.is_empty() {
+        notes.push(Note::PartialScanData);
+    }
 
-        let contents = Contents {
-            packages: pkgs,
-            distributions: vec![DISTRIBUTION.clone()],
-            repositories: vec![repository],
+    // The first collector is the one of the distribution
+    let distribution = collectors[0].distribution(&DISTRIBUTION);
+    Scan {
+        contents: Contents {
+            packages,
+            distributions: vec![distribution],
+            repositories,
             environments,
             ..Default::default()
-        };
+        },
+        notes,
+        failed: errors.len(),
+        errors,
+        collectors: collectors.len(),
+    }
+}
 
-        let index_v4 = fact_api::scanner::v4::IndexReport {
-            hash_id: format!("/v4/vm/{}", HOSTNAME.as_str()),
-            success: true,
-            contents: Some(contents),
-            ..Default::default()
-        };
+impl Sender {
+    /// Send a report to sensor, over VSOCK or gRPC.
+    async fn send(&self, index_report: IndexReport) -> anyhow::Result<()> {
+        if self.use_vsock {
+            self.send_vsock(index_report).await
+        } else if let Some(url) = &self.url {
+            self.send_grpc(url.to_string(), index_report).await
+        } else {
+            bail!("Nowhere to send the report to, use --url or --use-vsock");
+        }
+    }
 
-        let index_report = IndexReport {
-            vsock_cid: HOSTNAME.to_string(), // Use hostname as identifier for now
-            index_v4: Some(index_v4),
-        };
+    async fn create_client(
+        &self,
+        url: String,
+    ) -> anyhow::Result<
+        VirtualMachineIndexReportServiceClient<InterceptedService<Channel, MetadataInterceptor>>,
+    > {
+        let channel = self.transport.connect(&url).await?;
+        let client = VirtualMachineIndexReportServiceClient::with_interceptor(
+            channel,
+            MetadataInterceptor::new(COMPONENT),
+        );
+        Ok(client)
+    }
+
+    async fn send_grpc(&self, url: String, index_report: IndexReport) -> anyhow::Result<()> {
+        let mut client = self.create_client(url).await?;
 
-        println!("Full IndexReport content (gRPC): {:#?}", index_report);
+        output::print_index_report(&index_report, self.output, "gRPC");
 
-        let request = UpsertVirtualMachineIndexReportRequest {
+        let mut request = self.transport.request(UpsertVirtualMachineIndexReportRequest {
             index_report: Some(index_report),
-        };
+        });
+        if let Some(signer) = &self.signer {
+            signer.annotate(&mut request);
+        }
 
         client.upsert_virtual_machine_index_report(request).await?;
         Ok(())
     }
 
-    async fn send_vsock(&self, pkgs: Vec<Package>) -> anyhow::Result<()> {
-        if !VsockClient::is_available() {
-            return Err(anyhow::anyhow!("VSOCK is not available on this system"));
-        }
-
-        let mut client = VsockClient::connect()
-            .context("Failed to connect to VSOCK endpoint")?;
-
-        // Create repository information (required by Scanner V4)
-        let repository = Repository {
-            id: "0".to_string(),
-            name: format!("cpe:/{}", SYSTEM_CPE.clone()),
-            key: "rhel-cpe-repository".to_string(),
-            cpe: SYSTEM_CPE.clone(),
+    /// Send the facts of the VM, over VSOCK or gRPC like the index report.
+    async fn send_vm(&self, facts: Facts, notes: &[Note]) -> anyhow::Result<()> {
+        let identity = identity::get();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let virtual_machine = VirtualMachine {
+            name: identity.node_name().to_string(),
+            cluster_id: identity.cluster_id().unwrap_or_default().to_string(),
+            facts: facts.into_iter().collect(),
+            // Sent even without notes, clearing those of a previous scan
+            scan: Some(VirtualMachineScan {
+                notes: notes.iter().map(|note| *note as i32).collect(),
+                ..Default::default()
+            }),
+            last_updated: Some(prost_types::Timestamp {
+                seconds: now.as_secs() as i64,
+                nanos: now.subsec_nanos() as i32,
+            }),
             ..Default::default()
         };
+        debug!("Sending the facts of the VM: {:?}", virtual_machine.facts);
 
-        // Create environment mapping (required by Scanner V4)
-        let mut environments = std::collections::HashMap::new();
-        for (idx, pkg) in pkgs.iter().enumerate() {
-            let env = Environment {
-                package_db: "sqlite:usr/share/rpm".to_string(),
-                introduced_in: "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_string(),
-                repository_ids: vec!["0".to_string()],
-                ..Default::default()
+        if self.use_vsock {
+            let data = match &self.signer {
+                Some(signer) => signer.seal(&virtual_machine),
+                None => virtual_machine.encode_to_vec(),
             };
-            environments.insert(idx.to_string(), environment::List {
-                environments: vec![env],
-            });
+            let mut client = VsockClient::connect()
+                .await
+                .context("Failed to connect to VSOCK endpoint")?;
+            if !client.send_facts(&data).await? {
+                static UNSUPPORTED: Once = Once::new();
+                UNSUPPORTED.call_once(|| {
+                    warn!("The VSOCK listener does not relay the facts of VMs, only index reports")
+                });
+            }
+            return Ok(());
         }
-
-        let contents = Contents {
-            packages: pkgs,
-            distributions: vec![DISTRIBUTION.clone()],
-            repositories: vec![repository],
-            environments,
-            ..Default::default()
+        let Some(url) = &self.url else {
+            return Ok(());
         };
+        let channel = self.transport.connect(url).await?;
+        let mut client =
+            VirtualMachineServiceClient::with_interceptor(channel, MetadataInterceptor::new(COMPONENT));
+        let request = self.transport.request(UpsertVirtualMachineRequest {
+            virtual_machine: Some(virtual_machine),
+        });
+        client.upsert_virtual_machine(request).await?;
+        Ok(())
+    }
 
-        let index_v4 = fact_api::scanner::v4::IndexReport {
-            success: true,
-            contents: Some(contents),
-            ..Default::default()
-        };
+    async fn send_vsock(&self, index_report: IndexReport) -> anyhow::Result<()> {
+        if !VsockClient::is_available() {
+            return Err(anyhow::anyhow!("VSOCK is not available on this system"));
+        }
 
-        let index_report = IndexReport {
-            vsock_cid: HOSTNAME.to_string(),
-            index_v4: Some(index_v4),
-        };
+        let mut client = VsockClient::connect()
+            .await
+            .context("Failed to connect to VSOCK endpoint")?;
 
-        println!("Full IndexReport content (VSOCK): {:#?}", index_report);
+        output::print_index_report(&index_report, self.output, "VSOCK");
 
-        // Serialize the IndexReport to protobuf bytes
-        let data = index_report.encode_to_vec();
+        // Serialize the IndexReport to protobuf bytes, followed by its
+        // signature
+        let data = match &self.signer {
+            Some(signer) => signer.seal(&index_report),
+            None => index_report.encode_to_vec(),
+        };
 
         // Send the protobuf data
-        client.send_data(&data)
+        client.send_index_report(&data)
+            .await
             .context("Failed to send VM data via VSOCK")?;
 
         info!("Successfully sent {} packages via VSOCK", index_report.index_v4.as_ref().and_then(|i| i.contents.as_ref()).map(|c| c.packages.len()).unwrap_or(0));
@@ -353,42 +662,140 @@ impl VmAgent {
     }
 }
 
-impl TryFrom<&FactConfig> for VmAgent {
+impl TryFrom<&FactConfig> for Sender {
     type Error = anyhow::Error;
 
     fn try_from(cfg: &FactConfig) -> Result<Self, Self::Error> {
-        let url = if !cfg.skip_http { cfg.url.clone() } else { None };
-        let mut cmd = Command::new("rpm");
-        cmd.args([
-            "--dbpath",
-            &cfg.rpmdb,
-            "-qa",
-            "--qf",
-            "%{NAME}|%{VERSION}|%{RELEASE}|%{ARCH}\n",
-        ]);
-        let certs = if let Some(path) = &cfg.certs {
-            Some(path.clone().try_into()?)
-        } else {
-            None
+        let signer = match Signer::load(&cfg.signing, &cfg.state_dir) {
+            Ok(signer) => Some(signer),
+            Err(e) if cfg.signing.signing_key.is_some() => return Err(e),
+            Err(e) => {
+                warn!("Sending unsigned index reports: {e:#}");
+                None
+            }
         };
+        Ok(Sender {
+            url: if !cfg.skip_http { cfg.url.clone() } else { None },
+            use_vsock: cfg.use_vsock,
+            transport: Transport::try_from(cfg)?,
+            output: cfg.output,
+            signer,
+        })
+    }
+}
+
+impl TryFrom<&FactConfig> for VmAgent {
+    type Error = anyhow::Error;
 
+    fn try_from(cfg: &FactConfig) -> Result<Self, Self::Error> {
         Ok(VmAgent {
-            url,
-            cmd,
-            certs,
-            user_agent: UserAgentInterceptor {},
-            use_vsock: cfg.use_vsock,
+            scanner: Scanner::new(cfg),
+            sender: Sender::try_from(cfg)?,
+            // Stale once a scheduled scan has been missed, with some slack
+            scan_ttl: Duration::from_secs(cfg.interval.saturating_mul(2) + 60),
+            delivery: Delivery::load(&cfg.state_dir, Duration::from_secs(cfg.resend_interval)),
+            sbom: cfg.sbom.clone(),
+            sbom_digest: None,
+            facts_sent: None,
+            failures: 0,
+        })
+    }
+}
+
+/// Time to wait for a burst of package database changes to end, at
+/// most, so an endless stream of changes still triggers scans.
+const MAX_RESCAN_WAIT: Duration = Duration::from_secs(300);
+
+/// Watch the package databases, notifying `changes` once they have not
+/// changed for `delay`. Package managers write their database several
+/// times per transaction.
+async fn watch_packages(
+    paths: Vec<PathBuf>,
+    delay: Duration,
+    changes: mpsc::Sender<()>,
+) -> anyhow::Result<()> {
+    let watcher = Watcher::new()?;
+    // Databases are replaced rather than written to, the directories
+    // holding them are watched instead. Modifications are watched rather
+    // than files closed after being opened for writing, which rpm does
+    // even for queries when run as root.
+    let flags = AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_TO;
+    let mut names = Vec::new();
+    let mut dirs = Vec::new();
+    for path in &paths {
+        // Any change in a directory of databases counts
+        if path.is_dir() {
+            dirs.push(watcher.add(path, flags)?);
+            continue;
+        }
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        if !dir.is_dir() {
+            debug!("Not watching {}, it does not exist", dir.display());
+            continue;
+        }
+        watcher.add(dir, flags)?;
+        names.push(name.to_string_lossy().into_owned());
+    }
+    if names.is_empty() && dirs.is_empty() {
+        bail!("no package database to watch");
+    }
+    info!("Watching {paths:?} for package changes");
+
+    let is_change = |events: &[InotifyEvent]| {
+        events.iter().any(|event| {
+            dirs.contains(&event.wd)
+                || event
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| is_database(&name.to_string_lossy(), &names))
         })
+    };
+
+    loop {
+        if !is_change(&watcher.next().await?) {
+            continue;
+        }
+        let deadline = Instant::now() + MAX_RESCAN_WAIT;
+        while let Ok(events) = timeout_at(deadline.min(Instant::now() + delay), watcher.next()).await {
+            events?;
+        }
+        debug!("Package database changed");
+        // A scan already pending covers this change too
+        let _ = changes.try_send(());
     }
 }
 
+/// Whether `name` is one of the `watched` databases, or the journal or
+/// write-ahead log of one. Lock files and the shared memory of SQLite
+/// databases (`-shm`), which readers write to as well, are not.
+fn is_database(name: &str, watched: &[String]) -> bool {
+    watched.iter().any(|db| {
+        name.strip_prefix(db.as_str())
+            .is_some_and(|suffix| matches!(suffix, "" | "-journal" | "-wal"))
+    })
+}
+
+/// Time until the next scheduled scan, with up to 10% of random jitter
+/// so VMs started together do not all scan and report at once.
+fn next_scan(interval: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = interval.as_millis() as u64 / 10;
+    interval + Duration::from_millis(random.checked_rem(jitter).unwrap_or(0))
+}
+
 pub async fn run_vm_agent(config: &FactConfig) -> anyhow::Result<()> {
     let (tx, mut rx) = mpsc::channel::<()>(1);
-    let mut ticks = interval(Duration::from_secs(config.interval));
+    let interval = Duration::from_secs(config.interval);
     let mut vm_agent: VmAgent = config.try_into()?;
+    health::register(health::VM_SCAN);
 
     // Check VSOCK availability if requested
-    if vm_agent.use_vsock {
+    if vm_agent.sender.use_vsock {
         if !VsockClient::is_available() {
             return Err(anyhow::anyhow!(
                 "VSOCK support requested but not available on this system. \
@@ -396,24 +803,42 @@ pub async fn run_vm_agent(config: &FactConfig) -> anyhow::Result<()> {
             ));
         }
         info!("Using VSOCK communication mode");
-    } else if vm_agent.url.is_some() {
+    } else if vm_agent.sender.url.is_some() {
         info!("Using gRPC communication mode");
     } else {
         info!("No communication method configured");
     }
 
+    let cancelled = vm_agent.scanner.cancelled.clone();
     ctrlc::set_handler(move || {
+        cancelled.store(true, Ordering::Relaxed);
         let _ = tx.try_send(());
     })
     .context("Failed setting signal handler")?;
 
-    // Run once before going into the loop
-    vm_agent.run().await?;
+    let (changes_tx, mut changes) = mpsc::channel::<()>(1);
+    if config.rescan_delay > 0 {
+        let paths = vm_agent.scanner.watched_paths.clone();
+        let delay = Duration::from_secs(config.rescan_delay);
+        tokio::spawn(async move {
+            if let Err(e) = watch_packages(paths, delay, changes_tx).await {
+                warn!("Not rescanning on package changes: {e:#}");
+            }
+        });
+    }
 
+    // Failed scans are retried on schedule, only shutting down stops
+    // the agent
     loop {
+        vm_agent.scan().await;
+        if vm_agent.scanner.cancelled.load(Ordering::Relaxed) {
+            info!("Shutting down...");
+            break;
+        }
         select! {
-            _ = ticks.tick() => {
-                vm_agent.run().await?;
+            _ = sleep(next_scan(interval)) => {}
+            Some(()) = changes.recv() => {
+                info!("Packages changed, rescanning");
             }
             _ = rx.recv() => {
                 info!("Shutting down...");
@@ -424,3 +849,171 @@ pub async fn run_vm_agent(config: &FactConfig) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Reports are saved as JSON records when their file name ends with
+/// `.json`, encoded as protobuf otherwise.
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Collect the packages once and write the index report to `path`. Only
+/// the packages are collected, nothing is read from or written to the
+/// state directory and sensor is not connected to.
+pub async fn scan(config: &FactConfig, path: &Path) -> anyhow::Result<()> {
+    let scan = Scanner::new(config).collect().await?;
+    if scan.failed == scan.collectors {
+        bail!("No package could be collected: {}", scan.errors.join("; "));
+    }
+    let packages = scan.contents.packages.len();
+    let report = index_report(report_digest(&scan), scan);
+    write_report(path, &report)?;
+    info!("Saved the index report of {packages} packages to {}", path.display());
+    Ok(())
+}
+
+/// Save an index report, to be read by [`read_report`].
+fn write_report(path: &Path, report: &IndexReport) -> anyhow::Result<()> {
+    let data = if is_json(path) {
+        let mut json = output::index_report_to_json(report)?;
+        json.push('\n');
+        json.into_bytes()
+    } else {
+        report.encode_to_vec()
+    };
+    write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Read an index report saved by [`scan`].
+fn read_report(path: &Path) -> anyhow::Result<IndexReport> {
+    let data = read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if is_json(path) {
+        let json = std::str::from_utf8(&data).context("Invalid JSON report")?;
+        output::index_report_from_json(json)
+    } else {
+        IndexReport::decode(data.as_slice()).map_err(anyhow::Error::from)
+    }
+    .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Send an index report saved by [`scan`], as is.
+pub async fn replay(config: &FactConfig, path: &Path) -> anyhow::Result<()> {
+    let report = read_report(path)?;
+    Sender::try_from(config)?.send(report).await?;
+    info!("Sent {}", path.display());
+    Ok(())
+}
+
+/// Write the SBOM of the packages of this machine, or of those of an
+/// index report saved by [`scan`].
+pub async fn sbom(config: &FactConfig, args: &SbomArgs) -> anyhow::Result<()> {
+    let (contents, name) = match &args.report {
+        Some(path) => {
+            let report = read_report(path)?;
+            let contents = report
+                .index_v4
+                .and_then(|index| index.contents)
+                .unwrap_or_default();
+            (contents, report.vsock_cid)
+        }
+        None => {
+            let scan = Scanner::new(config).collect().await?;
+            if scan.failed > 0 {
+                bail!("Failed to collect every package: {}", scan.errors.join("; "));
+            }
+            (scan.contents, HOSTNAME.to_string())
+        }
+    };
+
+    let document = sbom::generate(args.format, &contents, &name);
+    match &args.output {
+        Some(path) => {
+            sbom::save(path, &document)?;
+            info!("Saved the SBOM of {} packages to {}", contents.packages.len(), path.display());
+        }
+        None => println!("{}", serde_json::to_string_pretty(&document)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn scan(ids: &[&str]) -> Scan {
+        let packages = ids
+            .iter()
+            .map(|id| Package {
+                id: id.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        Scan {
+            contents: Contents {
+                packages,
+                ..Default::default()
+            },
+            notes: Vec::new(),
+            errors: Vec::new(),
+            collectors: 1,
+            failed: 0,
+        }
+    }
+
+    #[test]
+    fn digest() {
+        let digest = report_digest(&scan(&["a", "b"]));
+        assert_eq!(report_digest(&scan(&["b", "a"])), digest);
+        assert_ne!(report_digest(&scan(&["a"])), digest);
+
+        // The same packages found by a partial scan
+        let mut partial = scan(&["a", "b"]);
+        partial.failed = 1;
+        let partial_digest = report_digest(&partial);
+        assert_ne!(partial_digest, digest);
+        partial.notes = vec![Note::PartialScanData, Note::OsCvesUnavailable];
+        assert_ne!(report_digest(&partial), partial_digest);
+        let notes_digest = report_digest(&partial);
+        partial.notes.reverse();
+        assert_eq!(report_digest(&partial), notes_digest);
+    }
+
+    #[test]
+    fn saved_reports() {
+        identity::init(&FactConfig::parse_from(["fact"])).unwrap();
+        let mut scan = scan(&["a", "b"]);
+        scan.failed = 1;
+        scan.errors = vec!["dpkg: permission denied".to_string()];
+        let report = index_report(report_digest(&scan), scan);
+
+        let dir = std::env::temp_dir().join(format!("fact-reports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["report.json", "report.pb"] {
+            let path = dir.join(name);
+            write_report(&path, &report).unwrap();
+            assert_eq!(read_report(&path).unwrap(), report, "{name}");
+        }
+        assert!(read(dir.join("report.json")).unwrap().ends_with(b"}\n"));
+
+        // Saved by another version of fact
+        let json = read_to_string(dir.join("report.json")).unwrap();
+        let version = format!("\"version\":{}", output::SCHEMA_VERSION);
+        assert!(json.contains(&version));
+        let newer = json.replace(&version, &format!("\"version\":{}", output::SCHEMA_VERSION + 1));
+        write(dir.join("newer.json"), newer).unwrap();
+        assert!(read_report(&dir.join("newer.json")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn database_changes() {
+        let watched = ["rpmdb.sqlite".to_string(), "status".to_string()];
+        for name in ["rpmdb.sqlite", "rpmdb.sqlite-wal", "rpmdb.sqlite-journal", "status"] {
+            assert!(is_database(name, &watched), "{name}");
+        }
+        for name in ["rpmdb.sqlite-shm", ".rpm.lock", "status-old", "status.d", "rpmdb"] {
+            assert!(!is_database(name, &watched), "{name}");
+        }
+    }
+}
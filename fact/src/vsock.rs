@@ -1,70 +1,336 @@
 use std::os::unix::io::{AsRawFd, RawFd, OwnedFd, FromRawFd};
-use std::io;
-use anyhow::{Context, Result};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    fs::File,
+    future::Future,
+    io,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use anyhow::{bail, Context, Result};
 use log::{debug, info, warn};
-use nix::sys::socket::{
-    accept, bind, connect, listen, socket, AddressFamily, SockFlag, SockType, VsockAddr, Backlog,
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        accept4, bind, connect, getpeername, getsockopt, listen, socket, sockopt, AddressFamily,
+        Backlog, SockFlag, SockType, VsockAddr,
+    },
+};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::mpsc,
+    task::JoinSet,
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    config::VsockConfig,
+    frame::{self, Frame, Header, MessageType, TooLarge, HEADER_LEN},
+    health, metrics,
 };
-use tokio::sync::mpsc;
 
 const VMADDR_CID_HOST: u32 = 2; // Host context ID
 const VMADDR_CID_ANY: u32 = 0xFFFFFFFF; // Any context ID (for server binding)
 
+/// Request returning the CID of this machine, from linux/vm_sockets.h
+const IOCTL_VM_SOCKETS_GET_LOCAL_CID: u32 = 0x7b9;
+
 #[derive(Debug)]
 pub struct VmMessage {
     pub vm_id: String,
+    /// CID of the VM the message was received from, as seen by the kernel
+    pub cid: Option<u32>,
+    /// `IndexReport` or `Facts`
+    pub kind: MessageType,
     pub data: Vec<u8>,
 }
 
+/// Context ID assigned to this VM by the hypervisor.
+pub fn local_cid() -> Result<u32> {
+    let dev = File::open("/dev/vsock").context("Failed to open /dev/vsock")?;
+    let mut cid: u32 = VMADDR_CID_ANY;
+    let res = unsafe {
+        libc::ioctl(dev.as_raw_fd(), IOCTL_VM_SOCKETS_GET_LOCAL_CID as _, &mut cid as *mut u32)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to get the local VSOCK CID");
+    }
+    // 0 to 2 are the hypervisor, local loopback and the host
+    if cid <= VMADDR_CID_HOST || cid == VMADDR_CID_ANY {
+        anyhow::bail!("No VSOCK CID assigned to this machine (got {cid})");
+    }
+    Ok(cid)
+}
+
+/// Non-blocking VSOCK socket, driven by the readiness events of the
+/// tokio reactor.
+struct VsockStream {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl VsockStream {
+    /// Wrap a socket opened with `SOCK_NONBLOCK`.
+    fn new(fd: OwnedFd) -> io::Result<Self> {
+        Ok(VsockStream {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Read exact number of bytes from the socket
+    async fn read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.fd.readable().await?;
+            match nix::unistd::read(self.fd.as_raw_fd(), buf) {
+                Ok(0) => return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer"
+                )),
+                Ok(n) => {
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                }
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => guard.clear_ready(),
+                Err(e) => return Err(io::Error::from(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all bytes to the socket
+    async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            match nix::unistd::write(self.fd.get_ref(), buf) {
+                Ok(0) => return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer"
+                )),
+                Ok(n) => buf = &buf[n..],
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => guard.clear_ready(),
+                Err(e) => return Err(io::Error::from(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
 pub struct VsockClient {
-    fd: OwnedFd,
+    stream: VsockStream,
+    port: u32,
+    /// Version of the framed protocol spoken with the listener, none for
+    /// listeners only reading a length followed by a report
+    version: Option<u8>,
+    next_request_id: u32,
+}
+
+/// `Error` reply of the listener to a request.
+#[derive(Debug)]
+pub struct Refused {
+    pub code: u32,
+    pub message: String,
 }
 
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server returned error code {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Time to wait for the listener to answer a hello, listeners predating
+/// the framed protocol never do.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest reply accepted from the listener, which only sends acks and
+/// errors.
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+/// Ports whose listener only speaks the legacy protocol. They are not sent
+/// a hello again until the agent restarts: a legacy listener reads the
+/// magic as the length of a report, and allocates that much.
+static LEGACY_LISTENERS: LazyLock<Mutex<HashSet<u32>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
 impl VsockClient {
     /// Create a new VSOCK client connection to the host
-    pub fn connect() -> Result<Self> {
-        Self::connect_to_port(818)
+    pub async fn connect() -> Result<Self> {
+        Self::connect_to_port(818).await
     }
 
-    /// Create a new VSOCK client connection to a specific port
-    pub fn connect_to_port(port: u32) -> Result<Self> {
+    /// Create a new VSOCK client connection to a specific port, and agree
+    /// on the protocol to speak with the listener
+    pub async fn connect_to_port(port: u32) -> Result<Self> {
         info!("Connecting to host via VSOCK on port {}", port);
-        
+        let mut client = VsockClient {
+            stream: Self::open(port).await?,
+            port,
+            version: None,
+            next_request_id: 1,
+        };
+        if LEGACY_LISTENERS.lock().unwrap().contains(&port) {
+            debug!("Speaking the legacy protocol on port {port}");
+            return Ok(client);
+        }
+        match client.negotiate().await? {
+            Some(version) => {
+                info!("Successfully connected to host via VSOCK, protocol version {version}");
+                client.version = Some(version);
+            }
+            None => {
+                warn!("The VSOCK listener does not speak the framed protocol, falling back to the legacy one");
+                LEGACY_LISTENERS.lock().unwrap().insert(port);
+                client.stream = Self::open(port).await?;
+            }
+        }
+        Ok(client)
+    }
+
+    async fn open(port: u32) -> Result<VsockStream> {
         // Create VSOCK socket
         let fd = socket(
             AddressFamily::Vsock,
             SockType::Stream,
-            SockFlag::empty(),
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
             None,
         )
         .context("Failed to create VSOCK socket")?;
         
-        // Connect to host
+        // Connect to host, which completes once the socket is writable
         let addr = VsockAddr::new(VMADDR_CID_HOST, port);
-        connect(fd.as_raw_fd(), &addr).context("Failed to connect to VSOCK host")?;
-        
-        info!("Successfully connected to host via VSOCK");
-        Ok(VsockClient { fd })
+        match connect(fd.as_raw_fd(), &addr) {
+            Ok(()) | Err(Errno::EINPROGRESS) => {}
+            Err(e) => return Err(e).context("Failed to connect to VSOCK host"),
+        }
+        let stream = VsockStream::new(fd)?;
+        let _ = stream.fd.writable().await?;
+        match getsockopt(stream.fd.get_ref(), sockopt::SocketError)? {
+            0 => Ok(stream),
+            errno => Err(io::Error::from_raw_os_error(errno))
+                .context("Failed to connect to VSOCK host"),
+        }
     }
-    
-    /// Send data with protocol header
-    pub fn send_data(&mut self, data: &[u8]) -> Result<()> {
+
+    /// Send a hello, returning the version picked by the listener or none
+    /// if it did not answer.
+    async fn negotiate(&mut self) -> Result<Option<u8>> {
+        self.stream.write_all(&Frame::hello().encode())
+            .await
+            .context("Failed to send hello")?;
+        let reply = match timeout(HELLO_TIMEOUT, self.read_frame()).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("Connection closed on port {} instead of a reply to hello", self.port);
+                return Ok(None);
+            }
+            Ok(Err(e)) => return Err(e).context("Failed to read the reply to hello"),
+            Err(_) => {
+                debug!("No reply to hello on port {} after {HELLO_TIMEOUT:?}", self.port);
+                return Ok(None);
+            }
+        };
+
+        match reply.kind {
+            MessageType::HelloAck => Ok(Some(reply.version)),
+            MessageType::Error => {
+                let (code, message) = reply.error_details();
+                bail!("VSOCK listener refused the connection (code {code}): {message}")
+            }
+            kind => bail!("Unexpected {kind:?} reply to hello"),
+        }
+    }
+
+    /// Send a serialized index report, and wait for the listener to
+    /// acknowledge it
+    pub async fn send_index_report(&mut self, data: &[u8]) -> Result<()> {
+        let Some(version) = self.version else {
+            return self.send_legacy(data).await;
+        };
+        self.request(version, MessageType::IndexReport, data).await
+    }
+
+    /// Send the serialized facts of the VM, and wait for the listener to
+    /// acknowledge them. False if the listener does not take facts, like
+    /// those speaking the legacy protocol.
+    pub async fn send_facts(&mut self, data: &[u8]) -> Result<bool> {
+        let Some(version) = self.version else {
+            return Ok(false);
+        };
+        match self.request(version, MessageType::Facts, data).await {
+            Err(e) if e
+                .downcast_ref::<Refused>()
+                .is_some_and(|refused| refused.code == frame::ERROR_UNSUPPORTED_TYPE) =>
+            {
+                Ok(false)
+            }
+            res => res.map(|()| true),
+        }
+    }
+
+    async fn request(&mut self, version: u8, kind: MessageType, data: &[u8]) -> Result<()> {
+        let request_id = self.next_request_id;
+        self.next_request_id = request_id.wrapping_add(1);
+
+        let frame = Frame::compressed(version, kind, request_id, data)?;
+        debug!(
+            "Sending VSOCK request {request_id}: len={}, compressed={}",
+            data.len(),
+            frame.payload.len()
+        );
+        self.stream.write_all(&frame.encode())
+            .await
+            .context("Failed to send message")?;
+
+        let reply = self.read_frame()
+            .await
+            .context("Failed to read acknowledgment")?;
+        if reply.request_id != request_id {
+            bail!("Got a reply to request {} instead of {request_id}", reply.request_id);
+        }
+        match reply.kind {
+            MessageType::Ack => {
+                debug!("Message sent successfully and acknowledged");
+                Ok(())
+            }
+            MessageType::Error => {
+                let (code, message) = reply.error_details();
+                Err(Refused { code, message }.into())
+            }
+            reply => bail!("Unexpected {reply:?} reply to {kind:?}"),
+        }
+    }
+
+    /// Send data to a listener speaking the legacy protocol, a length
+    /// followed by the data, acknowledged with a status code
+    async fn send_legacy(&mut self, data: &[u8]) -> Result<()> {
         debug!("Sending VSOCK message: len={}", data.len());
         
         // Create message header (4 bytes: length only)
         let header = (data.len() as u32).to_le_bytes();
         
         // Send header
-        self.write_all(&header)
+        self.stream.write_all(&header)
+            .await
             .context("Failed to send message header")?;
         
         // Send data
-        self.write_all(data)
+        self.stream.write_all(data)
+            .await
             .context("Failed to send message data")?;
         
         // Read acknowledgment (4 bytes)
         let mut ack = [0u8; 4];
-        self.read_exact(&mut ack)
+        self.stream.read_exact(&mut ack)
+            .await
             .context("Failed to read acknowledgment")?;
         
         let ack_code = u32::from_le_bytes(ack);
@@ -75,40 +341,19 @@ impl VsockClient {
         debug!("Message sent successfully and acknowledged");
         Ok(())
     }
-    
-    /// Write all bytes to the socket
-    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
-        while !buf.is_empty() {
-            match nix::unistd::write(&self.fd, buf) {
-                Ok(0) => return Err(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "failed to write whole buffer"
-                )),
-                Ok(n) => buf = &buf[n..],
-                Err(nix::errno::Errno::EINTR) => {}
-                Err(e) => return Err(io::Error::from(e)),
-            }
-        }
-        Ok(())
-    }
-    
-    /// Read exact number of bytes from the socket
-    fn read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
-        while !buf.is_empty() {
-            match nix::unistd::read(self.fd.as_raw_fd(), buf) {
-                Ok(0) => return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "failed to fill whole buffer"
-                )),
-                Ok(n) => {
-                    let tmp = buf;
-                    buf = &mut tmp[n..];
-                }
-                Err(nix::errno::Errno::EINTR) => {}
-                Err(e) => return Err(io::Error::from(e)),
-            }
+
+    async fn read_frame(&self) -> io::Result<Frame> {
+        let mut header = [0u8; HEADER_LEN];
+        self.stream.read_exact(&mut header).await?;
+        let header = Header::decode(&header)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if header.length as usize > MAX_REPLY_SIZE {
+            let limit = MAX_REPLY_SIZE;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge { limit }));
         }
-        Ok(())
+        let mut payload = vec![0u8; header.length as usize];
+        self.stream.read_exact(&mut payload).await?;
+        Ok(Frame::from_parts(header, payload))
     }
     
     /// Check if VSOCK is available on this system
@@ -129,33 +374,56 @@ impl VsockClient {
     }
 }
 
-impl Drop for VsockClient {
-    fn drop(&mut self) {
-        // OwnedFd automatically closes the file descriptor when dropped
-    }
-}
-
 impl AsRawFd for VsockClient {
     fn as_raw_fd(&self) -> RawFd {
-        self.fd.as_raw_fd()
+        self.stream.as_raw_fd()
     }
 }
 
 pub struct VsockServer {
     port: u32,
-    listener_fd: OwnedFd,
+    listener: AsyncFd<OwnedFd>,
+    limits: Limits,
+    /// Connections open, by CID
+    connections: Connections,
+}
+
+/// Connections open by CID, those of clients whose CID is unknown being
+/// counted together.
+type Connections = Arc<Mutex<HashMap<Option<u32>, u64>>>;
+
+/// Time a report waits for the sensor relay to take it before its VM is
+/// told to send it again later.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits applied to VSOCK clients, see [`VsockConfig`].
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_frame_size: usize,
+    read_timeout: Option<Duration>,
+    max_connections_per_cid: u64,
+}
+
+impl From<&VsockConfig> for Limits {
+    fn from(cfg: &VsockConfig) -> Self {
+        Limits {
+            max_frame_size: (cfg.max_frame_size * 1024 * 1024) as usize,
+            read_timeout: (cfg.read_timeout > 0).then(|| Duration::from_secs(cfg.read_timeout)),
+            max_connections_per_cid: cfg.max_connections_per_cid,
+        }
+    }
 }
 
 impl VsockServer {
     /// Create a new VSOCK server listening on the specified port
-    pub fn bind(port: u32) -> Result<Self> {
+    pub fn bind(port: u32, cfg: &VsockConfig) -> Result<Self> {
         info!("Creating VSOCK server on port {}", port);
         
         // Create VSOCK socket
         let fd = socket(
             AddressFamily::Vsock,
             SockType::Stream,
-            SockFlag::empty(),
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
             None,
         )
         .context("Failed to create VSOCK server socket")?;
@@ -170,171 +438,627 @@ impl VsockServer {
         info!("VSOCK server listening on port {}", port);
         Ok(VsockServer {
             port,
-            listener_fd: fd,
+            listener: AsyncFd::new(fd)?,
+            limits: cfg.into(),
+            connections: Arc::default(),
         })
     }
     
-    /// Accept incoming connections and handle them
-    pub async fn serve(&self, mut shutdown: tokio::sync::broadcast::Receiver<()>) -> Result<()> {
-        let (vm_tx, mut vm_rx) = mpsc::channel::<VmMessage>(100);
-        
-        // Spawn task to handle VM messages
-        let sensor_tx = vm_tx.clone();
-        tokio::spawn(async move {
-            while let Some(msg) = vm_rx.recv().await {
-                debug!("Received VM message from {}: {} bytes", msg.vm_id, msg.data.len());
-                // TODO: Forward to sensor relay
-            }
-        });
+    /// Accept incoming connections and forward their messages to `vm_tx`
+    pub async fn serve(
+        &self,
+        vm_tx: mpsc::Sender<VmMessage>,
+        mut shutdown: tokio::sync::broadcast::Receiver<()>,
+    ) -> Result<()> {
+        health::set_ready(health::VSOCK_SERVER);
+        // Connections being served, closed when the server stops
+        let mut clients = JoinSet::new();
         
         // Main server loop
         loop {
             tokio::select! {
                 _ = shutdown.recv() => {
-                    info!("VSOCK server shutting down");
+                    info!("VSOCK server shutting down, closing {} connections", clients.len());
                     break;
                 }
-                _ = self.accept_connection(sensor_tx.clone()) => {
-                    // Connection handled
-                }
+                res = self.accept() => match res {
+                    Ok(client_fd) => self.accept_connection(client_fd, &vm_tx, &mut clients),
+                    Err(e) => warn!("Failed to accept VSOCK connection: {}", e),
+                },
+                Some(_) = clients.join_next(), if !clients.is_empty() => {}
             }
         }
         
         Ok(())
     }
+
+    /// Wait for the next connection, set non-blocking like the listener
+    async fn accept(&self) -> io::Result<OwnedFd> {
+        loop {
+            let mut guard = self.listener.readable().await?;
+            match accept4(
+                self.listener.as_raw_fd(),
+                SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            ) {
+                Ok(fd) => return Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => guard.clear_ready(),
+                Err(e) => return Err(io::Error::from(e)),
+            }
+        }
+    }
     
-    /// Accept a single connection and handle it
-    async fn accept_connection(&self, vm_tx: mpsc::Sender<VmMessage>) -> Result<()> {
-        // Accept connection (blocking, but we're in a select loop)
-        let client_fd = match accept(self.listener_fd.as_raw_fd()) {
-            Ok(fd) => fd,
+    /// Handle an accepted connection in a task of its own
+    fn accept_connection(
+        &self,
+        client_fd: OwnedFd,
+        vm_tx: &mpsc::Sender<VmMessage>,
+        clients: &mut JoinSet<()>,
+    ) {
+        let cid = getpeername::<VsockAddr>(client_fd.as_raw_fd())
+            .map(|addr| addr.cid())
+            .inspect_err(|e| warn!("Failed to get the CID of a VSOCK client: {e}"))
+            .ok();
+        let vm_id = match cid {
+            Some(cid) => format!("cid-{cid}"),
+            None => format!("vm-{}", client_fd.as_raw_fd()),
+        };
+
+        let max = self.limits.max_connections_per_cid;
+        let Some(slot) = ConnectionSlot::take(&self.connections, cid, max) else {
+            warn!("Refusing a connection from {vm_id}, which already has {max} open");
+            metrics::VSOCK_REJECTED_TOTAL
+                .with_label_values(&["too_many_connections"])
+                .inc();
+            return;
+        };
+        let stream = match VsockStream::new(client_fd) {
+            Ok(stream) => stream,
             Err(e) => {
-                warn!("Failed to accept VSOCK connection: {}", e);
-                return Ok(());
+                warn!("Failed to register the connection from {vm_id}: {e}");
+                return;
             }
         };
         
-        let client_fd = unsafe { OwnedFd::from_raw_fd(client_fd) };
-        let vm_id = format!("vm-{}", client_fd.as_raw_fd());
-        
         info!("Accepted VSOCK connection from {}", vm_id);
+        metrics::VSOCK_CONNECTIONS_TOTAL.inc();
+        metrics::VSOCK_ACTIVE_CONNECTIONS.inc();
         
         // Spawn task to handle this client
-        tokio::spawn(async move {
-            if let Err(e) = Self::handle_client(client_fd, vm_id.clone(), vm_tx).await {
-                warn!("Error handling client {}: {}", vm_id, e);
+        let client = Client {
+            stream,
+            vm_id,
+            cid,
+            vm_tx: vm_tx.clone(),
+            limits: self.limits,
+            _slot: slot,
+        };
+        clients.spawn(Self::handle_client(client));
+    }
+    
+    /// Handle a single client connection, speaking the framed protocol
+    /// or the legacy one depending on what the client sends first
+    async fn handle_client(client: Client) {
+        let deadline = client.deadline();
+        let mut first = [0u8; 4];
+        let res = match client.read_exact(&mut first, deadline).await {
+            Ok(()) if first == frame::MAGIC => client.serve_framed(deadline).await,
+            Ok(()) => client.serve_legacy(u32::from_le_bytes(first), deadline).await,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            let timed_out = e
+                .root_cause()
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+            if timed_out {
+                metrics::VSOCK_REJECTED_TOTAL.with_label_values(&["timeout"]).inc();
             }
-        });
+            warn!("Error handling client {}: {e:#}", client.vm_id);
+        }
         
-        Ok(())
+        info!("Client {} connection closed", client.vm_id);
     }
-    
-    /// Handle a single client connection
-    async fn handle_client(
-        mut client_fd: OwnedFd,
-        vm_id: String,
-        vm_tx: mpsc::Sender<VmMessage>,
-    ) -> Result<()> {
+}
+
+impl Drop for VsockServer {
+    fn drop(&mut self) {
+        info!("VSOCK server on port {} shutting down", self.port);
+    }
+}
+
+/// Fail with `TimedOut` once `deadline` passed.
+async fn with_deadline<T>(
+    deadline: Option<Instant>,
+    io: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, io).await.unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        }),
+        None => io.await,
+    }
+}
+
+/// A connection counted against the limit of its VM until dropped.
+struct ConnectionSlot {
+    cid: Option<u32>,
+    connections: Connections,
+}
+
+impl ConnectionSlot {
+    /// Count a new connection from `cid`, unless it already has `max`.
+    fn take(connections: &Connections, cid: Option<u32>, max: u64) -> Option<Self> {
+        let mut open = connections.lock().unwrap();
+        let count = open.entry(cid).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            cid,
+            connections: connections.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.connections.lock().unwrap();
+        if let Entry::Occupied(mut count) = open.entry(self.cid) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
+/// A connection accepted by the VSOCK server.
+struct Client {
+    stream: VsockStream,
+    vm_id: String,
+    cid: Option<u32>,
+    vm_tx: mpsc::Sender<VmMessage>,
+    limits: Limits,
+    _slot: ConnectionSlot,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Also when the task serving the client is aborted on shutdown
+        metrics::VSOCK_ACTIVE_CONNECTIONS.dec();
+    }
+}
+
+impl Client {
+    /// Serve a client speaking the legacy protocol, a length followed by
+    /// an index report, acknowledged with a status code. The length of
+    /// the first message was already read.
+    async fn serve_legacy(&self, first_len: u32, mut deadline: Option<Instant>) -> Result<()> {
         let mut buffer = vec![0u8; 4096];
-        
+        let mut msg_len = first_len as usize;
         loop {
-            // Read message header (4 bytes: length)
-            let mut header = [0u8; 4];
-            match Self::read_exact_fd(&client_fd, &mut header).await {
-                Ok(()) => {},
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    debug!("Client {} disconnected", vm_id);
-                    break;
-                }
-                Err(e) => {
-                    warn!("Error reading header from {}: {}", vm_id, e);
-                    break;
-                }
+            if msg_len > self.limits.max_frame_size {
+                // Refused without reading it, which leaves nothing else
+                // to read on the connection
+                metrics::VSOCK_REJECTED_TOTAL.with_label_values(&["too_large"]).inc();
+                let status = frame::ERROR_TOO_LARGE.to_le_bytes();
+                self.write_all(&status)
+                    .await
+                    .context("Error sending status")?;
+                bail!("Refused a {msg_len} bytes message, {}", self.too_large());
             }
-            
-            let msg_len = u32::from_le_bytes(header) as usize;
             if msg_len > buffer.len() {
                 buffer.resize(msg_len, 0);
             }
             
             // Read message data
-            match Self::read_exact_fd(&client_fd, &mut buffer[..msg_len]).await {
-                Ok(()) => {},
-                Err(e) => {
-                    warn!("Error reading data from {}: {}", vm_id, e);
-                    break;
-                }
-            }
+            self.read_exact(&mut buffer[..msg_len], deadline)
+                .await
+                .context("Error reading data")?;
             
-            // Send acknowledgment (0 = success)
-            let ack = 0u32.to_le_bytes();
-            if let Err(e) = Self::write_all_fd(&client_fd, &ack).await {
-                warn!("Error sending ack to {}: {}", vm_id, e);
-                break;
-            }
+            metrics::VSOCK_MESSAGES_RECEIVED_TOTAL.inc();
+            metrics::VSOCK_BYTES_RECEIVED_TOTAL.inc_by(msg_len as u64);
             
-            // Forward message to sensor relay
-            let msg = VmMessage {
-                vm_id: vm_id.clone(),
-                data: buffer[..msg_len].to_vec(),
+            // Forward message to sensor relay, then send acknowledgment
+            // (0 = success)
+            let status = match self.forward(MessageType::IndexReport, buffer[..msg_len].to_vec()).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    warn!("Not relaying the report of {}: {e:#}", self.vm_id);
+                    frame::ERROR_UNAVAILABLE
+                }
             };
-            
-            if let Err(e) = vm_tx.send(msg).await {
-                warn!("Error forwarding message from {}: {}", vm_id, e);
-                break;
+            self.write_all(&status.to_le_bytes())
+                .await
+                .context("Error sending ack")?;
+
+            // Read message header (4 bytes: length)
+            let mut header = [0u8; 4];
+            deadline = self.deadline();
+            match self.read_exact(&mut header, deadline).await {
+                Ok(()) => msg_len = u32::from_le_bytes(header) as usize,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!("Client {} disconnected", self.vm_id);
+                    return Ok(());
+                }
+                Err(e) => return Err(e).context("Error reading header"),
             }
         }
-        
-        info!("Client {} connection closed", vm_id);
-        Ok(())
     }
-    
-    /// Read exact number of bytes from file descriptor
-    async fn read_exact_fd(fd: &OwnedFd, mut buf: &mut [u8]) -> io::Result<()> {
-        while !buf.is_empty() {
-            match nix::unistd::read(fd.as_raw_fd(), buf) {
-                Ok(0) => return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "failed to fill whole buffer"
-                )),
-                Ok(n) => {
-                    let tmp = buf;
-                    buf = &mut tmp[n..];
+
+    /// Serve a client speaking the framed protocol, whose first magic
+    /// was already read.
+    async fn serve_framed(&self, mut deadline: Option<Instant>) -> Result<()> {
+        let mut version = None;
+        let mut buf = [0u8; HEADER_LEN];
+        buf[..4].copy_from_slice(&frame::MAGIC);
+        let mut read = self.read_exact(&mut buf[4..], deadline).await;
+        loop {
+            match read {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!("Client {} disconnected", self.vm_id);
+                    return Ok(());
+                }
+                Err(e) => return Err(e).context("Error reading header"),
+            }
+            let header = Header::decode(&buf).context("Invalid frame header")?;
+            if header.length as usize > self.limits.max_frame_size {
+                // Refused without reading it, which leaves nothing else
+                // to read on the connection
+                metrics::VSOCK_REJECTED_TOTAL.with_label_values(&["too_large"]).inc();
+                let reply = Frame::error(
+                    version.unwrap_or(frame::MAX_VERSION),
+                    header.request_id,
+                    frame::ERROR_TOO_LARGE,
+                    &self.too_large().to_string(),
+                );
+                self.reply(&reply).await?;
+                bail!("Refused a {} bytes message, {}", header.length, self.too_large());
+            }
+            let mut payload = vec![0u8; header.length as usize];
+            self.read_exact(&mut payload, deadline)
+                .await
+                .context("Error reading data")?;
+            metrics::VSOCK_MESSAGES_RECEIVED_TOTAL.inc();
+            metrics::VSOCK_BYTES_RECEIVED_TOTAL.inc_by((HEADER_LEN + payload.len()) as u64);
+            let request = Frame::from_parts(header, payload);
+
+            match (request.kind, version) {
+                (MessageType::Hello, _) => {
+                    let (min, max) = request.hello_versions()?;
+                    let Some(picked) = frame::negotiate(min, max) else {
+                        let message = format!(
+                            "versions {min} to {max} are not supported, only {} to {}",
+                            frame::MIN_VERSION,
+                            frame::MAX_VERSION
+                        );
+                        let reply = Frame::error(
+                            frame::MAX_VERSION,
+                            request.request_id,
+                            frame::ERROR_UNSUPPORTED_VERSION,
+                            &message,
+                        );
+                        self.reply(&reply).await?;
+                        bail!("{message}");
+                    };
+                    debug!("Speaking protocol version {picked} with {}", self.vm_id);
+                    version = Some(picked);
+                    let ack = Frame::new(picked, MessageType::HelloAck, request.request_id, Vec::new());
+                    self.reply(&ack).await?;
                 }
-                Err(nix::errno::Errno::EINTR) => {}
-                Err(nix::errno::Errno::EAGAIN) | Err(nix::errno::Errno::EWOULDBLOCK) => {
-                    // Would block, yield and try again
-                    tokio::task::yield_now().await;
+                (_, None) => {
+                    let reply = Frame::error(
+                        frame::MAX_VERSION,
+                        request.request_id,
+                        frame::ERROR_INVALID,
+                        "expected a hello",
+                    );
+                    self.reply(&reply).await?;
+                    bail!("{:?} received before hello", request.kind);
+                }
+                (kind @ (MessageType::IndexReport | MessageType::Facts), Some(version)) => {
+                    match request.data(self.limits.max_frame_size) {
+                        Ok(data) => {
+                            let reply = match self.forward(kind, data).await {
+                                Ok(()) => Frame::ack(version, request.request_id),
+                                Err(e) => {
+                                    warn!("Not relaying {kind:?} from {}: {e:#}", self.vm_id);
+                                    Frame::error(
+                                        version,
+                                        request.request_id,
+                                        frame::ERROR_UNAVAILABLE,
+                                        &format!("{e:#}"),
+                                    )
+                                }
+                            };
+                            self.reply(&reply).await?;
+                        }
+                        Err(e) => {
+                            warn!("Invalid {kind:?} from {}: {e:#}", self.vm_id);
+                            let code = if e.is::<TooLarge>() {
+                                metrics::VSOCK_REJECTED_TOTAL
+                                    .with_label_values(&["too_large"])
+                                    .inc();
+                                frame::ERROR_TOO_LARGE
+                            } else {
+                                frame::ERROR_INVALID
+                            };
+                            let reply =
+                                Frame::error(version, request.request_id, code, &format!("{e:#}"));
+                            self.reply(&reply).await?;
+                        }
+                    }
+                }
+                (kind, Some(version)) => {
+                    debug!("Unsupported {kind:?} message from {}", self.vm_id);
+                    let reply = Frame::error(
+                        version,
+                        request.request_id,
+                        frame::ERROR_UNSUPPORTED_TYPE,
+                        &format!("unsupported message type {}", u8::from(kind)),
+                    );
+                    self.reply(&reply).await?;
                 }
-                Err(e) => return Err(io::Error::from(e)),
             }
+
+            deadline = self.deadline();
+            read = self.read_exact(&mut buf, deadline).await;
         }
-        Ok(())
     }
-    
-    /// Write all bytes to file descriptor
-    async fn write_all_fd(fd: &OwnedFd, mut buf: &[u8]) -> io::Result<()> {
-        while !buf.is_empty() {
-            match nix::unistd::write(fd, buf) {
-                Ok(0) => return Err(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "failed to write whole buffer"
-                )),
-                Ok(n) => buf = &buf[n..],
-                Err(nix::errno::Errno::EINTR) => {}
-                Err(nix::errno::Errno::EAGAIN) | Err(nix::errno::Errno::EWOULDBLOCK) => {
-                    // Would block, yield and try again
-                    tokio::task::yield_now().await;
-                }
-                Err(e) => return Err(io::Error::from(e)),
-            }
+
+    async fn reply(&self, frame: &Frame) -> Result<()> {
+        self.write_all(&frame.encode())
+            .await
+            .context("Error sending reply")
+    }
+
+    async fn read_exact(&self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<()> {
+        with_deadline(deadline, self.stream.read_exact(buf)).await
+    }
+
+    async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        with_deadline(self.deadline(), self.stream.write_all(buf)).await
+    }
+
+    /// Deadline of a read or write starting now.
+    fn deadline(&self) -> Option<Instant> {
+        self.limits.read_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    fn too_large(&self) -> TooLarge {
+        TooLarge {
+            limit: self.limits.max_frame_size,
         }
-        Ok(())
+    }
+
+    /// Forward a message to the sensor relay, which may not take it for
+    /// [`FORWARD_TIMEOUT`] while it is disconnected from sensor.
+    async fn forward(&self, kind: MessageType, data: Vec<u8>) -> Result<()> {
+        let msg = VmMessage {
+            vm_id: self.vm_id.clone(),
+            cid: self.cid,
+            kind,
+            data,
+        };
+        self.vm_tx
+            .send_timeout(msg, FORWARD_TIMEOUT)
+            .await
+            .map_err(|e| {
+                metrics::VSOCK_REJECTED_TOTAL.with_label_values(&["relay_unavailable"]).inc();
+                anyhow::anyhow!("Error forwarding message: {e}")
+            })
     }
 }
 
-impl Drop for VsockServer {
-    fn drop(&mut self) {
-        info!("VSOCK server on port {} shutting down", self.port);
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use flate2::{write::GzEncoder, Compression};
+    use nix::sys::socket::socketpair;
+
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_frame_size: 1000,
+        read_timeout: Some(Duration::from_millis(200)),
+        max_connections_per_cid: 2,
+    };
+
+    /// A client served over a socket pair, with the agent end of it and
+    /// the messages it forwards.
+    fn serve(
+        limits: Limits,
+    ) -> (
+        VsockClient,
+        mpsc::Receiver<VmMessage>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (server, agent) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let (vm_tx, vm_rx) = mpsc::channel(1);
+        let client = Client {
+            stream: VsockStream::new(server).unwrap(),
+            vm_id: "cid-3".to_string(),
+            cid: Some(3),
+            vm_tx,
+            limits,
+            _slot: ConnectionSlot::take(&Connections::default(), Some(3), 1).unwrap(),
+        };
+        let agent = VsockClient {
+            stream: VsockStream::new(agent).unwrap(),
+            port: 0,
+            version: None,
+            next_request_id: 1,
+        };
+        (
+            agent,
+            vm_rx,
+            tokio::spawn(VsockServer::handle_client(client)),
+        )
+    }
+
+    fn refused(res: Result<()>) -> u32 {
+        res.unwrap_err().downcast::<Refused>().unwrap().code
+    }
+
+    #[tokio::test]
+    async fn framed() {
+        let (mut agent, mut vm_rx, _) = serve(LIMITS);
+        assert_eq!(agent.negotiate().await.unwrap(), Some(frame::MAX_VERSION));
+        agent.version = Some(frame::MAX_VERSION);
+        agent.send_index_report(&[b'x'; 1000]).await.unwrap();
+        let msg = vm_rx.recv().await.unwrap();
+        assert_eq!(
+            (msg.cid, msg.kind, msg.data.len()),
+            (Some(3), MessageType::IndexReport, 1000)
+        );
+
+        // Compressed below the limit, but not once inflated, which leaves
+        // the connection usable
+        assert_eq!(
+            refused(agent.send_index_report(&[b'x'; 1001]).await),
+            frame::ERROR_TOO_LARGE
+        );
+        agent.send_index_report(b"report").await.unwrap();
+        assert_eq!(vm_rx.recv().await.unwrap().data, b"report");
+    }
+
+    #[tokio::test]
+    async fn framed_too_large() {
+        let (mut agent, _vm_rx, served) = serve(LIMITS);
+        agent.negotiate().await.unwrap();
+        // Not compressible
+        let data: Vec<u8> = (0..4000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        assert_eq!(
+            refused(
+                agent
+                    .request(frame::MAX_VERSION, MessageType::IndexReport, &data)
+                    .await
+            ),
+            frame::ERROR_TOO_LARGE
+        );
+        // The payload is not read, the connection is closed instead
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn before_hello() {
+        let (mut agent, _vm_rx, served) = serve(LIMITS);
+        assert_eq!(
+            refused(
+                agent
+                    .request(frame::MAX_VERSION, MessageType::IndexReport, b"report")
+                    .await
+            ),
+            frame::ERROR_INVALID
+        );
+        served.await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn legacy() {
+        let (mut agent, mut vm_rx, served) = serve(LIMITS);
+        agent.send_index_report(b"report").await.unwrap();
+        assert_eq!(vm_rx.recv().await.unwrap().data, b"report");
+
+        // Refused as soon as the length is read
+        agent
+            .stream
+            .write_all(&1001u32.to_le_bytes())
+            .await
+            .unwrap();
+        let mut status = [0; 4];
+        agent.stream.read_exact(&mut status).await.unwrap();
+        assert_eq!(u32::from_le_bytes(status), frame::ERROR_TOO_LARGE);
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_deadline() {
+        let (agent, _vm_rx, served) = serve(LIMITS);
+        // Half a header, then nothing
+        agent.stream.write_all(&frame::MAGIC[..2]).await.unwrap();
+        timeout(LIMITS.read_timeout.unwrap() * 5, served)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0; 1];
+        let eof = agent.stream.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_deadline_per_message() {
+        let (mut agent, mut vm_rx, served) = serve(LIMITS);
+        agent.negotiate().await.unwrap();
+        agent.version = Some(frame::MAX_VERSION);
+
+        // Every message gets the whole timeout
+        for _ in 0..3 {
+            tokio::time::sleep(LIMITS.read_timeout.unwrap() / 2).await;
+            agent.send_index_report(b"report").await.unwrap();
+            vm_rx.recv().await.unwrap();
+        }
+
+        // Even when slowly sent, a frame must arrive before the deadline
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"report").unwrap();
+        let payload = encoder.finish().unwrap();
+        let frame = Frame::new(frame::MAX_VERSION, MessageType::IndexReport, 9, payload).encode();
+        for byte in frame {
+            if agent.stream.write_all(&[byte]).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(LIMITS.read_timeout.unwrap() / 4).await;
+            if served.is_finished() {
+                break;
+            }
+        }
+        timeout(LIMITS.read_timeout.unwrap(), served)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(vm_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn connection_cap() {
+        let connections = Connections::default();
+        let first = ConnectionSlot::take(&connections, Some(3), 2).unwrap();
+        let second = ConnectionSlot::take(&connections, Some(3), 2).unwrap();
+        assert!(ConnectionSlot::take(&connections, Some(3), 2).is_none());
+        // Other VMs have limits of their own
+        let other = ConnectionSlot::take(&connections, Some(4), 2).unwrap();
+
+        drop(first);
+        let third = ConnectionSlot::take(&connections, Some(3), 2).unwrap();
+        assert!(ConnectionSlot::take(&connections, Some(3), 2).is_none());
+        drop((second, third, other));
+        assert!(connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connection_cap_unknown_cid() {
+        // Connections whose CID is unknown share a single limit
+        let connections = Connections::default();
+        let slots: Vec<_> = (0..2)
+            .map(|_| ConnectionSlot::take(&connections, None, 2).unwrap())
+            .collect();
+        assert!(ConnectionSlot::take(&connections, None, 2).is_none());
+        assert!(ConnectionSlot::take(&connections, Some(3), 2).is_some());
+        drop(slots);
+        assert!(ConnectionSlot::take(&connections, None, 2).is_some());
+    }
+}
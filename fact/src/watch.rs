@@ -0,0 +1,56 @@
+use std::{
+    os::fd::{AsFd, AsRawFd, RawFd},
+    path::Path,
+};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor},
+};
+use tokio::io::unix::AsyncFd;
+
+/// nix only exposes the inotify descriptor through `AsFd`, tokio wants
+/// `AsRawFd`.
+struct InotifyFd(Inotify);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+/// Asynchronous inotify watcher.
+pub struct Watcher {
+    inotify: AsyncFd<InotifyFd>,
+}
+
+impl Watcher {
+    pub fn new() -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("Failed to initialize inotify")?;
+        Ok(Watcher {
+            inotify: AsyncFd::new(InotifyFd(inotify))?,
+        })
+    }
+
+    pub fn add(&self, path: &Path, flags: AddWatchFlags) -> anyhow::Result<WatchDescriptor> {
+        self.inotify
+            .get_ref()
+            .0
+            .add_watch(path, flags)
+            .with_context(|| format!("Failed to watch {}", path.display()))
+    }
+
+    /// Wait for the next batch of events.
+    pub async fn next(&self) -> anyhow::Result<Vec<InotifyEvent>> {
+        loop {
+            let mut guard = self.inotify.readable().await?;
+            match guard.get_inner().0.read_events() {
+                Ok(events) => return Ok(events),
+                Err(Errno::EAGAIN) => guard.clear_ready(),
+                Err(e) => return Err(e).context("Failed to read inotify events"),
+            }
+        }
+    }
+}